@@ -12,6 +12,22 @@ const {h, render, Component} = window.preact;
 
 const SENTINEL_ERROR = -2100000000;
 const SENTINEL_NODATA = -2000000000;
+const SENTINEL_MISMATCH = -2110000000;
+
+// roughly how many points per series are worth fetching for the graph
+const MAX_GRAPH_POINTS = 2000;
+
+/*
+ * Converts a value from the server into a Dygraph value: nodata (i.e. this
+ * address wasn't sampled at this time) is null so that Dygraph connects the
+ * points around it, while errors are NaN so that they show up as gaps.
+ */
+function toGraphVal(n) {
+    if (n == SENTINEL_NODATA) {
+        return null;
+    }
+    return n > SENTINEL_NODATA ? n : NaN;
+}
 const TARGET_KINDS = [
     {
         name: 'tcpping',
@@ -20,6 +36,30 @@ const TARGET_KINDS = [
         valFormatter: function(val) {
             return (val / 1000).toFixed() + ' ms';
         }
+    },
+    {
+        name: 'ntp',
+        prettyName: 'NTP Clock Offset',
+        addrsPrompt: 'NTP servers (host or host:port) to query',
+        valFormatter: function(val) {
+            return (val / 1000).toFixed(1) + ' ms';
+        }
+    },
+    {
+        name: 'tcpexpect',
+        prettyName: 'TCP Service Check',
+        addrsPrompt: 'Services (host:port|send|expect) to check',
+        valFormatter: function(val) {
+            return (val / 1000).toFixed() + ' ms';
+        }
+    },
+    {
+        name: 'tlsexpiry',
+        prettyName: 'TLS Certificate Expiry',
+        addrsPrompt: 'TLS servers (host:port) to check',
+        valFormatter: function(val) {
+            return val + ' days';
+        }
     }
     /*
     {
@@ -32,6 +72,19 @@ const TARGET_KINDS = [
     */
 ];
 
+/*
+ * Reads an ArrayBuffer of back-to-back 32-bit little-endian integers (the byte
+ * order of everything the server sends, whatever our own) as an Int32Array.
+ */
+function readInt32LE(buf) {
+    var view = new DataView(buf);
+    var arr = new Int32Array(Math.floor(buf.byteLength / 4));
+    for (let i = 0; i < arr.length; i++) {
+        arr[i] = view.getInt32(i * 4, true);
+    }
+    return arr;
+}
+
 /*
  * A self-reconnecting WebSocket that tries to re-establish a connection if it
  * becomes disconnected for whatever reason.
@@ -175,6 +228,7 @@ class Graph extends Component {
                         axisLabelFormatter: this.props.valFormatter
                     }
                 },
+                connectSeparatedPoints: true,
                 isZoomedIgnoreProgrammaticZoom: true,
                 zoomCallback: function (lowerDate, upperDate, yRanges) {
                     // update the graph with new data when the user unzooms
@@ -390,7 +444,7 @@ class Target extends Component {
             ajax('POST', '/api/target/' + this.props.kind.name, 'arraybuffer', function(res) {
                 if (nonce == this.state.options.nonce) {
                     // read the response from the server as a Int32 Typed Array
-                    var raw = new Int32Array(res);
+                    var raw = readInt32LE(res);
 
                     // pre-allocate a large buffer array that will be assimilated into this.data
                     var newData = new Array(Math.ceil(raw.length / elementLength));
@@ -405,7 +459,7 @@ class Target extends Component {
                         let arr = new Array(elementLength);
                         for (let i = 0; i < arr.length; i++) {
                             let n = raw[j + i];
-                            arr[i] = n >= 0 ? n : null;
+                            arr[i] = toGraphVal(n);
                         }
                         newData[k++] = arr;
                     }
@@ -437,7 +491,9 @@ class Target extends Component {
             }.bind(this), JSON.stringify({
                 nonce: this.state.options.nonce,
                 lower: leftTarget,
-                upper: leftLimit
+                upper: leftLimit,
+                // let the server use a rollup when there are more points than we can show
+                resolution: Math.floor((leftLimit - leftTarget) / MAX_GRAPH_POINTS)
             }));
         }
     }
@@ -624,7 +680,7 @@ class Target extends Component {
         var arr = new Array(inArr.length);
         for (let i = 0; i < arr.length; i++) {
             let n = inArr[i];
-            arr[i] = n >= 0 ? n : null;
+            arr[i] = toGraphVal(n);
         }
         this.data.push(arr);
 
@@ -650,7 +706,7 @@ class App extends Component {
     handleSocketMessage(message) {
         // on receiving a websockets message, read it as an Int32 Typed Array
         var buf = message.data;
-        var raw = new Int32Array(buf);
+        var raw = readInt32LE(buf);
 
         // separate the target kind and nonce from the actual data
         var kind_id = raw[0];
@@ -0,0 +1,133 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Tracking of per-address degradation so that workers can probe degraded
+ * addresses more often (at *fast_interval*) until they stabilise.
+ */
+use std::collections::HashMap;
+
+use options::{SENTINEL_NODATA, is_failure};
+
+/// a value this many times the running average counts as a latency spike
+const SPIKE_FACTOR: f64 = 3.0;
+/// weight of each new healthy value in the running average
+const EWMA_ALPHA: f64 = 0.2;
+/// consecutive healthy values needed before falling back to the normal rate
+const RECOVERY_SAMPLES: u32 = 5;
+
+#[derive(Default)]
+struct AddrState {
+    ewma: Option<f64>,
+    degraded: bool,
+    healthy_streak: u32,
+}
+
+/**
+ * Per-address degradation state of a target, reset whenever the target's
+ * options (and hence nonce) change.
+ */
+pub struct AdaptiveState {
+    nonce: i32,
+    addrs: HashMap<String, AddrState>,
+}
+
+impl AdaptiveState {
+    pub fn new(nonce: i32) -> AdaptiveState {
+        AdaptiveState {
+            nonce: nonce,
+            addrs: HashMap::new(),
+        }
+    }
+
+    /**
+     * Forgets all state if the given nonce differs from the one this state
+     * was built under.
+     */
+    pub fn sync_nonce(&mut self, nonce: i32) {
+        if nonce != self.nonce {
+            self.nonce = nonce;
+            self.addrs.clear();
+        }
+    }
+
+    /**
+     * Whether the given address is currently degraded (and should thus be
+     * probed at the fast rate).
+     */
+    pub fn is_degraded(&self, addr: &str) -> bool {
+        self.addrs.get(addr).map_or(false, |s| s.degraded)
+    }
+
+    /**
+     * Takes a newly collected value for the given address into account,
+     * returning whether this changed its degraded state.
+     */
+    pub fn observe(&mut self, addr: &str, val: i32) -> bool {
+        if val == SENTINEL_NODATA {
+            return false;
+        }
+
+        let state = self.addrs.entry(addr.to_owned()).or_insert_with(AddrState::default);
+        let was_degraded = state.degraded;
+
+        let bad = is_failure(val) || match state.ewma {
+            Some(avg) => val as f64 > avg * SPIKE_FACTOR,
+            None => false,
+        };
+
+        if bad {
+            state.degraded = true;
+            state.healthy_streak = 0;
+        } else {
+            // only learn what "normal" looks like from healthy values
+            state.ewma = Some(match state.ewma {
+                Some(avg) => avg + EWMA_ALPHA * (val as f64 - avg),
+                None => val as f64,
+            });
+            if state.degraded {
+                state.healthy_streak += 1;
+                if state.healthy_streak >= RECOVERY_SAMPLES {
+                    state.degraded = false;
+                    state.healthy_streak = 0;
+                }
+            }
+        }
+
+        was_degraded != state.degraded
+    }
+}
+
+#[test]
+fn degrades_on_error_and_spike_and_recovers() {
+    use options::SENTINEL_ERROR;
+
+    let mut s = AdaptiveState::new(0);
+    for _ in 0..10 {
+        assert!(!s.observe("a", 10_000));
+    }
+    assert!(!s.is_degraded("a"));
+
+    // an error degrades
+    assert!(s.observe("a", SENTINEL_ERROR));
+    assert!(s.is_degraded("a"));
+    for _ in 0..(RECOVERY_SAMPLES - 1) {
+        assert!(!s.observe("a", 10_000));
+    }
+    assert!(s.observe("a", 10_000));
+    assert!(!s.is_degraded("a"));
+
+    // as does a latency spike
+    assert!(s.observe("a", 50_000));
+    assert!(s.is_degraded("a"));
+
+    // nodata is ignored, and a nonce change forgets everything
+    assert!(!s.observe("a", SENTINEL_NODATA));
+    s.sync_nonce(1);
+    assert!(!s.is_degraded("a"));
+}
@@ -0,0 +1,501 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * The per-target address index, mapping indices (the integers stored in data
+ * records) to the addresses they were collected for, along with the aliases,
+ * display names and retirement of those addresses.
+ *
+ * The index file is a versioned JSON object whose entries are in index order.
+ * An entry is `null` once its address has been retired for longer than the
+ * target's data reaches back; its index is never reused.
+ */
+use std::fs;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::fs::{OpenOptions, File};
+use std::io::Read;
+use std::ops::Deref;
+
+use helpers::{SPIOError, SPFile, overwrite_json, read_json_or_prev, with_suffix};
+use datafile::{Record, DataRecord};
+
+pub static INDEX_VERSION: i32 = 2;
+
+/**
+ * The entry of a single index: the address it was created for (or last
+ * renamed to), other addresses resolving to it, and how it is presented.
+ */
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug, PartialEq)]
+pub struct IndexEntry {
+    pub addr: String,
+    pub aliases: Vec<String>,
+    pub name: Option<String>,  // display name
+    pub retired: Option<i64>,  // when it was retired, in milliseconds from epoch
+    pub first_seen: Option<i64>,  // time of its first and last data, in milliseconds from epoch
+    pub last_seen: Option<i64>,
+}
+
+impl IndexEntry {
+    fn new(addr: &str) -> Self {
+        IndexEntry {
+            addr: addr.to_owned(),
+            aliases: Vec::new(),
+            name: None,
+            retired: None,
+            first_seen: None,
+            last_seen: None,
+        }
+    }
+}
+
+#[derive(RustcEncodable, RustcDecodable, Debug)]
+struct IndexFile {
+    version: i32,
+    entries: Vec<Option<IndexEntry>>,
+    seen_until: Option<i64>,  // time up to which data has been observed, in milliseconds from epoch
+}
+
+/**
+ * A change to the entry of the index that address `addr` resolves to, as
+ * requested by the client. Any combination of the fields may be given, and
+ * are applied in the order listed.
+ */
+#[derive(RustcDecodable, Debug)]
+pub struct AddrChange {
+    pub addr: String,
+    /// make (the new) `addr` resolve to the index of this address, continuing its history
+    pub alias_of: Option<String>,
+    /// display name, or empty to clear it
+    pub name: Option<String>,
+    pub retired: Option<bool>,
+    /// make this the address of the entry, keeping the old one as an alias
+    pub rename_to: Option<String>,
+}
+
+/**
+ * Description of an index entry as served to the client.
+ */
+#[derive(RustcEncodable, Debug)]
+pub struct AddrInfo {
+    pub index: i32,
+    pub addr: String,
+    pub aliases: Vec<String>,
+    pub name: Option<String>,
+    pub retired: Option<i64>,
+    pub first_seen: Option<i64>,
+    pub last_seen: Option<i64>,
+    pub active: bool,  // whether it is collected for under the current addrs
+}
+
+/**
+ * Error container for changes to the index, distinguishing requests that
+ * don't make sense from failures to save the index file.
+ */
+#[derive(Debug)]
+pub enum IndexError {
+    Invalid(String),
+    FileIO(SPIOError),
+}
+
+/**
+ * A per-target global persistent mapping of index (an integer) to an address
+ * (a string used in `TargetOptions.addrs`) backed by an index file.
+ */
+#[derive(Debug)]
+pub struct AddrIndex {
+    path: PathBuf,
+    entries: Vec<Option<IndexEntry>>,
+    map: HashMap<String, i32>,  // addresses and aliases to their index
+    seen_until: Option<i64>,
+    seen_dirty: bool,  // whether last seen times changed since the file was written
+}
+
+/**
+ * Reads the addresses of an index file from before the structured format, one
+ * per line, dropping a last line cut short by a crash (as appends were synced
+ * before any data referencing them was written, nothing refers to it).
+ */
+fn read_legacy(contents: &str, path: &Path) -> Vec<Option<IndexEntry>> {
+    let complete = contents.rfind('\n').map_or(0, |i| i + 1);
+    if complete < contents.len() {
+        println!("WARNING: dropping partially written entry {:?} from '{}'.",
+                 &contents[complete..], path.display());
+    }
+    contents[..complete].lines().map(|addr| Some(IndexEntry::new(addr))).collect()
+}
+
+fn build_map(entries: &[Option<IndexEntry>]) -> HashMap<String, i32> {
+    let mut map = HashMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        if let Some(ref e) = *entry {
+            map.insert(e.addr.clone(), i as i32);
+            for alias in e.aliases.iter() {
+                map.insert(alias.clone(), i as i32);
+            }
+        }
+    }
+    map
+}
+
+impl AddrIndex {
+    /**
+     * Creates an `AddrIndex` backed by the index file residing at the given
+     * path, converting an index file of plain lines (keeping the original
+     * next to it with a `.v1` suffix).
+     */
+    pub fn from_path<'b>(path: &'b Path) -> Result<Self, SPIOError> {
+        let mut contents = String::new();
+        if path.exists() {
+            let mut file = try!(File::open_from(OpenOptions::new().read(true), path));
+            try!(file.read_to_string(&mut contents)
+                 .map_err(|_| SPIOError::Read(Some(path.to_owned()))));
+        }
+
+        let legacy = !contents.is_empty() && !contents.trim_left().starts_with('{');
+        let mut seen_until = None;
+        let entries = if legacy {
+            // keep a copy of the original, which is replaced (atomically) below
+            let v1_path = with_suffix(path, "v1");
+            try!(fs::copy(path, &v1_path)
+                 .map_err(|_| SPIOError::Write(Some(v1_path.clone()))));
+            println!("Converting index file '{}' to version {} (original kept as '{}').",
+                     path.display(), INDEX_VERSION, v1_path.display());
+            read_legacy(&contents, path)
+        } else {
+            match try!(read_json_or_prev::<IndexFile>(path)) {
+                Some(ref f) if f.version > INDEX_VERSION => {
+                    return Err(SPIOError::Parse(Some(path.to_owned())));
+                },
+                Some(f) => {
+                    seen_until = f.seen_until;
+                    f.entries
+                },
+                None => Vec::new(),
+            }
+        };
+
+        let mut index = AddrIndex {
+            path: path.to_owned(),
+            map: build_map(&entries),
+            entries: entries,
+            seen_until: seen_until,
+            seen_dirty: false,
+        };
+        if legacy || !path.exists() {
+            try!(index.save());
+        }
+        Ok(index)
+    }
+
+    /**
+     * Writes out this index to its file (atomically, and durably before any
+     * data referencing new indices can be written).
+     */
+    fn save(&mut self) -> Result<(), SPIOError> {
+        let file = IndexFile {
+            version: INDEX_VERSION,
+            entries: self.entries.clone(),
+            seen_until: self.seen_until,
+        };
+        try!(overwrite_json(&file, &self.path));
+        self.seen_dirty = false;
+        Ok(())
+    }
+
+    /**
+     * The time (in milliseconds from epoch) from which data has yet to be
+     * observed for first and last seen times (observing any again is
+     * harmless).
+     */
+    pub fn seen_until(&self) -> i64 {
+        self.seen_until.unwrap_or(i64::min_value())
+    }
+
+    /**
+     * Takes the given data records into account in the first and last seen
+     * times of their indices. The index file is written right away when an
+     * index is first seen, but otherwise only by `flush_seen()` (or any other
+     * change).
+     */
+    pub fn observe(&mut self, records: &[DataRecord]) -> Result<(), SPIOError> {
+        let mut first = false;
+        for r in records {
+            if let Some(&mut Some(ref mut e)) = self.entries.get_mut(r.index() as usize) {
+                let t = r.time_ms();
+                if e.first_seen.map_or(true, |f| t < f) {
+                    e.first_seen = Some(t);
+                    first = true;
+                }
+                if e.last_seen.map_or(true, |l| t > l) {
+                    e.last_seen = Some(t);
+                }
+            }
+        }
+        if let Some(last) = records.iter().map(|r| r.time_ms()).max() {
+            if last >= self.seen_until() {
+                self.seen_until = Some(last);
+                self.seen_dirty = true;
+            }
+        }
+        if first {
+            try!(self.save());
+        }
+        Ok(())
+    }
+
+    /**
+     * Writes out the index file if last seen times changed since it was last
+     * written.
+     */
+    pub fn flush_seen(&mut self) -> Result<(), SPIOError> {
+        if self.seen_dirty {
+            try!(self.save());
+        }
+        Ok(())
+    }
+
+    /**
+     * Ensures (adding them if necessary) that all addrs in the given iterator
+     * exist in this index, and that none of them is retired.
+     */
+    pub fn ensure_for_addrs<'a, I, K>(&mut self, addrs: I) -> Result<(), SPIOError>
+            where I: Iterator<Item=&'a K>, K: 'a + Deref<Target=str> {
+        let mut changed = false;
+        for addr in addrs {
+            match self.map.get(&**addr).cloned() {
+                Some(i) => {
+                    if let Some(ref mut e) = self.entries[i as usize] {
+                        changed |= e.retired.take().is_some();
+                    }
+                },
+                None => {
+                    self.map.insert((&**addr).to_owned(), self.entries.len() as i32);
+                    self.entries.push(Some(IndexEntry::new(&**addr)));
+                    changed = true;
+                },
+            }
+        }
+        if changed {
+            try!(self.save());
+        }
+        Ok(())
+    }
+
+    /**
+     * Retrieves the index the given address (or alias) resolves to, if any.
+     */
+    pub fn lookup(&self, addr: &str) -> Option<i32> {
+        self.map.get(addr).cloned()
+    }
+
+    /**
+     * Retrieves the address of the given index, if it is still kept.
+     */
+    pub fn addr_of(&self, index: i32) -> Option<&str> {
+        match self.entries.get(index as usize) {
+            Some(&Some(ref e)) => Some(&e.addr),
+            _ => None,
+        }
+    }
+
+    /**
+     * Retrieves the index associated with the given address.
+     */
+    pub fn get_index(&self, addr: &str) -> i32 {
+        self.lookup(addr).expect("Non-existant addr requested from AddrIndex!")
+    }
+
+    /**
+     * Returns the length (as in number of indices ever handed out) of this
+     * index.
+     */
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /**
+     * Describes all entries still kept, marking those resolved to by the given
+     * current addrs as active.
+     */
+    pub fn describe(&self, current_addrs: &[String]) -> Vec<AddrInfo> {
+        let active: Vec<i32> = current_addrs.iter().filter_map(|a| self.lookup(a)).collect();
+        self.entries.iter().enumerate().filter_map(|(i, e)| e.as_ref().map(|e| AddrInfo {
+            index: i as i32,
+            addr: e.addr.clone(),
+            aliases: e.aliases.clone(),
+            name: e.name.clone(),
+            retired: e.retired,
+            first_seen: e.first_seen,
+            last_seen: e.last_seen,
+            active: active.contains(&(i as i32)),
+        })).collect()
+    }
+
+    /**
+     * Applies the given change, refusing to retire an address that resolves
+     * to the same index as one in the given current addrs.
+     */
+    pub fn apply(&mut self, change: &AddrChange, current_addrs: &[String],
+                 now_ms: i64) -> Result<(), IndexError> {
+        let invalid = |msg: String| Err(IndexError::Invalid(msg));
+
+        // work on a copy, so that a change failing half-way leaves us as we were
+        let mut entries = self.entries.clone();
+        let mut map = self.map.clone();
+
+        if let Some(ref target) = change.alias_of {
+            let i = match map.get(target) {
+                Some(&i) => i,
+                None => return invalid(format!("Unknown address '{}'.", target)),
+            };
+            match map.get(&change.addr).cloned() {
+                Some(j) if j == i => {},
+                Some(_) => return invalid(format!(
+                    "Address '{}' already has its own history.", change.addr)),
+                None => {
+                    entries[i as usize].as_mut().unwrap().aliases.push(change.addr.clone());
+                    map.insert(change.addr.clone(), i);
+                },
+            }
+        }
+
+        let i = match map.get(&change.addr) {
+            Some(&i) => i as usize,
+            None => return invalid(format!("Unknown address '{}'.", change.addr)),
+        };
+
+        if let Some(ref name) = change.name {
+            entries[i].as_mut().unwrap().name = if name.is_empty() { None } else { Some(name.clone()) };
+        }
+
+        match change.retired {
+            Some(true) => {
+                if current_addrs.iter().any(|a| map.get(a) == Some(&(i as i32))) {
+                    return invalid(format!(
+                        "Address '{}' is still in use; remove it from addrs first.", change.addr));
+                }
+                let e = entries[i].as_mut().unwrap();
+                e.retired = e.retired.or(Some(now_ms));
+            },
+            Some(false) => entries[i].as_mut().unwrap().retired = None,
+            None => {},
+        }
+
+        if let Some(ref new_addr) = change.rename_to {
+            match map.get(new_addr).cloned() {
+                Some(j) if j as usize != i => return invalid(format!(
+                    "Address '{}' already has its own history.", new_addr)),
+                _ => {},
+            }
+            let e = entries[i].as_mut().unwrap();
+            e.aliases.retain(|a| a != new_addr);
+            if e.addr != *new_addr {
+                let old = ::std::mem::replace(&mut e.addr, new_addr.clone());
+                e.aliases.push(old);
+            }
+            map.insert(new_addr.clone(), i as i32);
+        }
+
+        let old_entries = ::std::mem::replace(&mut self.entries, entries);
+        if let Err(e) = self.save() {
+            self.entries = old_entries;
+            return Err(IndexError::FileIO(e));
+        }
+        self.map = map;
+        Ok(())
+    }
+
+    /**
+     * Drops the entries of addresses retired before the given time (in
+     * milliseconds from epoch), which should be that of the oldest data still
+     * kept, so that no data refers to them anymore. Returns the number of
+     * entries dropped.
+     */
+    pub fn drop_retired_before(&mut self, cutoff_ms: i64) -> Result<usize, SPIOError> {
+        let mut dropped = 0;
+        for entry in self.entries.iter_mut() {
+            let expired = match *entry {
+                Some(IndexEntry { retired: Some(t), .. }) => t < cutoff_ms,
+                _ => false,
+            };
+            if expired {
+                *entry = None;
+                dropped += 1;
+            }
+        }
+        if dropped > 0 {
+            self.map = build_map(&self.entries);
+            try!(self.save());
+        }
+        Ok(dropped)
+    }
+}
+
+#[test]
+fn converts_aliases_renames_and_retires() {
+    use helpers::TempDir;
+    use std::io::Write;
+
+    let dir = TempDir::new("addrindex");
+    let path = dir.join("tcpping.index.json");
+    File::create(&path).unwrap().write_all(b"google.com:80\n8.8.8.8:53\ngoog").unwrap();
+
+    let change = |addr: &str| AddrChange {
+        addr: addr.to_owned(), alias_of: None, name: None, retired: None, rename_to: None,
+    };
+
+    {
+        let mut index = AddrIndex::from_path(&path).unwrap();
+        assert!(with_suffix(&path, "v1").exists());
+        assert_eq!(index.len(), 2);
+
+        // continue the history of the old address under a new one
+        let mut c = change("www.google.com:80");
+        c.alias_of = Some("google.com:80".to_owned());
+        c.name = Some("Google".to_owned());
+        index.apply(&c, &[], 0).unwrap();
+        index.ensure_for_addrs(["www.google.com:80".to_owned()].iter()).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.get_index("www.google.com:80"), 0);
+
+        let mut c = change("google.com:80");
+        c.rename_to = Some("www.google.com:80".to_owned());
+        index.apply(&c, &[], 0).unwrap();
+
+        let mut c = change("8.8.8.8:53");
+        c.retired = Some(true);
+        assert!(index.apply(&c, &["8.8.8.8:53".to_owned()], 1000).is_err());
+        index.apply(&c, &[], 1000).unwrap();
+    }
+
+    let mut index = AddrIndex::from_path(&path).unwrap();
+    let infos = index.describe(&["google.com:80".to_owned()]);
+    assert_eq!(infos[0].addr, "www.google.com:80");
+    assert_eq!(infos[0].aliases, vec!["google.com:80".to_owned()]);
+    assert_eq!(infos[0].name, Some("Google".to_owned()));
+    assert!(infos[0].active);
+    assert_eq!((infos[1].retired, infos[1].active), (Some(1000), false));
+
+    // retired entries go once the data reaches back no further, for good
+    assert_eq!(index.drop_retired_before(1000).unwrap(), 0);
+    assert_eq!(index.drop_retired_before(1001).unwrap(), 1);
+    assert_eq!(index.lookup("8.8.8.8:53"), None);
+    index.ensure_for_addrs(["8.8.8.8:53".to_owned()].iter()).unwrap();
+    assert_eq!(index.get_index("8.8.8.8:53"), 2);
+
+    // first and last seen times survive reopening once flushed
+    index.observe(&[DataRecord::new(5000, 0, 1), DataRecord::new(7000, 0, 1)]).unwrap();
+    index.observe(&[DataRecord::new(3000, 2, 1)]).unwrap();
+    index.flush_seen().unwrap();
+    let index = AddrIndex::from_path(&path).unwrap();
+    let infos = index.describe(&[]);
+    assert_eq!((infos[0].first_seen, infos[0].last_seen), (Some(5000), Some(7000)));
+    assert_eq!((infos[1].first_seen, infos[1].last_seen), (Some(3000), Some(3000)));
+    assert_eq!(index.seen_until(), 7000);
+}
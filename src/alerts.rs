@@ -0,0 +1,374 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Evaluation of the configured alert rules against live data, tracking which
+ * alerts are firing so that each is only notified of when it starts firing
+ * and when it resolves (unless silenced by a maintenance window).
+ */
+use std::f64;
+use std::collections::{BTreeMap, VecDeque};
+
+use options::{AlertRule, ALL_KINDS, SENTINEL_NODATA, SENTINEL_ERROR, SENTINEL_MISMATCH, is_failure};
+use helpers::percentile;
+use persist::TargetManager;
+
+/// the window of rules that don't specify one, in seconds
+pub static DEFAULT_WINDOW: u32 = 300;
+
+/**
+ * An alert of a rule for an address, as notified when it starts firing and
+ * when it resolves. Times are in seconds from epoch, and `value` is what the
+ * rule's condition evaluated to at `time`.
+ */
+#[derive(RustcEncodable, Clone, Debug, PartialEq)]
+pub struct Alert {
+    pub rule: String,
+    pub target: String,
+    pub addr: String,
+    pub state: String,  // "firing" or "resolved"
+    pub since: i64,  // when it started firing
+    pub time: i64,
+    pub value: f64,
+    pub message: String,
+    pub silenced: bool,  // firing during maintenance, so not notified of (yet)
+}
+
+/**
+ * What alert rules need to know about the addresses of a target beyond their
+ * values.
+ */
+pub trait AddrContext {
+    /**
+     * How many standard deviations off its baseline the last value of the
+     * given address was (see `baseline::Baselines`).
+     */
+    fn deviation(&self, addr: &str) -> Option<f64>;
+
+    /**
+     * Whether the given address is in maintenance at the given time.
+     */
+    fn in_maintenance(&self, addr: &str, time: i64) -> bool;
+}
+
+impl AddrContext for TargetManager {
+    fn deviation(&self, addr: &str) -> Option<f64> {
+        self.deviation_of(addr)
+    }
+
+    fn in_maintenance(&self, addr: &str, time: i64) -> bool {
+        TargetManager::in_maintenance(self, addr, time)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Condition {
+    P95Above,
+    LossAbove,
+    ErrorsInARow,
+    Below,
+    Anomaly,
+}
+
+impl Condition {
+    fn parse(name: &str) -> Option<Condition> {
+        match name {
+            "p95_above" => Some(Condition::P95Above),
+            "loss_above" => Some(Condition::LossAbove),
+            "errors_in_a_row" => Some(Condition::ErrorsInARow),
+            "below" => Some(Condition::Below),
+            "anomaly" => Some(Condition::Anomaly),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * What is being kept track of for one rule and one address.
+ */
+struct Watch {
+    samples: VecDeque<(i64, i32)>,  // (time, value) within the window, if needed
+    in_a_row: u32,  // consecutive failures so far (fast interval ones included)
+    last: Option<i32>,  // the last value or mismatch (attempts failing outright leave it be)
+    firing: Option<Alert>,
+}
+
+impl Watch {
+    fn new() -> Watch {
+        Watch { samples: VecDeque::new(), in_a_row: 0, last: None, firing: None }
+    }
+
+    fn add(&mut self, condition: Condition, window: i64, time: i64, val: i32) {
+        if is_failure(val) {
+            self.in_a_row += 1;
+        } else {
+            self.in_a_row = 0;
+        }
+        if val != SENTINEL_ERROR {
+            self.last = Some(val);
+        }
+
+        if condition == Condition::P95Above || condition == Condition::LossAbove {
+            self.samples.push_back((time, val));
+            while self.samples.front().map_or(false, |&(t, _)| t <= time - window) {
+                self.samples.pop_front();
+            }
+        }
+    }
+
+    /**
+     * Evaluates the given condition (other than an anomaly, which is judged
+     * against the address's baseline), returning its value, or `None` if
+     * there is nothing to go on.
+     */
+    fn evaluate(&self, condition: Condition) -> Option<f64> {
+        match condition {
+            Condition::P95Above => {
+                let mut vals: Vec<i32> = self.samples.iter()
+                    .map(|&(_, v)| v).filter(|&v| !is_failure(v)).collect();
+                vals.sort();
+                percentile(&vals, 95).map(|v| v as f64)
+            },
+            Condition::LossAbove => {
+                if self.samples.is_empty() {
+                    return None;
+                }
+                let errors = self.samples.iter().filter(|&&(_, v)| is_failure(v)).count();
+                Some(errors as f64 * 100.0 / self.samples.len() as f64)
+            },
+            Condition::ErrorsInARow => Some(self.in_a_row as f64),
+            // a mismatch (e.g. a certificate failing validation) is below any limit
+            Condition::Below => self.last.map(|v| if v == SENTINEL_MISMATCH {
+                f64::NEG_INFINITY
+            } else {
+                v as f64
+            }),
+            Condition::Anomaly => None,
+        }
+    }
+}
+
+/**
+ * The alert rules along with what is kept track of to evaluate them.
+ */
+pub struct AlertEngine {
+    rules: Vec<(AlertRule, Condition)>,
+    watches: BTreeMap<(usize, String), Watch>,  // by (rule position, addr)
+}
+
+impl AlertEngine {
+    /**
+     * Creates an engine for the given rules, or describes what is wrong with
+     * the first invalid one.
+     */
+    pub fn new(rules: &[AlertRule]) -> Result<AlertEngine, String> {
+        let mut checked = Vec::with_capacity(rules.len());
+        for rule in rules {
+            let condition = try!(Condition::parse(&rule.condition).ok_or_else(|| {
+                format!("Alert rule '{}' has unknown condition '{}'.", rule.name, rule.condition)
+            }));
+            if !ALL_KINDS.iter().any(|k| k.compact_name() == rule.target) {
+                return Err(format!("Alert rule '{}' has unknown target '{}'.", rule.name, rule.target));
+            }
+            if rule.window == Some(0) {
+                return Err(format!("Alert rule '{}' has an empty window.", rule.name));
+            }
+            checked.push((rule.clone(), condition));
+        }
+        Ok(AlertEngine {
+            rules: checked,
+            watches: BTreeMap::new(),
+        })
+    }
+
+    /**
+     * Takes into account the values (as (address, value) pairs, nodata for
+     * those not sampled, for every current address of the target) collected
+     * for the given target at the given time, returning the alerts to notify
+     * of because they started firing or resolved. Alerts starting to fire
+     * while their address is in maintenance are silenced: kept track of, but
+     * not notified of, unless still firing once the maintenance is over (and
+     * not even once resolved otherwise).
+     */
+    pub fn observe(&mut self, target: &str, time: i64, samples: &[(&str, i32)],
+                   context: &AddrContext) -> Vec<Alert> {
+        let mut changed = Vec::new();
+
+        for (pos, &(ref rule, condition)) in self.rules.iter().enumerate() {
+            if rule.target != target {
+                continue;
+            }
+            let window = rule.window.unwrap_or(DEFAULT_WINDOW) as i64;
+
+            for &(addr, val) in samples {
+                if val == SENTINEL_NODATA || rule.addr.as_ref().map_or(false, |a| a != addr) {
+                    continue;
+                }
+                let watch = self.watches.entry((pos, addr.to_owned())).or_insert_with(Watch::new);
+                watch.add(condition, window, time, val);
+
+                let evaluated = match condition {
+                    Condition::Anomaly => context.deviation(addr).map(|d| d.abs()),
+                    _ => watch.evaluate(condition),
+                };
+                let value = match evaluated {
+                    Some(v) => v,
+                    None => continue,
+                };
+                let holds = match condition {
+                    Condition::ErrorsInARow => value >= rule.limit,
+                    Condition::Below => value < rule.limit,
+                    _ => value > rule.limit,
+                };
+
+                if holds && watch.firing.is_none() {
+                    let alert = Alert {
+                        rule: rule.name.clone(),
+                        target: target.to_owned(),
+                        addr: addr.to_owned(),
+                        state: "firing".to_owned(),
+                        since: time,
+                        time: time,
+                        value: value,
+                        message: format!("{}: {} {} has {} of {} (limit {}).",
+                                         rule.name, target, addr, rule.condition, value, rule.limit),
+                        silenced: context.in_maintenance(addr, time),
+                    };
+                    watch.firing = Some(alert.clone());
+                    changed.push(alert);
+                } else if holds && watch.firing.as_ref().map_or(false, |a| a.silenced) &&
+                          !context.in_maintenance(addr, time) {
+                    // the maintenance is over, but the condition still holds
+                    let alert = watch.firing.as_mut().unwrap();
+                    alert.silenced = false;
+                    alert.time = time;
+                    alert.value = value;
+                    alert.message = format!("{}: {} {} has {} of {} (limit {}).",
+                                            rule.name, target, addr, rule.condition, value, rule.limit);
+                    changed.push(alert.clone());
+                } else if !holds && watch.firing.is_some() {
+                    let mut alert = watch.firing.take().unwrap();
+                    alert.state = "resolved".to_owned();
+                    alert.time = time;
+                    alert.value = value;
+                    alert.message = format!("{}: {} {} is back to {} of {} (limit {}).",
+                                            rule.name, target, addr, rule.condition, value, rule.limit);
+                    changed.push(alert);
+                }
+            }
+        }
+
+        // resolve the alerts of addrs no longer part of the target
+        let gone: Vec<(usize, String)> = self.watches.keys()
+            .filter(|&&(pos, ref addr)| {
+                self.rules[pos].0.target == target && !samples.iter().any(|&(a, _)| a == &addr[..])
+            })
+            .cloned().collect();
+        for key in gone {
+            if let Some(mut alert) = self.watches.remove(&key).and_then(|w| w.firing) {
+                alert.state = "resolved".to_owned();
+                alert.time = time;
+                alert.message = format!("{}: {} {} is no longer monitored.", alert.rule, target, alert.addr);
+                changed.push(alert);
+            }
+        }
+
+        changed.retain(|a| !a.silenced);
+        changed
+    }
+
+    /**
+     * Retrieves all alerts currently firing.
+     */
+    pub fn firing(&self) -> Vec<Alert> {
+        self.watches.values().filter_map(|w| w.firing.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+struct Fixed(Option<f64>, Option<&'static str>);  // (deviation of all, addr in maintenance)
+
+#[cfg(test)]
+impl AddrContext for Fixed {
+    fn deviation(&self, _: &str) -> Option<f64> {
+        self.0
+    }
+
+    fn in_maintenance(&self, addr: &str, _: i64) -> bool {
+        self.1 == Some(addr)
+    }
+}
+
+#[test]
+fn fires_and_resolves_once() {
+    use options::SENTINEL_ERROR;
+
+    let rule = |name: &str, condition: &str, limit: f64, window: Option<u32>| AlertRule {
+        name: name.to_owned(),
+        target: "tcpping".to_owned(),
+        addr: None,
+        condition: condition.to_owned(),
+        limit: limit,
+        window: window,
+    };
+    let mut engine = AlertEngine::new(&[
+        rule("down", "errors_in_a_row", 3.0, None),
+        rule("lossy", "loss_above", 50.0, Some(40)),
+    ]).unwrap();
+    assert!(AlertEngine::new(&[rule("bad", "sometimes", 1.0, None)]).is_err());
+
+    let e = SENTINEL_ERROR;
+    let a_vals = [10, e, e, e, e, 10, 10, 10, 10];
+    let mut events = Vec::new();
+    for (i, &v) in a_vals.iter().enumerate() {
+        let t = i as i64 * 10;
+        for alert in engine.observe("tcpping", t, &[("a", v), ("b", 10)], &Fixed(None, None)) {
+            events.push((alert.rule, alert.addr, alert.state, alert.time));
+        }
+    }
+    assert_eq!(events, vec![
+        ("lossy".to_owned(), "a".to_owned(), "firing".to_owned(), 20),
+        ("down".to_owned(), "a".to_owned(), "firing".to_owned(), 30),
+        ("down".to_owned(), "a".to_owned(), "resolved".to_owned(), 50),
+        ("lossy".to_owned(), "a".to_owned(), "resolved".to_owned(), 60),
+    ]);
+    assert!(engine.firing().is_empty());
+
+    // the alerts of an addr dropped from the target while firing resolve
+    for t in 10..13 {
+        engine.observe("tcpping", t * 10, &[("a", e), ("b", e)], &Fixed(None, None));
+    }
+    assert_eq!(engine.firing().len(), 4);
+    let gone = engine.observe("tcpping", 130, &[("a", e)], &Fixed(None, None));
+    assert_eq!(gone.len(), 2);
+    assert!(gone.iter().all(|a| a.addr == "b" && a.state == "resolved"));
+
+    // anomalies are judged by how far off their baselines values are
+    let mut engine = AlertEngine::new(&[rule("odd", "anomaly", 4.0, None)]).unwrap();
+    let fired = engine.observe("tcpping", 0, &[("a", 10)], &Fixed(Some(-6.5), None));
+    assert_eq!(fired.len(), 1);
+    assert_eq!((&fired[0].addr[..], fired[0].value), ("a", 6.5));
+    let resolved = engine.observe("tcpping", 10, &[("a", 10)], &Fixed(Some(0.5), None));
+    assert_eq!(resolved[0].state, "resolved");
+
+    // alerts starting to fire during maintenance are never notified of if
+    // resolved by its end
+    assert!(engine.observe("tcpping", 20, &[("a", 10)], &Fixed(Some(9.0), Some("a"))).is_empty());
+    assert_eq!(engine.firing().len(), 1);
+    assert!(engine.firing()[0].silenced);
+    assert!(engine.observe("tcpping", 30, &[("a", 10)], &Fixed(Some(0.0), None)).is_empty());
+
+    // but are once it is over, if still firing
+    assert!(engine.observe("tcpping", 40, &[("a", 10)], &Fixed(Some(9.0), Some("a"))).is_empty());
+    assert!(engine.observe("tcpping", 50, &[("a", 10)], &Fixed(Some(8.0), Some("a"))).is_empty());
+    let fired = engine.observe("tcpping", 60, &[("a", 10)], &Fixed(Some(7.0), None));
+    assert_eq!(fired.len(), 1);
+    assert_eq!((&fired[0].state[..], fired[0].since, fired[0].time, fired[0].silenced), ("firing", 40, 60, false));
+    let resolved = engine.observe("tcpping", 70, &[("a", 10)], &Fixed(Some(0.0), None));
+    assert_eq!(resolved[0].state, "resolved");
+}
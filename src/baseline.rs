@@ -0,0 +1,190 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * A learned baseline of each address's values (an exponentially weighted
+ * moving average and variance), against which values far off what is normal
+ * for that address are flagged as anomalies.
+ */
+use std::collections::{BTreeMap, VecDeque};
+
+use datafile::Record;
+use options::is_failure;
+
+/// how much weight each new value gets in the baseline (about the last 50 count)
+const ALPHA: f64 = 0.02;
+
+/// how many values a baseline needs before values are measured against it
+const WARMUP: u32 = 30;
+
+/// the most anomaly events kept (the oldest are dropped)
+pub static MAX_EVENTS: usize = 1000;
+
+/**
+ * The baseline of one address.
+ */
+#[derive(Clone, Debug)]
+pub struct Ewma {
+    mean: f64,
+    var: f64,
+    n: u32,
+    last: Option<f64>,  // how many standard deviations off the last value was
+}
+
+impl Ewma {
+    pub fn new() -> Ewma {
+        Ewma { mean: 0.0, var: 0.0, n: 0, last: None }
+    }
+
+    /**
+     * The standard deviation, floored so that a perfectly steady address
+     * doesn't make every small change infinitely anomalous.
+     */
+    pub fn stddev(&self) -> f64 {
+        self.var.sqrt().max(self.mean.abs() * 0.01).max(1.0)
+    }
+
+    /**
+     * Takes the given value into account, returning how many standard
+     * deviations off the baseline (before it) it was, once warmed up.
+     */
+    pub fn update(&mut self, val: f64) -> Option<f64> {
+        let sigmas = if self.n >= WARMUP {
+            Some((val - self.mean) / self.stddev())
+        } else {
+            None
+        };
+
+        if self.n == 0 {
+            self.mean = val;
+        } else {
+            let diff = val - self.mean;
+            let incr = ALPHA * diff;
+            self.mean += incr;
+            self.var = (1.0 - ALPHA) * (self.var + diff * incr);
+        }
+        self.n = self.n.saturating_add(1);
+        self.last = sigmas;
+        sigmas
+    }
+}
+
+/**
+ * A value found to be anomalous, in seconds from epoch (with `addr` only
+ * filled in when handed out).
+ */
+#[derive(RustcEncodable, Clone, Debug, PartialEq)]
+pub struct AnomalyEvent {
+    pub index: i32,
+    pub addr: Option<String>,
+    pub time: i64,
+    pub value: i32,
+    pub mean: f64,
+    pub stddev: f64,
+    pub sigmas: f64,
+}
+
+/**
+ * The baselines of all addresses of a target (by address index), along with
+ * the latest anomaly events.
+ */
+pub struct Baselines {
+    models: BTreeMap<i32, Ewma>,
+    events: VecDeque<AnomalyEvent>,
+}
+
+impl Baselines {
+    pub fn new() -> Baselines {
+        Baselines {
+            models: BTreeMap::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /**
+     * Takes the given records (in time order) into account, recording those
+     * more than the given number of standard deviations (if any) off their
+     * address's baseline as anomaly events. Failures don't count towards
+     * baselines.
+     */
+    pub fn observe<R: Record>(&mut self, records: &[R], sigma: Option<f64>) {
+        for r in records {
+            let model = self.models.entry(r.index()).or_insert_with(Ewma::new);
+            let val = r.val();
+            if is_failure(val) {
+                model.last = None;
+                continue;
+            }
+
+            let (mean, stddev) = (model.mean, model.stddev());
+            let sigmas = match model.update(val as f64) {
+                Some(s) => s,
+                None => continue,
+            };
+            if sigma.map_or(false, |limit| sigmas.abs() > limit) {
+                self.events.push_back(AnomalyEvent {
+                    index: r.index(),
+                    addr: None,
+                    time: r.time_ms() / 1000,
+                    value: val,
+                    mean: mean,
+                    stddev: stddev,
+                    sigmas: sigmas,
+                });
+                if self.events.len() > MAX_EVENTS {
+                    self.events.pop_front();
+                }
+            }
+        }
+    }
+
+    /**
+     * How many standard deviations off its baseline the last value of the
+     * given address index was, if it was a successful value measured against
+     * a warmed up baseline.
+     */
+    pub fn deviation(&self, index: i32) -> Option<f64> {
+        self.models.get(&index).and_then(|m| m.last)
+    }
+
+    /**
+     * Retrieves the anomaly events kept, oldest first.
+     */
+    pub fn events(&self) -> Vec<AnomalyEvent> {
+        self.events.iter().cloned().collect()
+    }
+}
+
+#[test]
+fn flags_values_off_baseline() {
+    use datafile::DataRecord;
+    use options::SENTINEL_ERROR;
+
+    let mut b = Baselines::new();
+    // two addresses with very different normal values
+    let mut records = Vec::new();
+    for i in 0..100 {
+        records.push(DataRecord::new(i * 1000, 0, 1000 + (i % 5) as i32 * 10));
+        records.push(DataRecord::new(i * 1000, 1, 50_000 + (i % 5) as i32 * 500));
+    }
+    b.observe(&records, Some(4.0));
+    assert!(b.events().is_empty());
+
+    // what is normal for one is anomalous for the other
+    b.observe(&[DataRecord::new(100_000, 0, 50_000), DataRecord::new(100_000, 1, 50_000)], Some(4.0));
+    let events = b.events();
+    assert_eq!(events.len(), 1);
+    assert_eq!((events[0].index, events[0].time, events[0].value), (0, 100, 50_000));
+    assert!(b.deviation(0).unwrap() > 4.0);
+    assert!(b.deviation(1).unwrap().abs() < 4.0);
+
+    // failures are no measure of latency
+    b.observe(&[DataRecord::new(101_000, 0, SENTINEL_ERROR)], Some(4.0));
+    assert_eq!(b.deviation(0), None);
+    assert_eq!(b.events().len(), 1);
+}
@@ -0,0 +1,398 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Compressed block format (data file version 3) for sealed data, i.e. data
+ * that will no longer be appended to.
+ *
+ * After the usual header (whose third field is the number of records per
+ * block instead of the record length), records are stored in blocks, each
+ * holding a run of rows of records taken at the same time. Timestamps are
+ * encoded as the delta-of-delta between rows, and values as the delta from
+ * the previous value of the same address in the block, all as zigzag varints,
+ * so that regularly collected, stable data takes a few bytes per record. A
+ * block index (the time range, offset and length of each block) followed by a
+ * fixed-length trailer pointing to it ends the file, allowing blocks to be
+ * found by time without decoding the ones before them.
+ */
+use std::cmp;
+use std::fs;
+use std::path::Path;
+use std::fs::{OpenOptions, File};
+use std::io::Write;
+use std::collections::HashMap;
+
+use helpers::{SPIOError, SPFile};
+use helpers::{encode_i32_le, decode_i32_le, encode_i64_le, decode_i64_le};
+use datafile::{MAGIC, HEADER_LEN, Record, DataRecord};
+use options::SENTINEL_NODATA;
+
+pub static COMPRESSED_VERSION: i32 = 3;
+
+/// a block is closed at the first row boundary after this many records
+const BLOCK_RECORDS: usize = 4096;
+/// length of a block index entry
+const INDEX_ENTRY_LEN: usize = 32;
+/// length of the trailer, [index offset (i64), number of blocks (i32), magic]
+const TRAILER_LEN: usize = 16;
+static TRAILER_MAGIC: &'static [u8; 4] = b"SIDX";
+
+/**
+ * A block's entry in the block index.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct BlockEntry {
+    pub first_ms: i64,
+    pub last_ms: i64,
+    offset: usize,
+    len: usize,
+    count: usize,
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(u: u64) -> i64 {
+    ((u >> 1) as i64) ^ -((u & 1) as i64)
+}
+
+fn put_varint(out: &mut Vec<u8>, v: i64) {
+    let mut u = zigzag(v);
+    while u >= 0x80 {
+        out.push((u as u8) | 0x80);
+        u >>= 7;
+    }
+    out.push(u as u8);
+}
+
+fn get_varint(raw: &[u8], pos: &mut usize) -> Result<i64, ()> {
+    let mut u = 0u64;
+    let mut shift = 0;
+    loop {
+        let b = try!(raw.get(*pos).cloned().ok_or(()));
+        *pos += 1;
+        if shift > 63 {
+            return Err(());
+        }
+        u |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(unzigzag(u));
+        }
+        shift += 7;
+    }
+}
+
+/**
+ * Encodes one block of (time-ordered) records, each row being
+ * [time delta-of-delta, number of records] followed by [index delta, flags,
+ * value delta, aux delta] for each record.
+ */
+fn encode_block(records: &[DataRecord]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut prev_time = records[0].time_ms();
+    let mut prev_delta = 0;
+    let mut prev_index = 0;
+    let mut prev_vals: HashMap<i32, (i32, i32)> = HashMap::new();
+
+    let mut i = 0;
+    while i < records.len() {
+        let time = records[i].time_ms();
+        let row_len = records[i..].iter().take_while(|r| r.time_ms() == time).count();
+
+        let delta = time - prev_time;
+        put_varint(&mut out, delta - prev_delta);
+        put_varint(&mut out, row_len as i64);
+        prev_time = time;
+        prev_delta = delta;
+
+        for r in &records[i..i + row_len] {
+            let index = r.index();
+            let &(prev_val, prev_aux) = prev_vals.get(&index).unwrap_or(&(0, SENTINEL_NODATA));
+            put_varint(&mut out, index as i64 - prev_index as i64);
+            put_varint(&mut out, r.flags() as i64);
+            put_varint(&mut out, r.val() as i64 - prev_val as i64);
+            put_varint(&mut out, r.aux() as i64 - prev_aux as i64);
+            prev_index = index;
+            prev_vals.insert(index, (r.val(), r.aux()));
+        }
+        i += row_len;
+    }
+    out
+}
+
+/**
+ * Decodes one block (starting at the given time) back into records.
+ */
+fn decode_block(raw: &[u8], first_ms: i64, count: usize) -> Result<Vec<DataRecord>, ()> {
+    // every record takes a few bytes, so a damaged count can't be believed beyond that
+    let mut records = Vec::with_capacity(cmp::min(count, raw.len()));
+    let mut pos = 0;
+    let mut prev_time = first_ms;
+    let mut prev_delta = 0;
+    let mut prev_index = 0i64;
+    let mut prev_vals: HashMap<i32, (i32, i32)> = HashMap::new();
+
+    while pos < raw.len() {
+        // (damaged blocks must not overflow)
+        let delta = prev_delta.wrapping_add(try!(get_varint(raw, &mut pos)));
+        let time = prev_time.wrapping_add(delta);
+        prev_time = time;
+        prev_delta = delta;
+
+        let row_len = try!(get_varint(raw, &mut pos));
+        for _ in 0..row_len {
+            let index = prev_index.wrapping_add(try!(get_varint(raw, &mut pos))) as i32;
+            let &(prev_val, prev_aux) = prev_vals.get(&index).unwrap_or(&(0, SENTINEL_NODATA));
+            let flags = try!(get_varint(raw, &mut pos)) as i32;
+            let val = (prev_val as i64).wrapping_add(try!(get_varint(raw, &mut pos))) as i32;
+            let aux = (prev_aux as i64).wrapping_add(try!(get_varint(raw, &mut pos))) as i32;
+            records.push(DataRecord::with_all(time, index, flags, val, aux));
+            prev_index = index as i64;
+            prev_vals.insert(index, (val, aux));
+        }
+    }
+
+    if records.len() == count {
+        Ok(records)
+    } else {
+        Err(())
+    }
+}
+
+/**
+ * Encodes the given (time-ordered) records as a whole compressed file.
+ */
+pub fn encode_file(records: &[DataRecord]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&encode_i32_le(COMPRESSED_VERSION));
+    out.extend_from_slice(&encode_i32_le(BLOCK_RECORDS as i32));
+
+    // cut the records into blocks, only ever between rows
+    let mut entries = Vec::new();
+    let mut begin = 0;
+    while begin < records.len() {
+        let mut end = begin + BLOCK_RECORDS;
+        if end >= records.len() {
+            end = records.len();
+        } else {
+            let time = records[end - 1].time_ms();
+            while end < records.len() && records[end].time_ms() == time {
+                end += 1;
+            }
+        }
+
+        let block = &records[begin..end];
+        let payload = encode_block(block);
+        entries.push(BlockEntry {
+            first_ms: block[0].time_ms(),
+            last_ms: block[block.len() - 1].time_ms(),
+            offset: out.len(),
+            len: payload.len(),
+            count: block.len(),
+        });
+        out.extend_from_slice(&payload);
+        begin = end;
+    }
+
+    let index_offset = out.len();
+    for e in entries.iter() {
+        out.extend_from_slice(&encode_i64_le(e.first_ms));
+        out.extend_from_slice(&encode_i64_le(e.last_ms));
+        out.extend_from_slice(&encode_i64_le(e.offset as i64));
+        out.extend_from_slice(&encode_i32_le(e.len as i32));
+        out.extend_from_slice(&encode_i32_le(e.count as i32));
+    }
+    out.extend_from_slice(&encode_i64_le(index_offset as i64));
+    out.extend_from_slice(&encode_i32_le(entries.len() as i32));
+    out.extend_from_slice(TRAILER_MAGIC);
+    out
+}
+
+/**
+ * Writes the given (time-ordered) records out as a compressed file at the
+ * given path, by way of a temporary file swapped in once complete.
+ */
+pub fn write_file(path: &Path, records: &[DataRecord]) -> Result<(), SPIOError> {
+    let mut tmp_path = path.to_owned();
+    tmp_path.set_extension("dat.tmp");
+    {
+        let mut tmp = try!(File::open_from(
+            OpenOptions::new().write(true).truncate(true).create(true), &tmp_path));
+        try!(
+            tmp.write_all(&encode_file(records))
+            .and_then(|_| tmp.sync_all())
+            .map_err(|_| SPIOError::Write(Some(tmp_path.clone())))
+        );
+    }
+    fs::rename(&tmp_path, path).map_err(|_| SPIOError::Write(Some(path.to_owned())))
+}
+
+fn read_i64(raw: &[u8], at: usize) -> i64 {
+    let mut b = [0u8; 8];
+    b.copy_from_slice(&raw[at..at + 8]);
+    decode_i64_le(b)
+}
+
+fn read_i32(raw: &[u8], at: usize) -> i32 {
+    let mut b = [0u8; 4];
+    b.copy_from_slice(&raw[at..at + 4]);
+    decode_i32_le(b)
+}
+
+/**
+ * Read access to the raw contents of a compressed file (e.g. as mapped into
+ * memory), by way of its block index.
+ */
+pub struct CompressedFile<'a> {
+    raw: &'a [u8],
+    blocks: Vec<BlockEntry>,
+}
+
+impl<'a> CompressedFile<'a> {
+    /**
+     * Reads the block index of a compressed file, or returns `None` if the
+     * contents aren't one (or are damaged).
+     */
+    pub fn parse(raw: &'a [u8]) -> Option<Self> {
+        if raw.len() < HEADER_LEN + TRAILER_LEN || &raw[..MAGIC.len()] != &MAGIC[..] ||
+                read_i32(raw, MAGIC.len()) != COMPRESSED_VERSION ||
+                &raw[raw.len() - TRAILER_MAGIC.len()..] != &TRAILER_MAGIC[..] {
+            return None;
+        }
+
+        let trailer = raw.len() - TRAILER_LEN;
+        let index_offset = read_i64(raw, trailer) as usize;
+        let num_blocks = read_i32(raw, trailer + 8) as usize;
+        // (damaged offsets and lengths must not overflow)
+        let index_end = num_blocks.checked_mul(INDEX_ENTRY_LEN).and_then(|len| index_offset.checked_add(len));
+        if index_offset < HEADER_LEN || index_end != Some(trailer) {
+            return None;
+        }
+
+        let mut blocks = Vec::with_capacity(num_blocks);
+        for i in 0..num_blocks {
+            let at = index_offset + i * INDEX_ENTRY_LEN;
+            let e = BlockEntry {
+                first_ms: read_i64(raw, at),
+                last_ms: read_i64(raw, at + 8),
+                offset: read_i64(raw, at + 16) as usize,
+                len: read_i32(raw, at + 24) as usize,
+                count: read_i32(raw, at + 28) as usize,
+            };
+            if e.offset < HEADER_LEN || e.offset.checked_add(e.len).map_or(true, |end| end > index_offset) {
+                return None;
+            }
+            blocks.push(e);
+        }
+
+        Some(CompressedFile {
+            raw: raw,
+            blocks: blocks,
+        })
+    }
+
+    pub fn blocks(&self) -> &[BlockEntry] {
+        &self.blocks
+    }
+
+    /**
+     * Finds the blocks (as a range of positions in `blocks()`) that may hold
+     * records in the given time range, in milliseconds from epoch.
+     */
+    pub fn blocks_in_range(&self, lower_ms: i64, upper_ms: i64) -> (usize, usize) {
+        let begin = match self.blocks.binary_search_by_key(&lower_ms, |b| b.last_ms) {
+            Ok(mut i) => {
+                while i > 0 && self.blocks[i - 1].last_ms == lower_ms {
+                    i -= 1;
+                }
+                i
+            },
+            Err(i) => i,
+        };
+        let end = begin + self.blocks[begin..].iter().take_while(|b| b.first_ms <= upper_ms).count();
+        (begin, end)
+    }
+
+    /**
+     * Decodes the records of the block at the given position.
+     */
+    pub fn decode(&self, i: usize) -> Option<Vec<DataRecord>> {
+        let e = self.blocks[i];
+        decode_block(&self.raw[e.offset..e.offset + e.len], e.first_ms, e.count).ok()
+    }
+}
+
+#[test]
+fn varints_round_trip() {
+    for &v in &[0i64, 1, -1, 63, -64, 64, 1 << 40, i64::max_value(), i64::min_value()] {
+        let mut buf = Vec::new();
+        put_varint(&mut buf, v);
+        let mut pos = 0;
+        assert_eq!(get_varint(&buf, &mut pos), Ok(v));
+        assert_eq!(pos, buf.len());
+    }
+    assert_eq!(get_varint(&[0x80], &mut 0), Err(()));
+}
+
+#[test]
+fn compressed_file_round_trips_and_finds_blocks() {
+    use std::mem;
+    use options::SENTINEL_ERROR;
+
+    // three addresses every ten seconds, with the odd error
+    let mut records = Vec::new();
+    for t in 0..5000i64 {
+        for i in 0..3 {
+            let val = if t % 97 == 0 { SENTINEL_ERROR } else { 20_000 + (t % 7) as i32 * 100 + i };
+            records.push(DataRecord::new(1_500_000_000_000 + t * 10_000, i, val));
+        }
+    }
+
+    let raw = encode_file(&records);
+    // well under a quarter of the uncompressed size
+    assert!(raw.len() * 4 < records.len() * mem::size_of::<DataRecord>());
+
+    let file = CompressedFile::parse(&raw).unwrap();
+    assert!(file.blocks().len() > 1);
+    let mut decoded = Vec::new();
+    for i in 0..file.blocks().len() {
+        decoded.extend_from_slice(&file.decode(i).unwrap());
+    }
+    assert_eq!(decoded.len(), records.len());
+    for (a, b) in decoded.iter().zip(records.iter()) {
+        assert_eq!((a.time_ms(), a.index(), a.val(), a.aux()), (b.time_ms(), b.index(), b.val(), b.aux()));
+    }
+
+    // rows are never split across blocks
+    for w in file.blocks().windows(2) {
+        assert!(w[0].last_ms < w[1].first_ms);
+    }
+
+    let t = 1_500_000_000_000 + 4000 * 10_000;
+    let (begin, end) = file.blocks_in_range(t, t);
+    assert_eq!(end - begin, 1);
+    assert!(file.decode(begin).unwrap().iter().any(|r| r.time_ms() == t));
+    assert_eq!(file.blocks().last().unwrap().last_ms, 1_500_000_000_000 + 4999 * 10_000);
+
+    assert!(CompressedFile::parse(&raw[..raw.len() - 1]).is_none());
+
+    // damaged offsets, lengths and counts are refused rather than overflowing
+    let trailer = raw.len() - TRAILER_LEN;
+    let index_offset = read_i64(&raw, trailer) as usize;
+    let mut damaged = raw.clone();
+    damaged[trailer..trailer + 8].copy_from_slice(&encode_i64_le(-1));
+    assert!(CompressedFile::parse(&damaged).is_none());
+    let mut damaged = raw.clone();
+    damaged[index_offset + 16..index_offset + 24].copy_from_slice(&encode_i64_le(-1));
+    assert!(CompressedFile::parse(&damaged).is_none());
+    let mut damaged = raw.clone();
+    damaged[index_offset + 28..index_offset + 32].copy_from_slice(&encode_i32_le(i32::max_value()));
+    assert!(CompressedFile::parse(&damaged).unwrap().decode(0).is_none());
+}
@@ -0,0 +1,48 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+use std::thread;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use options::MainConfiguration;
+use persist::TargetManager;
+
+/// how often the compactor checks targets against their retention policy
+const COMPACTION_PERIOD_SECS: u64 = 600;
+
+/**
+ * Runs the background compactor, which periodically deletes every target's
+ * expired data segments according to the target's retention policy (or the
+ * configuration's, if the target doesn't have its own).
+ */
+pub fn run_compactor(configuration: Arc<RwLock<MainConfiguration>>,
+                     targets: Vec<Arc<TargetManager>>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        loop {
+            for tm in targets.iter() {
+                let policy = {
+                    let options = tm.options_read();
+                    match options.retention {
+                        Some(ref p) => Some(p.clone()),
+                        None => configuration.read().unwrap().retention.clone(),
+                    }
+                };
+
+                if let Some(p) = policy {
+                    // failing to compact is not fatal, we can try again later
+                    if let Err(e) = tm.compact(&p) {
+                        println!("Failed to compact {} data: {}", tm.kind.compact_name(), e);
+                    }
+                }
+            }
+
+            thread::sleep(Duration::from_secs(COMPACTION_PERIOD_SECS));
+        }
+    })
+}
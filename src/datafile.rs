@@ -0,0 +1,395 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * On-disk formats of a target's data file, and migration between them.
+ *
+ * Version 1 files are headerless back-to-back [time, index, val] triplets of
+ * 32-bit integers in host byte order, with time in seconds from epoch.
+ * Version 2 files begin with a header (magic, version and record length),
+ * followed by back-to-back records with 64-bit millisecond timestamps and room
+ * for flags and a secondary value, all little-endian. Version 3 files hold
+ * sealed data in a compressed block format (see `blockfile`).
+ */
+use std::io;
+use std::fs;
+use std::cmp;
+use std::mem;
+use std::path::Path;
+use std::sync::RwLock;
+use std::fs::{OpenOptions, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use memmap::{Mmap, Protection};
+
+use helpers::{SPIOError, SPFile, raw_bytes_as_slice, slice_as_raw_bytes, sync_parent_dir};
+use helpers::{encode_i32_le, decode_i32_le, encode_i64_le, decode_i64_le};
+use options::SENTINEL_NODATA;
+
+pub static MAGIC: &'static [u8; 8] = b"STABPING";
+pub static CURRENT_VERSION: i32 = 2;
+pub const HEADER_LEN: usize = 16;
+
+/**
+ * Representation of version 1 data elements on-disk in a target's data file.
+ * They are 32-bit back-to-back integers (in the byte order of the host that
+ * wrote them).
+ */
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct DataElementV1 {
+    pub time: i32,
+    pub index: i32,
+    pub val: i32,
+}
+
+/**
+ * Representation of version 2 data records on-disk in a target's data file.
+ * All fields are little-endian integers, kept as bytes so that records can be
+ * read straight out of a memory-mapped file on a host of any byte order.
+ */
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct DataRecord {
+    time: [u8; 8],  // milliseconds from epoch
+    index: [u8; 4],
+    flags: [u8; 4],  // bit flags about this record, currently all reserved (zero)
+    val: [u8; 4],
+    aux: [u8; 4],  // kind-defined secondary value, or the nodata sentinel
+}
+
+impl DataRecord {
+    pub fn new(time_ms: i64, index: i32, val: i32) -> Self {
+        DataRecord {
+            time: encode_i64_le(time_ms),
+            index: encode_i32_le(index),
+            flags: encode_i32_le(0),
+            val: encode_i32_le(val),
+            aux: encode_i32_le(SENTINEL_NODATA),
+        }
+    }
+
+    pub fn with_all(time_ms: i64, index: i32, flags: i32, val: i32, aux: i32) -> Self {
+        DataRecord {
+            time: encode_i64_le(time_ms),
+            index: encode_i32_le(index),
+            flags: encode_i32_le(flags),
+            val: encode_i32_le(val),
+            aux: encode_i32_le(aux),
+        }
+    }
+
+    pub fn flags(&self) -> i32 { decode_i32_le(self.flags) }
+}
+
+/**
+ * Common read access to the records of any version of the data file.
+ */
+pub trait Record: Copy {
+    fn time_ms(&self) -> i64;
+    fn index(&self) -> i32;
+    fn val(&self) -> i32;
+    /// the secondary (kind-defined) value, nodata for records without one
+    fn aux(&self) -> i32 { SENTINEL_NODATA }
+}
+
+impl Record for DataElementV1 {
+    fn time_ms(&self) -> i64 { self.time as i64 * 1000 }
+    fn index(&self) -> i32 { self.index }
+    fn val(&self) -> i32 { self.val }
+}
+
+impl Record for DataRecord {
+    fn time_ms(&self) -> i64 { decode_i64_le(self.time) }
+    fn index(&self) -> i32 { decode_i32_le(self.index) }
+    fn val(&self) -> i32 { decode_i32_le(self.val) }
+    fn aux(&self) -> i32 { decode_i32_le(self.aux) }
+}
+
+/**
+ * Finds the position of the first record at or after the given time in a
+ * (time-ordered) slice of records.
+ */
+pub fn lower_bound<R: Record>(data: &[R], time_ms: i64) -> usize {
+    match data.binary_search_by_key(&time_ms, |d| d.time_ms()) {
+        Ok(mut i) => {
+            while i > 0 && data[i - 1].time_ms() == time_ms {
+                i -= 1;
+            }
+            i
+        },
+        Err(i) => i
+    }
+}
+
+/**
+ * Encodes the header of a current-version data file.
+ */
+pub fn header_bytes() -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&encode_i32_le(CURRENT_VERSION));
+    header.extend_from_slice(&encode_i32_le(mem::size_of::<DataRecord>() as i32));
+    header
+}
+
+/**
+ * Detects the version of a data file from its raw contents: 2 (or later) if
+ * it starts with our header, otherwise 1.
+ */
+pub fn detect_version(raw: &[u8]) -> i32 {
+    if raw.len() >= HEADER_LEN && &raw[..MAGIC.len()] == &MAGIC[..] {
+        let mut version = [0u8; 4];
+        version.copy_from_slice(&raw[MAGIC.len()..MAGIC.len() + 4]);
+        decode_i32_le(version)
+    } else {
+        1
+    }
+}
+
+/**
+ * Migrates the version 1 data file at the given path (if it is one) to the
+ * current version in-place, leaving the original next to it with a `.v1`
+ * suffix. Returns whether a migration was done.
+ */
+pub fn migrate_if_needed(path: &Path) -> Result<bool, SPIOError> {
+    let mut file = try!(File::open_from(OpenOptions::new().read(true), path));
+    if try!(file.length_p(path)) == 0 {
+        return Ok(false);
+    }
+
+    let map = try!(
+        Mmap::open(&file, Protection::Read)
+        .map_err(|_| SPIOError::Read(Some(path.to_owned())))
+    );
+    let raw = unsafe { map.as_slice() };
+    let version = detect_version(raw);
+    if version != 1 {
+        return Ok(false);
+    }
+
+    // a current-version file torn while writing its header isn't v1 either
+    let prefix_len = cmp::min(raw.len(), MAGIC.len());
+    if raw.len() < HEADER_LEN && &raw[..prefix_len] == &MAGIC[..prefix_len] {
+        return Ok(false);
+    }
+
+    /*
+     * convert every whole v1 element (ignoring any partial one at the end,
+     * which remains in the original)
+     */
+    let whole = raw.len() - raw.len() % mem::size_of::<DataElementV1>();
+    let old: &[DataElementV1] = raw_bytes_as_slice(&raw[..whole]).unwrap();
+    let records: Vec<DataRecord> = old.iter()
+        .map(|e| DataRecord::new(e.time_ms(), e.index, e.val))
+        .collect();
+
+    let mut tmp_path = path.to_owned();
+    tmp_path.set_extension("dat.tmp");
+    {
+        let mut tmp = try!(File::open_from(
+            OpenOptions::new().write(true).truncate(true).create(true), &tmp_path));
+        try!(
+            tmp.write_all(&header_bytes())
+            .and_then(|_| tmp.write_all(slice_as_raw_bytes(&records)))
+            .and_then(|_| tmp.sync_all())
+            .map_err(|_| SPIOError::Write(Some(tmp_path.clone())))
+        );
+    }
+
+    /*
+     * keep a (durable) copy of the original around, and swap in the migrated
+     * file with a single rename, so that a crash at any point leaves either
+     * the original or the migrated file in place
+     */
+    let mut backup_path = path.to_owned();
+    backup_path.set_extension("dat.v1");
+    drop(map);
+    try!(
+        fs::copy(path, &backup_path)
+        .and_then(|_| File::open(&backup_path))
+        .and_then(|f| f.sync_all())
+        .map_err(|_| SPIOError::Write(Some(backup_path.clone())))
+    );
+    try!(fs::rename(&tmp_path, path).map_err(|_| SPIOError::Write(Some(path.to_owned()))));
+    sync_parent_dir(path);
+
+    println!("Migrated {} records of data file '{}' to version {} (original kept as '{}').",
+             records.len(), path.to_str().unwrap_or(""), CURRENT_VERSION,
+             backup_path.to_str().unwrap_or(""));
+    Ok(true)
+}
+
+/**
+ * Drops the first `cut` bytes following the header of the file (of
+ * fixed-length, append-only records) at the given path, guarded by the given
+ * lock, by copying the rest into a new file and swapping it in.
+ *
+ * As records already written never change, the bulk of the copying happens
+ * without holding the lock. Only records appended in the meantime are copied
+ * (and the new file swapped in) under the write lock, so appends are only
+ * blocked briefly.
+ */
+pub fn compact_front(file_lock: &RwLock<File>, path: &Path, header_len: usize, cut: u64) -> Result<(), SPIOError> {
+    let metadata_err = |_| SPIOError::Metadata(Some(path.to_owned()));
+    let len = try!(file_lock.read().unwrap().metadata().map_err(&metadata_err)).len();
+
+    let mut tmp_path = path.to_owned();
+    tmp_path.set_extension("dat.tmp");
+    let read_err = |_| SPIOError::Read(Some(path.to_owned()));
+    let write_err = |_| SPIOError::Write(Some(tmp_path.clone()));
+
+    let mut src = try!(File::open_from(OpenOptions::new().read(true), path));
+    let mut tmp = try!(File::open_from(
+        OpenOptions::new().write(true).truncate(true).create(true), &tmp_path));
+
+    // copy the header, and then everything after the cut (as of now)
+    let mut buf = Vec::new();
+    try!((&src).take(header_len as u64).read_to_end(&mut buf).map_err(&read_err));
+    try!(tmp.write_all(&buf).map_err(&write_err));
+    try!(src.seek(SeekFrom::Start(header_len as u64 + cut)).map_err(&read_err));
+    try!(io::copy(&mut (&src).take(len - header_len as u64 - cut), &mut tmp).map_err(&write_err));
+
+    let mut guard = file_lock.write().unwrap();
+
+    // catch up on anything appended while we were copying
+    let new_len = try!(guard.metadata().map_err(&metadata_err)).len();
+    try!(src.seek(SeekFrom::Start(len)).map_err(&read_err));
+    try!(io::copy(&mut (&src).take(new_len - len), &mut tmp).map_err(&write_err));
+    try!(tmp.sync_all().map_err(&write_err));
+
+    try!(fs::rename(&tmp_path, path).map_err(|_| SPIOError::Write(Some(path.to_owned()))));
+    *guard = try!(File::open_from(OpenOptions::new().read(true).append(true), path));
+    Ok(())
+}
+
+/**
+ * Repairs a file of fixed-length records (after a header of the given length)
+ * whose last record was only partially written, e.g. because we crashed in
+ * the middle of appending it. The partial record (or partial header) is moved
+ * aside, appended to a file next to it with a `.torn` suffix, and the file is
+ * truncated to its last whole record. Returns the number of bytes moved aside.
+ */
+pub fn repair_torn_tail(path: &Path, header_len: usize, record_len: usize) -> Result<u64, SPIOError> {
+    let mut file = try!(File::open_from(OpenOptions::new().read(true).write(true), path));
+    let len = try!(file.length_p(path));
+
+    let whole = if len < header_len as u64 {
+        0
+    } else {
+        len - (len - header_len as u64) % record_len as u64
+    };
+    if whole == len {
+        return Ok(0);
+    }
+
+    // read out the torn tail
+    let mut tail = Vec::new();
+    try!(
+        file.seek(SeekFrom::Start(whole))
+        .and_then(|_| file.read_to_end(&mut tail))
+        .map_err(|_| SPIOError::Read(Some(path.to_owned())))
+    );
+
+    // move it aside before truncating it away, so that nothing is lost
+    let mut torn_path = path.to_owned();
+    let torn_ext = format!("{}.torn", path.extension().and_then(|e| e.to_str()).unwrap_or(""));
+    torn_path.set_extension(torn_ext);
+    try!(
+        File::open_from(OpenOptions::new().append(true).create(true), &torn_path)
+        .and_then(|mut f| f.write_all(&tail).and_then(|_| f.sync_all())
+                  .map_err(|_| SPIOError::Write(Some(torn_path.clone()))))
+    );
+    try!(
+        file.set_len(whole)
+        .and_then(|_| file.sync_all())
+        .map_err(|_| SPIOError::Write(Some(path.to_owned())))
+    );
+
+    println!("Repaired torn tail of '{}': moved {} trailing bytes to '{}'.",
+             path.to_str().unwrap_or(""), tail.len(), torn_path.to_str().unwrap_or(""));
+    Ok(tail.len() as u64)
+}
+
+#[test]
+fn repair_torn_record_and_header() {
+    use helpers::TempDir;
+
+    let dir = TempDir::new("torn");
+    let path = dir.join("tcpping.data.dat");
+    let record_len = mem::size_of::<DataRecord>();
+
+    // a header, a whole record, and five bytes of the next one
+    {
+        let mut f = File::create(&path).unwrap();
+        f.write_all(&header_bytes()).unwrap();
+        f.write_all(&slice_as_raw_bytes(&[DataRecord::new(1000, 0, 5); 2])[..record_len + 5]).unwrap();
+    }
+    assert_eq!(repair_torn_tail(&path, HEADER_LEN, record_len).unwrap(), 5);
+    assert_eq!(fs::metadata(&path).unwrap().len(), (HEADER_LEN + record_len) as u64);
+    assert_eq!(fs::metadata(dir.join("tcpping.data.dat.torn")).unwrap().len(), 5);
+    // nothing left to repair
+    assert_eq!(repair_torn_tail(&path, HEADER_LEN, record_len).unwrap(), 0);
+
+    // a header torn part-way through is neither migrated nor kept
+    {
+        let mut f = File::create(&path).unwrap();
+        f.write_all(&header_bytes()[..10]).unwrap();
+    }
+    assert!(!migrate_if_needed(&path).unwrap());
+    assert_eq!(repair_torn_tail(&path, HEADER_LEN, record_len).unwrap(), 10);
+    assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+}
+
+#[test]
+fn migrate_v1_file() {
+    use helpers::TempDir;
+
+    let dir = TempDir::new("migrate");
+    let path = dir.join("tcpping.data.dat");
+    {
+        let mut f = File::create(&path).unwrap();
+        // v1 files are in host byte order
+        f.write_all(slice_as_raw_bytes(&[100i32, 0, 5, 100, 1, 6, 110, 0, 7])).unwrap();
+    }
+
+    assert!(migrate_if_needed(&path).unwrap());
+    // a second run has nothing to do
+    assert!(!migrate_if_needed(&path).unwrap());
+
+    let mut raw = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut raw).unwrap();
+    assert_eq!(detect_version(&raw), 2);
+    let records: &[DataRecord] = raw_bytes_as_slice(&raw[HEADER_LEN..]).unwrap();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[2].time_ms(), 110_000);
+    assert_eq!(records[1].index(), 1);
+    assert_eq!(records[1].val(), 6);
+    assert!(dir.join("tcpping.data.dat.v1").exists());
+}
+
+#[test]
+fn record_bytes_are_little_endian() {
+    let bytes = [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01,
+                 3, 0, 0, 0,
+                 0, 0, 0, 0,
+                 0xfe, 0xff, 0xff, 0xff,
+                 0x00, 0x6c, 0xca, 0x88];
+    let record = DataRecord::new(0x0102030405060708, 3, -2);
+    assert_eq!(slice_as_raw_bytes(&[record]), &bytes[..]);
+
+    // the same fixed bytes (which a host of either byte order writes) decode here
+    let records: &[DataRecord] = raw_bytes_as_slice(&bytes).unwrap();
+    assert_eq!(records[0].time_ms(), 0x0102030405060708);
+    assert_eq!(records[0].index(), 3);
+    assert_eq!(records[0].val(), -2);
+
+    let header = header_bytes();
+    assert_eq!(&header[8..], &[2, 0, 0, 0, 24, 0, 0, 0][..]);
+    assert_eq!(detect_version(&header), 2);
+}
@@ -0,0 +1,159 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Exclusive lock on the data directory, so that only one stabping process at
+ * a time appends to (or otherwise modifies) the files in it.
+ */
+use std::fmt;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::fs::{OpenOptions, File};
+use std::io::{Read, Write};
+use std::process;
+
+use helpers::{SPIOError, SPFile};
+
+static LOCK_FILENAME: &'static str = "stabping.lock";
+
+/**
+ * Error container for failing to lock the data directory.
+ */
+#[derive(Debug)]
+pub enum LockError {
+    /// another process holds the lock (with its PID, if it could be read)
+    Held(PathBuf, Option<u32>),
+    LockFileIO(SPIOError),
+}
+
+impl Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LockError::Held(ref path, Some(pid)) => write!(f,
+                "Data directory is in use by another stabping process (PID {}), holding the lock '{}'.",
+                pid, path.to_str().unwrap()),
+            LockError::Held(ref path, None) => write!(f,
+                "Data directory is in use by another stabping process, holding the lock '{}'.",
+                path.to_str().unwrap()),
+            LockError::LockFileIO(ref e) => write!(f, "{} lock file.", e.description()),
+        }
+    }
+}
+
+/**
+ * A held lock on a data directory, released when dropped (or when the process
+ * exits, however it does).
+ */
+pub struct DataLock {
+    #[allow(dead_code)]
+    file: File,
+    #[allow(dead_code)]
+    path: PathBuf,
+}
+
+/**
+ * Reads the PID recorded in the given lock file by the process holding it.
+ */
+fn holder_pid(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).ok().and_then(|_| contents.trim().parse().ok())
+}
+
+#[cfg(unix)]
+fn try_lock(file: &File) -> Result<bool, ()> {
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use libc;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        Ok(true)
+    } else if io::Error::last_os_error().raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(false)
+    } else {
+        Err(())
+    }
+}
+
+impl DataLock {
+    /**
+     * Takes the exclusive lock on the given data directory, recording our PID
+     * in its lock file, or fails naming the PID of the process that holds it.
+     *
+     * On Unix the lock is an advisory `flock` on the lock file, which the
+     * system releases even if we crash. Elsewhere the lock file is created
+     * exclusively and removed again when the lock is dropped.
+     */
+    #[cfg(unix)]
+    pub fn acquire(data_path: &Path) -> Result<DataLock, LockError> {
+        let path = data_path.join(LOCK_FILENAME);
+        let mut file = try!(
+            File::open_from(OpenOptions::new().read(true).write(true).create(true), &path)
+            .map_err(|e| LockError::LockFileIO(e))
+        );
+
+        match try_lock(&file) {
+            Ok(true) => {},
+            Ok(false) => return Err(LockError::Held(path.clone(), holder_pid(&mut file))),
+            Err(()) => return Err(LockError::LockFileIO(SPIOError::Open(Some(path)))),
+        }
+
+        try!(
+            file.set_len(0)
+            .and_then(|_| file.write_all(format!("{}\n", process::id()).as_bytes()))
+            .and_then(|_| file.sync_data())
+            .map_err(|_| LockError::LockFileIO(SPIOError::Write(Some(path.clone()))))
+        );
+
+        Ok(DataLock { file: file, path: path })
+    }
+
+    #[cfg(not(unix))]
+    pub fn acquire(data_path: &Path) -> Result<DataLock, LockError> {
+        let path = data_path.join(LOCK_FILENAME);
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(_) => {
+                let pid = File::open(&path).ok().and_then(|mut f| holder_pid(&mut f));
+                return Err(LockError::Held(path, pid));
+            },
+        };
+
+        try!(
+            file.write_all(format!("{}\n", process::id()).as_bytes())
+            .and_then(|_| file.sync_data())
+            .map_err(|_| LockError::LockFileIO(SPIOError::Write(Some(path.clone()))))
+        );
+
+        Ok(DataLock { file: file, path: path })
+    }
+}
+
+#[cfg(not(unix))]
+impl Drop for DataLock {
+    fn drop(&mut self) {
+        let _ = ::std::fs::remove_file(&self.path);
+    }
+}
+
+#[test]
+fn refuses_second_lock_naming_holder() {
+    use helpers::TempDir;
+
+    let dir = TempDir::new("datalock");
+
+    {
+        let _lock = DataLock::acquire(&dir).unwrap();
+        match DataLock::acquire(&dir) {
+            Err(LockError::Held(_, pid)) => assert_eq!(pid, Some(process::id())),
+            _ => panic!("data directory locked twice"),
+        }
+    }
+
+    // once released, it can be taken again
+    DataLock::acquire(&dir).unwrap();
+}
@@ -0,0 +1,159 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * A daily digest by email of how available each target's addresses were over
+ * the past day, naming the worst of them.
+ */
+use std::cmp::Ordering;
+use std::thread;
+use std::sync::{Arc, RwLock};
+use std::time::Duration as StdDuration;
+
+use chrono::{Local, DateTime};
+
+use options::MainConfiguration;
+use persist::TargetManager;
+use stats;
+use stats::{StatsRequest, AddrStats};
+use smtp;
+
+/// how many of the least available addresses of each target to name
+const WORST_SHOWN: usize = 3;
+
+/**
+ * Describes the given statistics of a target's addresses: its overall
+ * availability (that of each address, weighted by the time it covers), and
+ * the least available of them.
+ */
+fn describe(kind_name: &str, all_stats: &[AddrStats]) -> String {
+    let (attempts, covered, up) = all_stats.iter().fold((0, 0, 0.0), |(n, c, u), s| {
+        (n + s.count + s.errors, c + s.covered, u + s.availability.unwrap_or(0.0) * s.covered as f64)
+    });
+    if covered == 0 {
+        return format!("{}: no data\n", kind_name);
+    }
+    let mut text = format!("{}: {:.2}% available over {} attempts\n", kind_name,
+                           up / covered as f64, attempts);
+
+    let mut worst: Vec<&AddrStats> = all_stats.iter()
+        .filter(|s| s.availability.map_or(false, |a| a < 100.0))
+        .collect();
+    worst.sort_by(|a, b| a.availability.partial_cmp(&b.availability).unwrap_or(Ordering::Equal));
+    if worst.is_empty() {
+        text.push_str("  no failures\n");
+    }
+    for s in worst.iter().take(WORST_SHOWN) {
+        text.push_str(&format!(
+            "  {}: {:.2}% available, {} outage(s) (longest {}s), p95 {}\n",
+            s.addr.as_ref().map_or("?", |a| &a[..]), s.availability.unwrap_or(0.0),
+            s.outages, s.longest_outage, s.p95.map_or("-".to_owned(), |p| format!("{}", p))));
+    }
+    text
+}
+
+/**
+ * Puts together the digest of the given targets for the day up to now.
+ */
+fn digest(targets: &[Arc<TargetManager>]) -> String {
+    let now = Local::now();
+    let upper = now.timestamp() as i32;
+    let mut text = format!("Availability over the day up to {}:\n", now.to_rfc2822());
+    for tm in targets.iter() {
+        let sr = StatsRequest::current(tm, upper - 86400, upper, Some(60));
+        text.push('\n');
+        text.push_str(&match stats::compute(tm, sr) {
+            Some(Ok(all_stats)) => describe(tm.kind.compact_name(), &all_stats),
+            Some(Err(e)) => format!("{}: failed to read data ({})\n", tm.kind.compact_name(), e),
+            None => format!("{}: no data\n", tm.kind.compact_name()),
+        });
+    }
+    text
+}
+
+/**
+ * When the digest is next due after the given time: at the start of the given
+ * local hour, or of the first hour after it that exists (and isn't repeated)
+ * on a day it doesn't because of a DST change.
+ */
+fn next_digest(now: DateTime<Local>, hour: u32) -> DateTime<Local> {
+    let mut date = now.date();
+    loop {
+        if let Some(next) = (hour..24).filter_map(|h| date.and_hms_opt(h, 0, 0)).next() {
+            if next > now {
+                return next;
+            }
+        }
+        date = date.succ();
+    }
+}
+
+/**
+ * Runs the digest thread, which emails a digest of the given targets every
+ * day at the configured hour, if the configuration has SMTP settings with a
+ * digest hour (or describes what is wrong with it).
+ */
+pub fn run_digest(configuration: Arc<RwLock<MainConfiguration>>,
+                  targets: Vec<Arc<TargetManager>>) -> Result<Option<thread::JoinHandle<()>>, String> {
+    let (settings, hour) = match configuration.read().unwrap().smtp {
+        Some(ref s) => match s.digest_hour {
+            Some(hour) => (s.clone(), hour),
+            None => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+    if hour > 23 {
+        return Err(format!("Digest hour {} is not an hour of the day (0 to 23).", hour));
+    }
+
+    Ok(Some(thread::spawn(move || {
+        loop {
+            // wait until the digest hour comes around again
+            let now = Local::now();
+            let wait = (next_digest(now, hour) - now).num_seconds();
+            thread::sleep(StdDuration::from_secs(if wait > 0 { wait as u64 } else { 1 }));
+
+            let subject = format!("[stabping] Daily digest for {}", Local::now().format("%Y-%m-%d"));
+            if let Err(e) = smtp::send_mail(&settings, &subject, &digest(&targets)) {
+                println!("Failed to email daily digest: {}", e);
+            }
+        }
+    })))
+}
+
+#[test]
+fn describes_worst_addrs() {
+    let addr = |name: &str, count: u64, errors: u64| AddrStats {
+        index: 0,
+        addr: Some(name.to_owned()),
+        count: count,
+        errors: errors,
+        availability: Some(count as f64 * 100.0 / (count + errors) as f64),
+        covered: (count + errors) * 60,
+        min: None, mean: None, max: None, p50: None, p95: Some(1200), p99: None,
+        outages: if errors > 0 { 1 } else { 0 },
+        longest_outage: 60,
+        longest_outage_start: None,
+        maintenance: 0,
+    };
+    let text = describe("tcpping", &[addr("a", 100, 0), addr("b", 90, 10), addr("c", 50, 50)]);
+    assert_eq!(text, "tcpping: 80.00% available over 300 attempts\n\
+                      \x20 c: 50.00% available, 1 outage(s) (longest 60s), p95 1200\n\
+                      \x20 b: 90.00% available, 1 outage(s) (longest 60s), p95 1200\n");
+    assert_eq!(describe("ntp", &[]), "ntp: no data\n");
+}
+
+#[test]
+fn schedules_the_next_digest() {
+    use chrono::TimeZone;
+
+    let now = Local.ymd(2020, 1, 4).and_hms(10, 0, 0);
+    assert_eq!(next_digest(now, 12), Local.ymd(2020, 1, 4).and_hms(12, 0, 0));
+    assert_eq!(next_digest(now, 10), Local.ymd(2020, 1, 5).and_hms(10, 0, 0));
+    assert_eq!(next_digest(now, 2), Local.ymd(2020, 1, 5).and_hms(2, 0, 0));
+}
@@ -0,0 +1,446 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Server-side downsampling of data requested over long time ranges into a
+ * bounded number of equal-width buckets, keeping gaps and failures visible:
+ * each bucket carries, for each address, an aggregate of its successfully
+ * collected values along with the number of failures and of mismatches.
+ */
+use std::io;
+use std::cmp;
+
+use helpers::{VecIntoRawBytes, percentile};
+use datafile::Record;
+use rollup::RollupRecord;
+use options::{SENTINEL_ERROR, SENTINEL_NODATA, SENTINEL_MISMATCH, is_failure};
+
+/**
+ * How the values of an address within a bucket are combined into one.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aggregate {
+    Avg,
+    Min,
+    Max,
+    P95,
+    /// Largest-Triangle-Three-Buckets: the value that best keeps the visual shape
+    Lttb,
+}
+
+impl Aggregate {
+    pub fn parse(name: &str) -> Option<Aggregate> {
+        match name {
+            "avg" => Some(Aggregate::Avg),
+            "min" => Some(Aggregate::Min),
+            "max" => Some(Aggregate::Max),
+            "p95" => Some(Aggregate::P95),
+            "lttb" => Some(Aggregate::Lttb),
+            _ => None,
+        }
+    }
+
+    /**
+     * Whether this aggregate can be computed from rollup records (which only
+     * keep the counts, min, max and sum of each of their buckets).
+     */
+    pub fn from_summaries(&self) -> bool {
+        match *self {
+            Aggregate::Avg | Aggregate::Min | Aggregate::Max => true,
+            Aggregate::P95 | Aggregate::Lttb => false,
+        }
+    }
+
+    fn keeps_points(&self) -> bool {
+        !self.from_summaries()
+    }
+}
+
+/**
+ * What is known of one address within one bucket.
+ */
+#[derive(Clone)]
+struct Slot {
+    count: i32,
+    errors: i32,
+    mismatches: i32,
+    min: i32,
+    max: i32,
+    sum: i64,
+    points: Vec<(i64, i32)>,  // (time in millis, value) of successful values, if needed
+}
+
+impl Slot {
+    fn new() -> Slot {
+        Slot {
+            count: 0, errors: 0, mismatches: 0,
+            min: i32::max_value(), max: i32::min_value(), sum: 0,
+            points: Vec::new(),
+        }
+    }
+
+    fn average(&self) -> Option<(f64, f64)> {
+        if self.points.is_empty() {
+            return None;
+        }
+        let n = self.points.len() as f64;
+        let t = self.points.iter().map(|&(t, _)| t as f64).sum::<f64>() / n;
+        let v = self.points.iter().map(|&(_, v)| v as f64).sum::<f64>() / n;
+        Some((t, v))
+    }
+}
+
+struct Bucket {
+    number: i64,
+    slots: Vec<Slot>,
+}
+
+/**
+ * Cuts the given time range (inclusive, in milliseconds from epoch) into at
+ * most the given number of buckets, returning their width, a multiple of the
+ * given unit.
+ */
+fn bucket_width(lower_ms: i64, upper_ms: i64, max_points: u32, unit_ms: i64) -> i64 {
+    let span_ms = cmp::max(upper_ms - lower_ms + 1, 1);
+    let per_point = (span_ms + max_points as i64 - 1) / cmp::max(max_points as i64, 1);
+    cmp::max((per_point + unit_ms - 1) / unit_ms, 1) * unit_ms
+}
+
+/**
+ * Streams records (or rollup records) of a time range, in time order, into
+ * buckets and writes out one row per bucket holding any data, as
+ * back-to-back [time, value1, errors1, mismatches1, value2, ...] arrays of
+ * 32-bit little-endian integers, with the (value, errors, mismatches) triples
+ * in the order of the given ordered list of indices. A value is the nodata
+ * sentinel if there was no data for the address in the bucket at all, and if
+ * there were only failures, the mismatch sentinel if any were mismatches and
+ * the error sentinel otherwise.
+ */
+pub struct Bucketer {
+    origin_ms: i64,  // start of the first bucket
+    upper_ms: i64,
+    max_points: u32,
+    width_ms: i64,
+    aggregate: Aggregate,
+    positions: Vec<Vec<usize>>,  // index -> positions in the ordered list
+    len: usize,
+    current: Option<Bucket>,
+    pending: Option<Bucket>,  // for LTTB, the bucket waiting on the next one
+    selected: Vec<Option<(i64, i32)>>,  // for LTTB, the last point selected for each position
+}
+
+impl Bucketer {
+    /**
+     * Creates a bucketer for the given time range (inclusive, in milliseconds
+     * from epoch), cut into at most the given number of buckets of whole
+     * seconds.
+     */
+    pub fn new(lower_ms: i64, upper_ms: i64, max_points: u32, aggregate: Aggregate,
+               ordered_list: &[i32]) -> Bucketer {
+        let mut positions: Vec<Vec<usize>> = Vec::new();
+        for (pos, &i) in ordered_list.iter().enumerate() {
+            if positions.len() <= i as usize {
+                positions.resize(i as usize + 1, Vec::new());
+            }
+            positions[i as usize].push(pos);
+        }
+
+        Bucketer {
+            origin_ms: lower_ms,
+            upper_ms: upper_ms,
+            max_points: max_points,
+            width_ms: bucket_width(lower_ms, upper_ms, max_points, 1000),
+            aggregate: aggregate,
+            positions: positions,
+            len: ordered_list.len(),
+            current: None,
+            pending: None,
+            selected: vec![None; ordered_list.len()],
+        }
+    }
+
+    pub fn aggregate(&self) -> Aggregate {
+        self.aggregate
+    }
+
+    /**
+     * The width of each bucket, in milliseconds.
+     */
+    pub fn width_ms(&self) -> i64 {
+        self.width_ms
+    }
+
+    /**
+     * The start of the first bucket, in milliseconds from epoch.
+     */
+    pub fn origin_ms(&self) -> i64 {
+        self.origin_ms
+    }
+
+    /**
+     * Aligns the buckets to those of a rollup tier of the given width (in
+     * milliseconds) before anything is added, so that each rollup record falls
+     * within a single bucket: the first bucket is moved back to start on a
+     * rollup bucket, and the width is rounded up to a whole number of them.
+     */
+    pub fn align_to(&mut self, tier_ms: i64) {
+        self.origin_ms -= ((self.origin_ms % tier_ms) + tier_ms) % tier_ms;
+        self.width_ms = bucket_width(self.origin_ms, self.upper_ms, self.max_points, tier_ms);
+    }
+
+    /**
+     * Finds the slots of the given record's address in the bucket of the
+     * given time, moving on to that bucket (writing out finished ones) first
+     * if necessary.
+     */
+    fn slots_for<'a>(&'a mut self, time_ms: i64, index: i32,
+                     out: &mut io::Write) -> io::Result<(&'a mut Bucket, Vec<usize>)> {
+        let number = (time_ms - self.origin_ms) / self.width_ms;
+        let moved_on = match self.current {
+            Some(ref b) => number > b.number,
+            None => true,
+        };
+        if moved_on {
+            let fresh = Bucket { number: number, slots: vec![Slot::new(); self.len] };
+            if let Some(finished) = self.current.take() {
+                try!(self.finish_bucket(finished, out));
+            }
+            self.current = Some(fresh);
+        }
+
+        let positions = self.positions.get(index as usize).cloned().unwrap_or_else(Vec::new);
+        Ok((self.current.as_mut().unwrap(), positions))
+    }
+
+    /**
+     * Takes the given data record into account.
+     */
+    pub fn add<R: Record>(&mut self, r: &R, out: &mut io::Write) -> io::Result<()> {
+        let keep = self.aggregate.keeps_points();
+        let (time_ms, val) = (r.time_ms(), r.val());
+        let (bucket, positions) = try!(self.slots_for(time_ms, r.index(), out));
+        for pos in positions {
+            let ref mut slot = bucket.slots[pos];
+            if val == SENTINEL_MISMATCH {
+                slot.mismatches += 1;
+            } else if is_failure(val) {
+                slot.errors += 1;
+            } else {
+                slot.count += 1;
+                slot.min = cmp::min(slot.min, val);
+                slot.max = cmp::max(slot.max, val);
+                slot.sum += val as i64;
+                if keep {
+                    slot.points.push((time_ms, val));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Takes the given rollup record into account (only sensible for
+     * aggregates computed `from_summaries()`, with buckets aligned to those of
+     * the rollup tier, see `align_to()`).
+     */
+    pub fn add_summary(&mut self, r: &RollupRecord, out: &mut io::Write) -> io::Result<()> {
+        let (bucket, positions) = try!(self.slots_for(r.time_ms(), r.index(), out));
+        for pos in positions {
+            let ref mut slot = bucket.slots[pos];
+            slot.errors += r.errors();
+            slot.mismatches += r.mismatches();
+            if r.count() > 0 {
+                slot.count += r.count();
+                slot.min = cmp::min(slot.min, r.min());
+                slot.max = cmp::max(slot.max, r.max());
+                slot.sum += r.sum();
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Writes out whatever buckets remain.
+     */
+    pub fn finish(&mut self, out: &mut io::Write) -> io::Result<()> {
+        if let Some(last) = self.current.take() {
+            try!(self.finish_bucket(last, out));
+        }
+        if let Some(pending) = self.pending.take() {
+            try!(self.write_bucket(&pending, None, out));
+        }
+        Ok(())
+    }
+
+    fn finish_bucket(&mut self, bucket: Bucket, out: &mut io::Write) -> io::Result<()> {
+        if self.aggregate != Aggregate::Lttb {
+            return self.write_bucket(&bucket, None, out);
+        }
+
+        // LTTB needs to look at the next bucket before settling on a value
+        if let Some(pending) = self.pending.take() {
+            try!(self.write_bucket(&pending, Some(&bucket), out));
+        }
+        self.pending = Some(bucket);
+        Ok(())
+    }
+
+    fn write_bucket(&mut self, bucket: &Bucket, next: Option<&Bucket>,
+                    out: &mut io::Write) -> io::Result<()> {
+        let mut row: Vec<i32> = Vec::with_capacity(1 + 3 * self.len);
+        row.push(((self.origin_ms + bucket.number * self.width_ms) / 1000) as i32);
+
+        for (pos, slot) in bucket.slots.iter().enumerate() {
+            let val = if slot.count == 0 {
+                if slot.mismatches > 0 {
+                    SENTINEL_MISMATCH
+                } else if slot.errors > 0 {
+                    SENTINEL_ERROR
+                } else {
+                    SENTINEL_NODATA
+                }
+            } else {
+                match self.aggregate {
+                    Aggregate::Avg => (slot.sum / slot.count as i64) as i32,
+                    Aggregate::Min => slot.min,
+                    Aggregate::Max => slot.max,
+                    Aggregate::P95 => {
+                        let mut vals: Vec<i32> = slot.points.iter().map(|&(_, v)| v).collect();
+                        vals.sort();
+                        percentile(&vals, 95).unwrap()
+                    },
+                    Aggregate::Lttb => {
+                        let next_avg = next.and_then(|n| n.slots[pos].average());
+                        let point = select_lttb(&slot.points, self.selected[pos], next_avg);
+                        self.selected[pos] = Some(point);
+                        point.1
+                    },
+                }
+            };
+            row.push(val);
+            row.push(slot.errors);
+            row.push(slot.mismatches);
+        }
+
+        out.write_all(&row.into_raw_bytes())
+    }
+}
+
+/**
+ * Selects, of the given (non-empty) points of a bucket, the one forming the
+ * largest triangle with the point selected in the previous bucket and the
+ * average of the next. Without a previous point the first is selected, and
+ * without a next average the last.
+ */
+fn select_lttb(points: &[(i64, i32)], prev: Option<(i64, i32)>,
+               next_avg: Option<(f64, f64)>) -> (i64, i32) {
+    let (a, (ct, cv)) = match (prev, next_avg) {
+        (None, _) => return points[0],
+        (_, None) => return points[points.len() - 1],
+        (Some(a), Some(c)) => (a, c),
+    };
+    let (at, av) = (a.0 as f64, a.1 as f64);
+
+    let mut best = points[0];
+    let mut best_area = -1.0;
+    for &(t, v) in points {
+        let area = ((at - ct) * (v as f64 - av) - (at - t as f64) * (cv - av)).abs();
+        if area > best_area {
+            best_area = area;
+            best = (t, v);
+        }
+    }
+    best
+}
+
+#[test]
+fn buckets_keep_gaps_and_errors() {
+    use datafile::DataRecord;
+    use helpers::raw_bytes_as_slice;
+
+    let run = |aggregate: Aggregate, data: &[DataRecord]| -> Vec<i32> {
+        let mut out: Vec<u8> = Vec::new();
+        let mut b = Bucketer::new(0, 59_999, 3, aggregate, &[1, 0]);
+        assert_eq!(b.width_ms(), 20_000);
+        for r in data {
+            b.add(r, &mut out).unwrap();
+        }
+        b.finish(&mut out).unwrap();
+        raw_bytes_as_slice::<[u8; 4]>(&out).unwrap().iter()
+            .map(|&b| ::helpers::decode_i32_le(b)).collect()
+    };
+
+    let data = [
+        DataRecord::new(0, 0, 10),
+        DataRecord::new(1_000, 0, 30),
+        DataRecord::new(2_000, 0, SENTINEL_ERROR),
+        DataRecord::new(3_000, 1, SENTINEL_ERROR),
+        DataRecord::new(4_000, 1, SENTINEL_MISMATCH),
+        // nothing at all in the second bucket
+        DataRecord::new(40_000, 0, 5),
+        DataRecord::new(41_000, 1, 7),
+    ];
+    assert_eq!(run(Aggregate::Avg, &data), vec![
+        0, SENTINEL_MISMATCH, 1, 1, 20, 1, 0,
+        40, 7, 0, 0, 5, 0, 0,
+    ]);
+    assert_eq!(run(Aggregate::Max, &data)[4], 30);
+    assert_eq!(run(Aggregate::P95, &data)[4], 30);
+
+    // LTTB picks the point furthest off the line to the next bucket's average
+    let data = [
+        DataRecord::new(0, 0, 0),
+        DataRecord::new(20_000, 0, 0),
+        DataRecord::new(21_000, 0, 50),
+        DataRecord::new(22_000, 0, 1),
+        DataRecord::new(40_000, 0, 0),
+    ];
+    let rows = run(Aggregate::Lttb, &data);
+    assert_eq!((rows[4], rows[11], rows[18]), (0, 50, 0));
+}
+
+#[test]
+fn buckets_align_to_rollups() {
+    use datafile::DataRecord;
+    use helpers::{TempDir, raw_bytes_as_slice};
+    use rollup::RollupTier;
+
+    let dir = TempDir::new("downsample");
+    let tier = RollupTier::open("tcpping", &dir, "1m", 60).unwrap();
+    tier.observe(&[
+        DataRecord::new(10_000, 0, 10),
+        DataRecord::new(50_000, 0, 20),
+        DataRecord::new(70_000, 0, SENTINEL_MISMATCH),
+        DataRecord::new(130_000, 0, 30),
+        DataRecord::new(250_000, 0, SENTINEL_ERROR),
+    ]).unwrap();
+
+    // starting mid-way through a rollup bucket, and with buckets of 60s
+    let mut b = Bucketer::new(30_000, 269_999, 4, Aggregate::Avg, &[0]);
+    assert_eq!(b.width_ms(), 60_000);
+    b.align_to(60_000);
+    assert_eq!((b.origin_ms(), b.width_ms()), (0, 120_000));
+
+    let mut out: Vec<u8> = Vec::new();
+    tier.with_records(|records, open| {
+        for r in records.iter().chain(open.iter()) {
+            b.add_summary(r, &mut out).unwrap();
+        }
+        Ok(())
+    }).unwrap();
+    b.finish(&mut out).unwrap();
+    let rows: Vec<i32> = raw_bytes_as_slice::<[u8; 4]>(&out).unwrap().iter()
+        .map(|&b| ::helpers::decode_i32_le(b)).collect();
+
+    // the first rollup bucket isn't lost, and the mismatch stays apart
+    assert_eq!(rows, vec![
+        0, 15, 0, 1,
+        120, 30, 0, 0,
+        240, SENTINEL_ERROR, 1, 0,
+    ]);
+}
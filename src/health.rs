@@ -0,0 +1,80 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Per-target health tracking, i.e. conditions noticed by workers that are not
+ * captured by a datapoint alone (e.g. local clock drift) and that should be
+ * surfaced to the user.
+ */
+use std::sync::Mutex;
+
+use chrono::Local;
+
+/**
+ * A single outstanding health warning for an address of a target (or with an
+ * empty address, for the target as a whole).
+ */
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct HealthWarning {
+    pub addr: String,
+    pub since: i64,  // time the warning was first raised, in seconds from epoch
+    pub message: String,
+}
+
+/**
+ * The set of currently outstanding health warnings for a target.
+ */
+pub struct Health {
+    warnings: Mutex<Vec<HealthWarning>>,
+}
+
+impl Health {
+    pub fn new() -> Health {
+        Health {
+            warnings: Mutex::new(Vec::new()),
+        }
+    }
+
+    /**
+     * Raises (or updates the message of an already raised) warning for the
+     * given address.
+     */
+    pub fn raise(&self, kind_name: &str, addr: &str, message: String) {
+        let mut guard = self.warnings.lock().unwrap();
+        if let Some(w) = guard.iter_mut().find(|w| w.addr == addr) {
+            w.message = message;
+            return;
+        }
+
+        println!("WARNING: {} {}: {}", kind_name, addr, message);
+        guard.push(HealthWarning {
+            addr: addr.to_owned(),
+            since: Local::now().timestamp(),
+            message: message,
+        });
+    }
+
+    /**
+     * Clears the warning (if any) for the given address.
+     */
+    pub fn clear(&self, kind_name: &str, addr: &str) {
+        let mut guard = self.warnings.lock().unwrap();
+        let before = guard.len();
+        guard.retain(|w| w.addr != addr);
+        if guard.len() != before {
+            println!("Cleared {} warning for {}.", kind_name, addr);
+        }
+    }
+
+    /**
+     * Retrieves a copy of all currently outstanding warnings.
+     */
+    pub fn snapshot(&self) -> Vec<HealthWarning> {
+        self.warnings.lock().unwrap().clone()
+    }
+}
@@ -10,13 +10,20 @@
  * Helper traits and functions for reducing verbosity, wraping errors, and
  * containing unsafe code for many commonly used I/O and parsing operations.
  */
+use std::cmp;
 use std::mem;
+use std::slice;
 use std::fmt::{self, Display};
 use std::path::{Path, PathBuf};
+use std::fs;
 use std::fs::{OpenOptions, File};
+use std::io;
 use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
 
 use rustc_serialize::{json, Encodable, Decodable};
+use time;
 
 /**
  * Stabping-specific I/O error container, representing the possible failrue
@@ -61,25 +68,141 @@ impl Display for SPIOError {
 }
 
 
+/*
+ * All of stabping's disk and network formats are little-endian, regardless of
+ * the byte order of the host, so that data directories can be moved between
+ * hosts and the data read by other tools. These encode and decode integers to
+ * and from that byte order without ever looking at host memory layout.
+ */
+
+pub fn encode_i32_le(v: i32) -> [u8; 4] {
+    let u = v as u32;
+    [u as u8, (u >> 8) as u8, (u >> 16) as u8, (u >> 24) as u8]
+}
+
+pub fn decode_i32_le(b: [u8; 4]) -> i32 {
+    (b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24) as i32
+}
+
+pub fn encode_i64_le(v: i64) -> [u8; 8] {
+    let u = v as u64;
+    let mut b = [0u8; 8];
+    for i in 0..8 {
+        b[i] = (u >> (8 * i)) as u8;
+    }
+    b
+}
+
+pub fn decode_i64_le(b: [u8; 8]) -> i64 {
+    b.iter().rev().fold(0u64, |acc, &x| (acc << 8) | x as u64) as i64
+}
+
 /**
- * Trait for turning arbitrary data into a series of bytes that can be put directly
- * into a file or onto the network.
+ * Trait for turning arbitrary data into a series of (little-endian) bytes that
+ * can be put directly into a file or onto the network.
  */
 pub trait VecIntoRawBytes {
     fn into_raw_bytes(self) -> Vec<u8>;
 }
 
 impl VecIntoRawBytes for Vec<i32> {
-    fn into_raw_bytes(mut self) -> Vec<u8> {
-        let raw_ptr = self.as_mut_ptr();
-        let new_len = self.len() * mem::size_of::<i32>();
-        let new_cap = self.capacity() * mem::size_of::<i32>();
-
-        unsafe {
-            // take full control over memory originally controlled by orig_data
-            mem::forget(self);
-            Vec::from_raw_parts(raw_ptr as *mut u8, new_len, new_cap)
+    fn into_raw_bytes(self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.len() * mem::size_of::<i32>());
+        for v in self {
+            out.extend_from_slice(&encode_i32_le(v));
         }
+        out
+    }
+}
+
+/**
+ * Obtains the current wall-clock time in milliseconds from epoch.
+ */
+pub fn now_millis() -> i64 {
+    let t = time::get_time();
+    t.sec * 1000 + (t.nsec / 1_000_000) as i64
+}
+
+/**
+ * The (1-based) rank of the `p`th percentile among `total` sorted values (or
+ * total weight), by the nearest-rank method.
+ */
+pub fn nearest_rank(total: u64, p: u64) -> u64 {
+    cmp::max((total * p + 99) / 100, 1)
+}
+
+/**
+ * The `p`th (nearest-rank) percentile of the given sorted values, if any.
+ */
+pub fn percentile(sorted: &[i32], p: u64) -> Option<i32> {
+    if sorted.is_empty() {
+        return None;
+    }
+    Some(sorted[nearest_rank(sorted.len() as u64, p) as usize - 1])
+}
+
+/**
+ * Appends the given default port to an address given without one (a host,
+ * an IPv4 address, or a bare or bracketed IPv6 address, which gets bracketed),
+ * leaving `host:port` and `[v6 address]:port` addresses as they are.
+ */
+pub fn with_default_port(addr: &str, port: u16) -> String {
+    match addr.matches(':').count() {
+        // plain host or IPv4 address
+        0 => format!("{}:{}", addr, port),
+        // host:port or IPv4:port
+        1 => addr.to_owned(),
+        // bracketed IPv6 address with port, or bare IPv6 address
+        _ if addr.contains("]:") => addr.to_owned(),
+        _ => format!("[{}]:{}", addr.trim_matches(|c| c == '[' || c == ']'), port),
+    }
+}
+
+/**
+ * Connects to the given `host:port`, trying each address it resolves to in
+ * turn, without waiting longer than the given timeout on any of them.
+ */
+pub fn connect_timeout(host_port: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::Other, "No address resolved.");
+    for sock_addr in try!(host_port.to_socket_addrs()) {
+        match TcpStream::connect_timeout(&sock_addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+/**
+ * Reinterprets a series of raw bytes (e.g. those of a memory-mapped data file)
+ * as a slice of back-to-back elements, or returns `None` if the bytes are not
+ * a whole multiple of the element size.
+ *
+ * `T` must be a `#[repr(C, packed)]` struct of plain integers (or of byte
+ * arrays holding little-endian integers).
+ */
+pub fn raw_bytes_as_slice<'a, T: Copy>(raw: &'a [u8]) -> Option<&'a [T]> {
+    if raw.len() % mem::size_of::<T>() != 0 {
+        return None;
+    }
+    let new_len = raw.len() / mem::size_of::<T>();
+
+    unsafe {
+        Some(slice::from_raw_parts(raw.as_ptr() as *const T, new_len))
+    }
+}
+
+/**
+ * Views a slice of elements as its raw bytes, e.g. for writing it directly
+ * into a file.
+ *
+ * `T` must be a `#[repr(C, packed)]` struct of plain integers (or of byte
+ * arrays holding little-endian integers).
+ */
+pub fn slice_as_raw_bytes<'a, T: Copy>(elements: &'a [T]) -> &'a [u8] {
+    unsafe {
+        slice::from_raw_parts(elements.as_ptr() as *const u8,
+                              elements.len() * mem::size_of::<T>())
     }
 }
 
@@ -177,15 +300,218 @@ impl SPFile for File {
 }
 
 /**
- * Overwrite (create if necessary, truncate if already exists) the file
+ * Returns the given path with the given suffix appended to its file name
+ * (e.g. `foo.options.json` with "prev" gives `foo.options.json.prev`).
+ */
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_owned()).unwrap_or_default();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/**
+ * Flushes the directory containing the given path to disk, so that renames
+ * into it survive a crash. Not all platforms can open a directory for this,
+ * so failures are ignored.
+ */
+pub fn sync_parent_dir(path: &Path) {
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        if let Ok(d) = File::open(dir) {
+            let _ = d.sync_all();
+        }
+    }
+}
+
+/**
+ * Atomically writes the given bytes to the file at the given path, by way of
+ * a temporary file that is flushed to disk and then renamed over it.
+ */
+fn replace_file(bytes: &[u8], path: &Path) -> Result<(), SPIOError> {
+    let tmp_path = with_suffix(path, "tmp");
+    {
+        let mut tmp = try!(File::open_from(
+            OpenOptions::new().write(true).truncate(true).create(true), &tmp_path));
+        try!(
+            tmp.write_all(bytes)
+            .and_then(|_| tmp.sync_all())
+            .map_err(|_| SPIOError::Write(Some(tmp_path.clone())))
+        );
+    }
+    try!(
+        fs::rename(&tmp_path, path)
+        .map_err(|_| SPIOError::Write(Some(path.to_owned())))
+    );
+    sync_parent_dir(path);
+    Ok(())
+}
+
+/**
+ * Overwrite (create if necessary, replace if already exists) the file
  * residing at the given path with the given JSON object (`rustc::Encodable`).
+ *
+ * The file is replaced atomically, so a crash leaves either the old or the
+ * new contents in place, never a truncated file. If the old contents are
+ * valid JSON they are first kept as a previous-good copy (the path with
+ * `.prev` appended) for `read_json_or_prev()` to fall back to.
  */
 pub fn overwrite_json<'a, 'b, T: Encodable>(obj: &'a T, path: &'b Path) -> Result<(), SPIOError> {
-    let mut file = try!(
-        OpenOptions::new().write(true).truncate(true).create(true).open(path)
-        .map_err(|_| SPIOError::Open(Some(path.to_owned())))
-    );
+    let mut old = String::new();
+    let kept = File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut old))
+        .is_ok() && json::Json::from_str(&old).is_ok();
+    if kept {
+        try!(replace_file(old.as_bytes(), &with_suffix(path, "prev")));
+    }
 
-    try!(file.write_json_p(obj, path));
-    Ok(())
+    let buffer = json::encode(obj).unwrap();
+    replace_file(buffer.as_bytes(), path)
+}
+
+/**
+ * Reads back a JSON object (`rustc::Decodable`) written by `overwrite_json()`
+ * from the file at the given path. If that file is missing, empty or cannot
+ * be decoded, but its previous-good copy can, the file is restored from that
+ * copy. Returns `None` if neither file exists or has anything in it.
+ */
+pub fn read_json_or_prev<'a, T: Decodable + Encodable>(path: &'a Path) -> Result<Option<T>, SPIOError> {
+    fn read_nonempty<T: Decodable>(path: &Path) -> Result<Option<T>, SPIOError> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut file = try!(File::open_from(OpenOptions::new().read(true), path));
+        if try!(file.length_p(path)) == 0 {
+            return Ok(None);
+        }
+        file.read_json_p(path).map(Some)
+    }
+
+    let current = read_nonempty::<T>(path);
+    if let Ok(Some(obj)) = current {
+        return Ok(Some(obj));
+    }
+
+    let prev_path = with_suffix(path, "prev");
+    match read_nonempty::<T>(&prev_path) {
+        Ok(Some(obj)) => {
+            println!("WARNING: '{}' is missing or damaged, restoring it from '{}'.",
+                     path.display(), prev_path.display());
+            try!(replace_file(json::encode(&obj).unwrap().as_bytes(), path));
+            Ok(Some(obj))
+        },
+        _ => current,
+    }
+}
+
+/**
+ * A fresh directory (in the system's temporary directory) for the files of a
+ * test, removed along with them once dropped, whether the test passes or not.
+ */
+#[cfg(test)]
+pub struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let dir = ::std::env::temp_dir().join(format!("stabping_{}_{}", name, ::std::process::id()));
+        // clear out whatever a previous run that was killed left behind
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+#[cfg(test)]
+impl ::std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn encodes_little_endian_fixture() {
+    assert_eq!(vec![0x01020304, -2].into_raw_bytes(),
+               vec![0x04, 0x03, 0x02, 0x01, 0xfe, 0xff, 0xff, 0xff]);
+    assert_eq!(encode_i64_le(0x0102030405060708),
+               [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]);
+}
+
+#[test]
+fn decodes_bytes_from_byte_swapped_host() {
+    use datafile::{DataRecord, Record};
+
+    /*
+     * bytes as written by a big-endian host (which, the formats being
+     * little-endian, are what any host writes), decoded on this one: a data
+     * record at 1476000000.123 s of index 2 with value 1234 and no aux...
+     */
+    let record_bytes = [0x7b, 0xa8, 0x74, 0xa8, 0x57, 0x01, 0x00, 0x00,
+                        0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                        0xd2, 0x04, 0x00, 0x00, 0x00, 0x6c, 0xca, 0x88];
+    let records: &[DataRecord] = raw_bytes_as_slice(&record_bytes).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!((records[0].time_ms(), records[0].index(), records[0].flags(),
+                records[0].val(), records[0].aux()),
+               (1_476_000_000_123, 2, 0, 1234, -2_000_000_000));
+    assert_eq!(slice_as_raw_bytes(&[DataRecord::with_all(1_476_000_000_123, 2, 0, 1234, -2_000_000_000)]),
+               &record_bytes[..]);
+
+    // ...and a data response / websockets payload of [time, value1, value2]
+    let wire_bytes = [0x00, 0xf9, 0xf9, 0x57, 0xd2, 0x04, 0x00, 0x00, 0x00, 0x8b, 0xd4, 0x82];
+    let decoded: Vec<i32> = wire_bytes.chunks(4)
+        .map(|c| decode_i32_le([c[0], c[1], c[2], c[3]]))
+        .collect();
+    assert_eq!(decoded, vec![1_476_000_000, 1234, -2_100_000_000]);
+    assert_eq!(vec![1_476_000_000, 1234, -2_100_000_000].into_raw_bytes(), wire_bytes.to_vec());
+}
+
+#[test]
+fn ranks_percentiles_and_defaults_ports() {
+    assert_eq!(percentile(&[], 95), None);
+    assert_eq!(percentile(&[7], 50), Some(7));
+    assert_eq!(percentile(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10], 95), Some(10));
+    assert_eq!(percentile(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10], 50), Some(5));
+    assert_eq!(nearest_rank(0, 99), 1);
+
+    assert_eq!(with_default_port("example.com", 587), "example.com:587");
+    assert_eq!(with_default_port("example.com:25", 587), "example.com:25");
+    assert_eq!(with_default_port("[::1]", 80), "[::1]:80");
+    assert_eq!(with_default_port("[::1]:8080", 80), "[::1]:8080");
+    assert_eq!(with_default_port("::1", 123), "[::1]:123");
+    assert_eq!(with_default_port("10.0.0.1", 123), "10.0.0.1:123");
+}
+
+#[test]
+fn overwrites_json_keeping_previous_copy() {
+
+    let dir = TempDir::new("helpers");
+    let path = dir.join("test.options.json");
+
+    assert_eq!(read_json_or_prev::<Vec<i32>>(&path).unwrap(), None);
+    overwrite_json(&vec![1], &path).unwrap();
+    overwrite_json(&vec![2], &path).unwrap();
+    assert!(!with_suffix(&path, "tmp").exists());
+    assert_eq!(read_json_or_prev::<Vec<i32>>(&path).unwrap(), Some(vec![2]));
+
+    // a file left empty or half-written is restored from the previous copy
+    File::create(&path).unwrap().write_all(b"[2").unwrap();
+    assert_eq!(read_json_or_prev::<Vec<i32>>(&path).unwrap(), Some(vec![1]));
+    assert_eq!(read_json_or_prev::<Vec<i32>>(&path).unwrap(), Some(vec![1]));
+
+    // and the damaged contents never replace a good previous copy
+    File::create(&path).unwrap().set_len(0).unwrap();
+    overwrite_json(&vec![3], &path).unwrap();
+    let mut prev = String::new();
+    File::open(with_suffix(&path, "prev")).unwrap().read_to_string(&mut prev).unwrap();
+    assert_eq!(prev, "[1]");
 }
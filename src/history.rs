@@ -0,0 +1,113 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * The history of a target's options, one version per nonce, so that previous
+ * configurations can be looked at, rolled back to, and used to interpret data
+ * collected under them.
+ */
+use std::path::{Path, PathBuf};
+
+use helpers::{SPIOError, overwrite_json, read_json_or_prev, now_millis};
+use options::TargetOptions;
+
+/**
+ * A single version of a target's options, with when (in milliseconds from
+ * epoch) and by whom (client IP or user) it was put in place.
+ */
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug)]
+pub struct OptionsVersion {
+    pub nonce: i32,
+    pub time: i64,
+    pub source: String,
+    pub options: TargetOptions,
+}
+
+/**
+ * The options history of a target, backed by a JSON file holding all
+ * versions in the order they were put in place.
+ */
+pub struct OptionsHistory {
+    path: PathBuf,
+    versions: Vec<OptionsVersion>,
+}
+
+impl OptionsHistory {
+    /**
+     * Opens the options history file at the given path, recording the given
+     * current options if the history doesn't end with them (i.e. it is new, or
+     * the options file was changed behind our back).
+     */
+    pub fn open(path: &Path, current: &TargetOptions) -> Result<OptionsHistory, SPIOError> {
+        let mut history = OptionsHistory {
+            path: path.to_owned(),
+            versions: try!(read_json_or_prev(path)).unwrap_or_else(Vec::new),
+        };
+
+        let recorded = history.versions.last().map_or(false, |v| v.nonce == current.nonce);
+        if !recorded {
+            let source = if history.versions.is_empty() { "initial" } else { "options file" };
+            try!(history.record(current, source));
+        }
+        Ok(history)
+    }
+
+    /**
+     * Records the given options as the latest version, put in place by the
+     * given source.
+     */
+    pub fn record(&mut self, options: &TargetOptions, source: &str) -> Result<(), SPIOError> {
+        self.versions.push(OptionsVersion {
+            nonce: options.nonce,
+            time: now_millis(),
+            source: source.to_owned(),
+            options: options.clone(),
+        });
+        overwrite_json(&self.versions, &self.path)
+    }
+
+    /**
+     * Retrieves all versions, oldest first.
+     */
+    pub fn versions(&self) -> &[OptionsVersion] {
+        &self.versions
+    }
+
+    /**
+     * Retrieves the latest version with the given nonce, if any.
+     */
+    pub fn find(&self, nonce: i32) -> Option<&OptionsVersion> {
+        self.versions.iter().rev().find(|v| v.nonce == nonce)
+    }
+}
+
+#[test]
+fn records_versions_and_notices_outside_changes() {
+    use helpers::TempDir;
+    use options::TargetKind;
+
+    let dir = TempDir::new("history");
+    let path = dir.join("tcpping.history.json");
+
+    let mut options = TargetKind::TcpPing.default_options();
+    {
+        let mut history = OptionsHistory::open(&path, &options).unwrap();
+        options.nonce = 1;
+        options.addrs.pop();
+        history.record(&options, "127.0.0.1").unwrap();
+    }
+
+    // options changed behind our back are recorded on reopening
+    options.nonce = 2;
+    let history = OptionsHistory::open(&path, &options).unwrap();
+    let sources: Vec<_> = history.versions().iter().map(|v| (v.nonce, v.source.clone())).collect();
+    assert_eq!(sources, vec![(0, "initial".to_owned()), (1, "127.0.0.1".to_owned()),
+                             (2, "options file".to_owned())]);
+    assert_eq!(history.find(0).unwrap().options.addrs.len(), 2);
+    assert!(history.find(3).is_none());
+}
@@ -0,0 +1,242 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Detection of incidents (periods during which some address of a target is
+ * failing or degraded) from the values a target collects, kept in a
+ * per-target incident log.
+ */
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use helpers::{SPIOError, overwrite_json, read_json_or_prev};
+use options::{SENTINEL_NODATA, is_failure};
+use addrindex::AddrIndex;
+use datafile::{DataRecord, Record};
+
+/// the most incidents kept in a target's incident log (the oldest are dropped)
+pub static MAX_INCIDENTS: usize = 1000;
+
+/**
+ * The state of an address as of its last collected value.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum AddrState {
+    Up,
+    Degraded,  // above the target's latency threshold
+    Down,  // failing (an error sentinel)
+}
+
+/**
+ * A single incident: from when some address stopped being up until when all
+ * were up again (or still ongoing, without an `end`). Times are in seconds
+ * from epoch.
+ *
+ * `severity` is "down" if any address failed during it, or else "degraded".
+ * `scope` is "all" if at some point every address was failing at once (most
+ * likely a problem with our own link), "one" if only a single address was
+ * ever affected (most likely a problem on its end), or else "some".
+ */
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug, PartialEq)]
+pub struct Incident {
+    pub id: u32,
+    pub start: i64,
+    pub end: Option<i64>,
+    pub addrs: Vec<String>,  // affected addresses, in order of being affected
+    pub severity: String,
+    pub scope: String,
+}
+
+/**
+ * The incident log of a target along with what is needed to keep deriving
+ * incidents from newly collected values, backed by a JSON file.
+ */
+pub struct IncidentLog {
+    path: PathBuf,
+    incidents: Vec<Incident>,
+    states: BTreeMap<String, AddrState>,
+}
+
+impl IncidentLog {
+    /**
+     * Opens the incident log file at the given path (creating it when first
+     * written). An incident left ongoing is carried on, ending once all
+     * addresses are up again, after the states of addresses are picked back
+     * up with `replay()`.
+     */
+    pub fn open(path: &Path) -> Result<IncidentLog, SPIOError> {
+        Ok(IncidentLog {
+            path: path.to_owned(),
+            incidents: try!(read_json_or_prev(path)).unwrap_or_else(Vec::new),
+            states: BTreeMap::new(),
+        })
+    }
+
+    /**
+     * From when (in millis from epoch) collected data needs to be replayed to
+     * pick back up the states of addresses: the start of the ongoing incident,
+     * if any (otherwise every address was last up).
+     */
+    pub fn resume_ms(&self) -> Option<i64> {
+        self.incidents.last()
+            .and_then(|i| if i.end.is_none() { Some(i.start * 1000) } else { None })
+    }
+
+    /**
+     * Replays the given stored data records (in time order) as they were
+     * observed, resolving their addresses through the given index.
+     */
+    pub fn replay(&mut self, records: &[DataRecord], index: &AddrIndex, addrs: &[String],
+                  latency_threshold: Option<i32>) -> Result<(), SPIOError> {
+        let mut begin = 0;
+        while begin < records.len() {
+            // the records of one round share a time
+            let time_ms = records[begin].time_ms();
+            let round = records[begin..].iter().take_while(|r| r.time_ms() == time_ms).count();
+            let samples: Vec<(&str, i32)> = records[begin..begin + round].iter()
+                .filter_map(|r| index.addr_of(r.index()).map(|a| (a, r.val())))
+                .collect();
+            try!(self.observe(time_ms / 1000, &samples, addrs, latency_threshold));
+            begin += round;
+        }
+        Ok(())
+    }
+
+    /**
+     * Retrieves all incidents kept, oldest first.
+     */
+    pub fn incidents(&self) -> &[Incident] {
+        &self.incidents
+    }
+
+    /**
+     * Takes into account the values (as (address, value) pairs, nodata for
+     * those not sampled) collected at the given time (in seconds from epoch)
+     * for the given current addrs, values above the given latency threshold
+     * counting as degraded. Writes out the log if an incident started,
+     * changed or ended, returning the incident in that case.
+     */
+    pub fn observe(&mut self, time: i64, samples: &[(&str, i32)], addrs: &[String],
+                   latency_threshold: Option<i32>) -> Result<Option<Incident>, SPIOError> {
+        for &(addr, val) in samples {
+            if val == SENTINEL_NODATA {
+                continue;
+            }
+            let state = if is_failure(val) {
+                AddrState::Down
+            } else if latency_threshold.map_or(false, |t| val > t) {
+                AddrState::Degraded
+            } else {
+                AddrState::Up
+            };
+            self.states.insert(addr.to_owned(), state);
+        }
+        // forget about addresses that were removed from addrs
+        self.states.retain(|a, _| addrs.contains(a));
+
+        let affected: Vec<(&String, AddrState)> = self.states.iter()
+            .filter(|&(_, &s)| s != AddrState::Up)
+            .map(|(a, &s)| (a, s))
+            .collect();
+        let all_down = addrs.len() > 1 && self.states.len() == addrs.len() &&
+            affected.iter().filter(|&&(_, s)| s == AddrState::Down).count() == addrs.len();
+
+        let ongoing = self.incidents.last().map_or(false, |i| i.end.is_none());
+        let before = if ongoing { self.incidents.last().cloned() } else { None };
+
+        if affected.is_empty() {
+            if !ongoing {
+                return Ok(None);
+            }
+            self.incidents.last_mut().unwrap().end = Some(time);
+        } else {
+            if !ongoing {
+                let id = self.incidents.last().map_or(1, |i| i.id + 1);
+                self.incidents.push(Incident {
+                    id: id,
+                    start: time,
+                    end: None,
+                    addrs: Vec::new(),
+                    severity: "degraded".to_owned(),
+                    scope: "one".to_owned(),
+                });
+                if self.incidents.len() > MAX_INCIDENTS {
+                    self.incidents.remove(0);
+                }
+            }
+
+            let incident = self.incidents.last_mut().unwrap();
+            for &(addr, state) in affected.iter() {
+                if !incident.addrs.contains(addr) {
+                    incident.addrs.push(addr.clone());
+                }
+                if state == AddrState::Down {
+                    incident.severity = "down".to_owned();
+                }
+            }
+            if all_down {
+                incident.scope = "all".to_owned();
+            } else if incident.addrs.len() > 1 && incident.scope != "all" {
+                incident.scope = "some".to_owned();
+            }
+        }
+
+        let after = self.incidents.last().cloned();
+        if after == before {
+            return Ok(None);
+        }
+        try!(overwrite_json(&self.incidents, &self.path));
+        Ok(after)
+    }
+}
+
+#[test]
+fn derives_and_classifies_incidents() {
+    use helpers::TempDir;
+    use options::SENTINEL_ERROR;
+
+    let dir = TempDir::new("incidents");
+    let path = dir.join("tcpping.incidents.json");
+    let addrs = vec!["a".to_owned(), "b".to_owned()];
+
+    {
+        let mut log = IncidentLog::open(&path).unwrap();
+        assert_eq!(log.observe(0, &[("a", 10), ("b", 10)], &addrs, Some(100)).unwrap(), None);
+
+        // one address slow, then failing
+        let i = log.observe(10, &[("a", 200), ("b", 10)], &addrs, Some(100)).unwrap().unwrap();
+        assert_eq!((i.id, i.start, &i.severity[..], &i.scope[..]), (1, 10, "degraded", "one"));
+        assert_eq!(log.observe(20, &[("a", 150)], &addrs, Some(100)).unwrap(), None);
+        let i = log.observe(30, &[("a", SENTINEL_ERROR)], &addrs, Some(100)).unwrap().unwrap();
+        assert_eq!(&i.severity[..], "down");
+        let i = log.observe(40, &[("a", 10), ("b", 10)], &addrs, Some(100)).unwrap().unwrap();
+        assert_eq!(i.end, Some(40));
+
+        // everything failing at once
+        log.observe(50, &[("a", SENTINEL_ERROR), ("b", 10)], &addrs, None).unwrap();
+        let i = log.observe(60, &[("a", SENTINEL_ERROR), ("b", SENTINEL_ERROR)], &addrs, None).unwrap().unwrap();
+        assert_eq!((i.id, &i.scope[..], i.addrs.len()), (2, "all", 2));
+    }
+
+    // the ongoing incident is carried on after reopening, with the states of
+    // addresses replayed from the data (b is still down while only a is
+    // sampled at the fast interval)
+    let mut index = AddrIndex::from_path(&dir.join("tcpping.index.json")).unwrap();
+    index.ensure_for_addrs(addrs.iter()).unwrap();
+    let records = [
+        DataRecord::new(50_000, 0, SENTINEL_ERROR), DataRecord::new(50_000, 1, 10),
+        DataRecord::new(60_000, 0, SENTINEL_ERROR), DataRecord::new(60_000, 1, SENTINEL_ERROR),
+    ];
+    let mut log = IncidentLog::open(&path).unwrap();
+    assert_eq!(log.resume_ms(), Some(50_000));
+    log.replay(&records, &index, &addrs, None).unwrap();
+    assert_eq!(log.observe(65, &[("a", 10)], &addrs, None).unwrap(), None);
+    let i = log.observe(70, &[("a", 10), ("b", 10)], &addrs, None).unwrap().unwrap();
+    assert_eq!((i.id, i.start, i.end), (2, 50, Some(70)));
+    assert_eq!(log.incidents().len(), 2);
+}
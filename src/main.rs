@@ -14,29 +14,58 @@ extern crate ws;
 extern crate iron;
 extern crate router;
 extern crate mount;
+extern crate regex;
+extern crate openssl;
+#[cfg(unix)]
+extern crate libc;
 
 mod helpers;
+mod adaptive;
+mod addrindex;
+mod alerts;
+mod baseline;
+mod blockfile;
+mod compaction;
+mod datafile;
+mod datalock;
+mod digest;
+mod downsample;
+mod health;
+mod history;
+mod incidents;
+mod maintenance;
+mod notify;
 mod options;
 mod persist;
 mod reader;
+mod rollup;
+mod segments;
+mod smtp;
+mod stats;
 mod webserver;
 mod wsserver;
 mod tcpping;
+mod ntp;
+mod tcpexpect;
+mod tlsexpiry;
 
 use std::env;
+use std::process;
 use std::path::PathBuf;
 use std::fs;
 use std::fs::{OpenOptions, File};
 use std::sync::Arc;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
 use std::sync::mpsc::channel;
 
 use rustc_serialize::json;
 
 use wsserver::Broadcaster;
+use alerts::AlertEngine;
 
-use helpers::{SPIOError, SPFile, VecIntoRawBytes};
-use options::{TargetKind, MainConfiguration};
+use helpers::{SPIOError, SPFile};
+use datalock::DataLock;
+use options::{TargetKind, MainConfiguration, SegmentSpan};
 use persist::ManagerError;
 
 static CONFIG_FILENAME: &'static str = "stabping_config.json";
@@ -44,10 +73,11 @@ static CONFIG_FILENAME: &'static str = "stabping_config.json";
 /**
  * Attempts to discover the configuration file and associated data directory.
  *
- * Returns a tuple of an `Arc` to the `MainConfiguration` and the path to the
- * data directory if found.
+ * Returns a tuple of an `Arc` to the `MainConfiguration`, the path to the
+ * data directory, and the exclusive lock on it (to be held for as long as we
+ * run) if found.
  */
-fn get_configuration() -> Option<(Arc<RwLock<MainConfiguration>>, PathBuf)> {
+fn get_configuration() -> Option<(Arc<RwLock<MainConfiguration>>, PathBuf, DataLock)> {
     /*
      * the list of (description, path) tuples of directories to try/places we
      * want to check for the existence of the configuration file
@@ -100,7 +130,19 @@ fn get_configuration() -> Option<(Arc<RwLock<MainConfiguration>>, PathBuf)> {
                             println!("Failed to create data directory '{}'. Please ensure this directory is writable by stabping.", data_path.to_str().unwrap());
                             return None;
                         }
-                        return Some((Arc::new(RwLock::new(mc)), data_path));
+
+                        /*
+                         * make sure no other stabping process is using this
+                         * data directory
+                         */
+                        let lock = match DataLock::acquire(&data_path) {
+                            Ok(lock) => lock,
+                            Err(e) => {
+                                println!("\n{} Please stop the other process (or point this one at a different configuration file).", e);
+                                return None;
+                            },
+                        };
+                        return Some((Arc::new(RwLock::new(mc)), data_path, lock));
                     },
                     _ => {
                         /*
@@ -133,7 +175,7 @@ fn get_configuration() -> Option<(Arc<RwLock<MainConfiguration>>, PathBuf)> {
 
 fn main() {
     // try and obtain our configuration and data directory path
-    let (configuration, data_path) = match get_configuration() {
+    let (configuration, data_path, _lock) = match get_configuration() {
         Some(c) => c,
         None => {
             panic!("Failed to get configuration");
@@ -141,18 +183,99 @@ fn main() {
     };
 
     // create managers for all the targets
-    let targets = match TargetKind::new_managers_for_all(&data_path) {
+    let span = configuration.read().unwrap().segment_span.unwrap_or(SegmentSpan::Day);
+    let targets = match TargetKind::new_managers_for_all(&data_path, span) {
         Ok(targets) => targets,
         Err(e) => handle_fatal_error(e),
     };
 
+    /*
+     * `stabping compress [kind...]` converts the sealed data segments of the
+     * given (or all) targets to the compressed format, and exits instead of
+     * running the server
+     */
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map_or(false, |a| a == "compress") {
+        for tm in targets.iter() {
+            if args.len() > 1 && !args[1..].iter().any(|k| k == tm.kind.compact_name()) {
+                continue;
+            }
+            if let Err(e) = tm.compress() {
+                handle_fatal_error(e);
+            }
+        }
+        return;
+    }
+
+    /*
+     * `stabping rebuild-rollups [kind...]` rebuilds the rollup tiers of the
+     * given (or all) targets from their data segments, and exits instead of
+     * running the server
+     */
+    if args.first().map_or(false, |a| a == "rebuild-rollups") {
+        for tm in targets.iter() {
+            if args.len() > 1 && !args[1..].iter().any(|k| k == tm.kind.compact_name()) {
+                continue;
+            }
+            if let Err(e) = tm.rebuild_rollups() {
+                handle_fatal_error(e);
+            }
+        }
+        return;
+    }
+
+    /*
+     * `stabping fsck` checks the data of all targets for problems (such as
+     * records out of time order) and exits, unsuccessfully if there are any
+     */
+    if args.first().map_or(false, |a| a == "fsck") {
+        let mut ok = true;
+        for tm in targets.iter() {
+            let problems = tm.fsck();
+            if problems.is_empty() {
+                println!("{}: OK", tm.kind.compact_name());
+            }
+            for p in problems.iter() {
+                println!("{}: {}", tm.kind.compact_name(), p);
+                ok = false;
+            }
+        }
+        process::exit(if ok { 0 } else { 1 });
+    }
+
     // create a broadcaster to be initialized with the websockets server
     let broadcaster = Arc::new(Broadcaster::new());
 
+    /*
+     * set up the alert rules and start the notifier thread to send out the
+     * alerts they raise
+     */
+    let (alerts, notifications) = {
+        let config = configuration.read().unwrap();
+        let engine = match AlertEngine::new(config.alerts.as_ref().map_or(&[][..], |r| &r[..])) {
+            Ok(engine) => engine,
+            Err(e) => panic!("{}", e),
+        };
+        let notifications = match notify::run_notifier(config.notifiers.clone().unwrap_or_else(Vec::new),
+                                                     config.smtp.clone()) {
+            Ok(sender) => sender,
+            Err(e) => panic!("{}", e),
+        };
+        (Arc::new(Mutex::new(engine)), notifications)
+    };
+
     // start the web and websockets servers
-    webserver::web_server(configuration.clone(), targets.iter());
+    webserver::web_server(configuration.clone(), targets.iter(), alerts.clone());
     wsserver::ws_server(configuration.clone(), broadcaster.clone());
 
+    // start dropping expired data in the background
+    compaction::run_compactor(configuration.clone(), targets.clone());
+
+    // email a daily digest, if configured to
+    if let Err(e) = digest::run_digest(configuration.clone(), targets.clone()) {
+        panic!("{}", e);
+    }
+
     /*
      * start the workers for all the targets, passing them one end of an MPSC
      * communications channel so that we can receive all the data
@@ -170,13 +293,26 @@ fn main() {
         let kind_id = r.0[0];
 
         // append the data to the data file via the appropriate manager
-        if let Err(e) = targets[kind_id as usize].append_data(&r) {
+        let tm = &targets[kind_id as usize];
+        if let Err(e) = tm.append_data(&r) {
             handle_fatal_error(e);
         }
 
+        // evaluate the alert rules against the data, sending out any (unsilenced) changes
+        {
+            let options = tm.options_read();
+            if options.nonce == r.0[1] {
+                let samples: Vec<(&str, i32)> = options.addrs.iter().map(|a| &a[..])
+                    .zip(r.0[3..].iter().cloned()).collect();
+                let changed = alerts.lock().unwrap().observe(tm.kind.compact_name(), r.1 / 1000, &samples, &**tm);
+                for alert in changed {
+                    let _ = notifications.send(alert);
+                }
+            }
+        }
+
         // broadcast the live data over websockets
-        let raw_data_bytes = r.0.into_raw_bytes();
-        let _ = broadcaster.send(raw_data_bytes);
+        let _ = broadcaster.send_results(r);
     }
 }
 
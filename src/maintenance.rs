@@ -0,0 +1,392 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Maintenance windows of a target (or of some of its addresses), one-off or
+ * recurring on a cron-like schedule, during which alerts are silenced and
+ * data doesn't count towards availability.
+ */
+use std::cmp;
+use std::path::{Path, PathBuf};
+
+use chrono::{Local, TimeZone, Timelike, Datelike};
+
+use helpers::{SPIOError, overwrite_json, read_json_or_prev};
+use addrindex::AddrIndex;
+
+/**
+ * A maintenance window, either one-off (from `start` until `end`, in seconds
+ * from epoch) or recurring (for `duration` seconds from every minute matching
+ * the `cron` schedule, in local time), for the given `addrs` of a target or
+ * the whole target if unset. The `id` is assigned when it is added.
+ */
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug, PartialEq)]
+pub struct MaintenanceWindow {
+    pub id: Option<u32>,
+    pub addrs: Option<Vec<String>>,
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+    pub cron: Option<String>,
+    pub duration: Option<u32>,
+    pub comment: Option<String>,
+}
+
+/**
+ * A parsed cron schedule: "minute hour day-of-month month day-of-week", each
+ * field being `*` or a comma-separated list of values or ranges (`a-b`),
+ * either optionally stepped by a `/n` suffix. As in cron, if both the day of
+ * month and the day of week are restricted, a day matching either matches.
+ */
+#[derive(Debug)]
+pub struct Cron {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,  // Sunday is 0 (or 7)
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/**
+ * Parses one cron field with values from `min` to `max` into the set of
+ * matching values (indexed by value).
+ */
+fn parse_field(text: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let bad = || format!("Invalid cron field '{}' (values are {} to {}).", text, min, max);
+    let number = |s: &str| s.parse::<u32>().map_err(|_| bad());
+
+    let mut set = vec![false; max as usize + 1];
+    for part in text.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(i) => (&part[..i], try!(number(&part[i + 1..]))),
+            None => (part, 1),
+        };
+        let (lo, hi) = if range == "*" {
+            (min, max)
+        } else if let Some(i) = range.find('-') {
+            (try!(number(&range[..i])), try!(number(&range[i + 1..])))
+        } else {
+            // a single value with a step (e.g. "5/15") runs to the end
+            let v = try!(number(range));
+            (v, if step > 1 { max } else { v })
+        };
+        if step == 0 || lo < min || hi > max || lo > hi {
+            return Err(bad());
+        }
+        let mut v = lo;
+        while v <= hi {
+            set[v as usize] = true;
+            v += step;
+        }
+    }
+    Ok(set)
+}
+
+impl Cron {
+    pub fn parse(expr: &str) -> Result<Cron, String> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("Cron schedule '{}' must have 5 fields (minute hour day month weekday).", expr));
+        }
+        let mut weekdays = try!(parse_field(fields[4], 0, 7));
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+        weekdays.truncate(7);
+        Ok(Cron {
+            minutes: try!(parse_field(fields[0], 0, 59)),
+            hours: try!(parse_field(fields[1], 0, 23)),
+            days: try!(parse_field(fields[2], 1, 31)),
+            months: try!(parse_field(fields[3], 1, 12)),
+            weekdays: weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    /**
+     * Whether the minute of the given time (in seconds from epoch) matches.
+     */
+    pub fn matches(&self, time: i64) -> bool {
+        let t = Local.timestamp(time, 0);
+        self.minutes[t.minute() as usize] && self.hours[t.hour() as usize] && self.day_matches(&t)
+    }
+
+    /**
+     * Finds the first matching minute from `from` (the start of a minute) up
+     * to `to`, in seconds from epoch. Whole days and hours that don't match
+     * are skipped, and within a matching hour it goes straight to the next
+     * matching minute.
+     */
+    pub fn next_match(&self, from: i64, to: i64) -> Option<i64> {
+        let mut time = from;
+        while time <= to {
+            let t = Local.timestamp(time, 0);
+            let into_hour = (t.minute() * 60 + t.second()) as i64;
+            if !self.day_matches(&t) {
+                // up to an hour before midnight, as a day may be an hour longer or shorter
+                let into_day = t.hour() as i64 * 3600 + into_hour;
+                time += cmp::max(3600 - into_hour, 23 * 3600 - into_day);
+            } else if !self.hours[t.hour() as usize] {
+                time += 3600 - into_hour;
+            } else {
+                let minute = t.minute() as usize;
+                match (minute..60).find(|&m| self.minutes[m]) {
+                    Some(m) if m == minute => return Some(time - t.second() as i64),
+                    Some(m) => time += (m - minute) as i64 * 60 - t.second() as i64,
+                    None => time += 3600 - into_hour,
+                }
+            }
+        }
+        None
+    }
+
+    /**
+     * Whether the month and day of the given date match.
+     */
+    fn day_matches<D: Datelike>(&self, t: &D) -> bool {
+        if !self.months[t.month() as usize] {
+            return false;
+        }
+        let day = self.days[t.day() as usize];
+        let weekday = self.weekdays[t.weekday().num_days_from_sunday() as usize];
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+}
+
+/**
+ * Checks that the given window is either one-off or recurring (and makes
+ * sense), returning its parsed schedule if recurring.
+ */
+pub fn check(w: &MaintenanceWindow) -> Result<Option<Cron>, String> {
+    match (w.start, w.end, w.cron.as_ref(), w.duration) {
+        (Some(start), Some(end), None, None) => {
+            if end <= start {
+                return Err("A maintenance window must end after it starts.".to_owned());
+            }
+            Ok(None)
+        },
+        (None, None, Some(cron), Some(duration)) => {
+            if duration == 0 {
+                return Err("A recurring maintenance window must have a duration.".to_owned());
+            }
+            Cron::parse(cron).map(Some)
+        },
+        _ => Err("A maintenance window must have either a start and end, or a cron schedule and duration.".to_owned()),
+    }
+}
+
+/**
+ * The maintenance windows of a target, backed by a JSON file.
+ */
+pub struct Maintenance {
+    path: PathBuf,
+    windows: Vec<(MaintenanceWindow, Option<Cron>)>,
+}
+
+impl Maintenance {
+    /**
+     * Opens the maintenance windows file at the given path (creating it when
+     * first written). Windows that don't make sense (e.g. edited by hand) are
+     * kept, but never in effect.
+     */
+    pub fn open(path: &Path) -> Result<Maintenance, SPIOError> {
+        let windows: Vec<MaintenanceWindow> = try!(read_json_or_prev(path)).unwrap_or_else(Vec::new);
+        Ok(Maintenance {
+            path: path.to_owned(),
+            windows: windows.into_iter().map(|w| {
+                let cron = match check(&w) {
+                    Ok(cron) => cron,
+                    Err(e) => {
+                        println!("Ignoring maintenance window {:?}: {}", w.id, e);
+                        None
+                    },
+                };
+                (w, cron)
+            }).collect(),
+        })
+    }
+
+    fn save(&self) -> Result<(), SPIOError> {
+        let windows: Vec<&MaintenanceWindow> = self.windows.iter().map(|&(ref w, _)| w).collect();
+        overwrite_json(&windows, &self.path)
+    }
+
+    /**
+     * Retrieves all windows, in the order they were added.
+     */
+    pub fn windows(&self) -> Vec<MaintenanceWindow> {
+        self.windows.iter().map(|&(ref w, _)| w.clone()).collect()
+    }
+
+    /**
+     * Adds the given window (already checked, with the given schedule if
+     * recurring), returning the id assigned to it.
+     */
+    pub fn add(&mut self, mut w: MaintenanceWindow, cron: Option<Cron>) -> Result<u32, SPIOError> {
+        let id = self.windows.iter().filter_map(|&(ref w, _)| w.id).max().unwrap_or(0) + 1;
+        w.id = Some(id);
+        self.windows.push((w, cron));
+        try!(self.save());
+        Ok(id)
+    }
+
+    /**
+     * Removes the window with the given id, returning whether there was one.
+     */
+    pub fn remove(&mut self, id: u32) -> Result<bool, SPIOError> {
+        let before = self.windows.len();
+        self.windows.retain(|&(ref w, _)| w.id != Some(id));
+        if self.windows.len() == before {
+            return Ok(false);
+        }
+        try!(self.save());
+        Ok(true)
+    }
+
+    /**
+     * Finds when the given address is in maintenance between the given times
+     * (inclusive, in seconds from epoch), as sorted, non-overlapping
+     * [start, end) intervals. Windows for some addrs apply to an address if
+     * any of them resolves to the same index in the given address index (e.g.
+     * an alias, or its name before being renamed).
+     */
+    pub fn intervals(&self, index: &AddrIndex, addr: &str, from: i64, to: i64) -> Vec<(i64, i64)> {
+        let resolved = index.lookup(addr);
+        let applies = |a: &String| a == addr || (resolved.is_some() && index.lookup(a) == resolved);
+
+        let mut found = Vec::new();
+        for &(ref w, ref cron) in self.windows.iter() {
+            if w.addrs.as_ref().map_or(false, |addrs| !addrs.iter().any(&applies)) {
+                continue;
+            }
+            match (w.start, w.end, cron.as_ref(), w.duration) {
+                (Some(start), Some(end), None, None) if w.cron.is_none() && end > start => {
+                    if start <= to && end > from {
+                        found.push((start, end));
+                    }
+                },
+                (_, _, Some(cron), Some(duration)) => {
+                    // every matching minute since one duration before from starts an interval
+                    let duration = duration as i64;
+                    let earliest = from - duration;
+                    let mut next = earliest - (earliest % 60 + 60) % 60;
+                    while let Some(minute) = cron.next_match(next, to) {
+                        if minute + duration > from {
+                            found.push((minute, minute + duration));
+                        }
+                        next = minute + 60;
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        found.sort();
+        let mut merged: Vec<(i64, i64)> = Vec::with_capacity(found.len());
+        for (start, end) in found {
+            if let Some(last) = merged.last_mut() {
+                if start <= last.1 {
+                    last.1 = cmp::max(last.1, end);
+                    continue;
+                }
+            }
+            merged.push((start, end));
+        }
+        merged
+    }
+
+    /**
+     * Whether the given address (resolved through the given address index) is
+     * in maintenance at the given time.
+     */
+    pub fn covers(&self, index: &AddrIndex, addr: &str, time: i64) -> bool {
+        !self.intervals(index, addr, time, time).is_empty()
+    }
+}
+
+/**
+ * Whether the given time falls in any of the given sorted, non-overlapping
+ * intervals (as found by `Maintenance::intervals()`).
+ */
+pub fn within(intervals: &[(i64, i64)], time: i64) -> bool {
+    // the last interval starting no later than time
+    match intervals.binary_search_by(|&(start, _)| start.cmp(&time)) {
+        Ok(_) => true,
+        Err(0) => false,
+        Err(i) => time < intervals[i - 1].1,
+    }
+}
+
+#[test]
+fn schedules_one_off_and_recurring_windows() {
+    use helpers::TempDir;
+    use addrindex::AddrChange;
+
+    let cron = Cron::parse("*/15 2-3 * * 0,6").unwrap();
+    let base = Local.ymd(2020, 1, 4).and_hms(2, 30, 0).timestamp();  // a Saturday
+    assert!(cron.matches(base));
+    assert!(!cron.matches(base + 60));
+    assert!(!cron.matches(base + 2 * 86400));  // a Monday
+    assert!(Cron::parse("60 * * * *").is_err());
+    assert!(Cron::parse("* * *").is_err());
+
+    // stepping from match to match over a week, or a year of a never matching day
+    let saturday = Local.ymd(2020, 1, 4).and_hms(0, 0, 0).timestamp();
+    assert_eq!(cron.next_match(saturday, saturday + 7 * 86400), Some(saturday + 7200));
+    assert_eq!(cron.next_match(saturday + 7260, saturday + 7 * 86400), Some(saturday + 7200 + 900));
+    assert_eq!(cron.next_match(saturday + 4 * 3600, saturday + 7 * 86400), Some(saturday + 86400 + 7200));
+    assert_eq!(Cron::parse("0 0 30 2 *").unwrap().next_match(saturday, saturday + 366 * 86400), None);
+
+    let dir = TempDir::new("maintenance");
+    let path = dir.join("tcpping.maintenance.json");
+
+    // "a" is also known by the alias "a2"
+    let mut index = AddrIndex::from_path(&dir.join("tcpping.index.json")).unwrap();
+    index.ensure_for_addrs(["a".to_owned(), "b".to_owned()].iter()).unwrap();
+    index.apply(&AddrChange {
+        addr: "a2".to_owned(), alias_of: Some("a".to_owned()), name: None, retired: None, rename_to: None,
+    }, &[], 0).unwrap();
+
+    let one_off = MaintenanceWindow {
+        id: None, addrs: Some(vec!["a".to_owned()]), start: Some(base), end: Some(base + 600),
+        cron: None, duration: None, comment: None,
+    };
+    let nightly = MaintenanceWindow {
+        id: None, addrs: None, start: None, end: None,
+        cron: Some("0 2 * * *".to_owned()), duration: Some(3600), comment: Some("backups".to_owned()),
+    };
+    assert!(check(&MaintenanceWindow { end: Some(base), ..one_off.clone() }).is_err());
+    {
+        let mut m = Maintenance::open(&path).unwrap();
+        assert_eq!(m.add(one_off.clone(), check(&one_off).unwrap()).unwrap(), 1);
+        assert_eq!(m.add(nightly.clone(), check(&nightly).unwrap()).unwrap(), 2);
+    }
+
+    let mut m = Maintenance::open(&path).unwrap();
+    let two = base - 1800;  // 2:00 that night
+    // the one-off window (only for "a") merges into the nightly one
+    assert_eq!(m.intervals(&index, "a", base, base + 7200), vec![(two, two + 3600)]);
+    assert!(m.covers(&index, "b", two + 3599));
+    assert!(!m.covers(&index, "b", two + 3600));
+    assert!(within(&m.intervals(&index, "b", two, two + 86400), two + 86400 + 60));
+    assert_eq!(m.intervals(&index, "b", two, two + 7 * 86400 - 1).len(), 7);
+
+    assert!(m.remove(2).unwrap());
+    assert!(!m.remove(2).unwrap());
+    assert_eq!(m.intervals(&index, "a", base - 86400, base + 86400), vec![(base, base + 600)]);
+    assert_eq!(m.intervals(&index, "a2", base - 86400, base + 86400), vec![(base, base + 600)]);
+    assert!(!m.covers(&index, "b", base));
+    assert!(!m.covers(&index, "b", two));
+}
@@ -0,0 +1,277 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Delivery of alert notifications to the configured notifiers (HTTP webhooks,
+ * local commands, syslog or email), off the thread processing live data.
+ */
+use std::thread;
+use std::time::{Duration, Instant};
+use std::io::{Read, Write, BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Sender};
+
+use openssl::ssl::{SslConnector, SslMethod};
+use chrono::{Local, TimeZone};
+use rustc_serialize::json;
+
+use alerts::Alert;
+use options::{Notifier, SmtpSettings};
+use smtp;
+use tlsexpiry::host_of;
+use helpers::{with_default_port, connect_timeout};
+
+/// how long to wait on a webhook before giving up on it
+const WEBHOOK_TIMEOUT_SECS: u64 = 10;
+
+/// how long to let a notifier command run before killing it
+const COMMAND_TIMEOUT_SECS: u64 = 30;
+
+/**
+ * Checks that the given notifier specifies exactly one way of notifying (and
+ * that there are SMTP settings, if that is email).
+ */
+fn check(n: &Notifier, smtp: Option<&SmtpSettings>) -> Result<(), String> {
+    let ways = n.webhook.iter().count() + n.command.iter().count() +
+        n.syslog.iter().filter(|&&s| s).count() + n.email.iter().filter(|&&e| e).count();
+    if ways != 1 {
+        return Err(format!("Notifier {:?} must have exactly one of webhook, command, syslog or email.", n));
+    }
+    if n.email == Some(true) && smtp.is_none() {
+        return Err("Notifying by email needs the configuration's smtp settings.".to_owned());
+    }
+    if let Some(ref url) = n.webhook {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!("Webhook URL '{}' is not an http:// or https:// URL.", url));
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Sends the given body as a JSON `POST` request to the given http:// or
+ * https:// URL, succeeding if it is answered with a 2xx status.
+ */
+fn post_json(url: &str, body: &str) -> Result<(), String> {
+    let (tls, rest) = if url.starts_with("https://") {
+        (true, &url["https://".len()..])
+    } else {
+        (false, &url["http://".len()..])
+    };
+    let (host_port, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let addr = with_default_port(host_port, if tls { 443 } else { 80 });
+
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: stabping\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path, host_port, body.len(), body);
+
+    let timeout = Duration::from_secs(WEBHOOK_TIMEOUT_SECS);
+    let stream = try!(connect_timeout(&addr, timeout).map_err(|e| format!("{}", e)));
+    try!(stream.set_read_timeout(Some(timeout)).map_err(|e| format!("{}", e)));
+    try!(stream.set_write_timeout(Some(timeout)).map_err(|e| format!("{}", e)));
+
+    let status_line = if tls {
+        let connector = try!(SslConnector::builder(SslMethod::tls()).map_err(|e| format!("{}", e))).build();
+        let stream = try!(
+            connector.connect(host_of(&addr), stream)
+            .map_err(|e| format!("TLS handshake failed: {}", e))
+        );
+        try!(exchange(stream, &request))
+    } else {
+        try!(exchange(stream, &request))
+    };
+
+    // e.g. "HTTP/1.1 204 No Content"
+    match status_line.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(format!("Webhook responded with '{}'.", status_line.trim())),
+    }
+}
+
+/**
+ * Writes out the given request and reads back the status line of the
+ * response.
+ */
+fn exchange<S: Read + Write>(mut stream: S, request: &str) -> Result<String, String> {
+    try!(stream.write_all(request.as_bytes()).map_err(|e| format!("{}", e)));
+    let mut status_line = String::new();
+    try!(BufReader::new(stream).read_line(&mut status_line).map_err(|e| format!("{}", e)));
+    Ok(status_line)
+}
+
+/**
+ * Runs the given command through the shell with the given body on its
+ * standard input, and the alert's fields in `STABPING_ALERT_*` environment
+ * variables, killing it if it hasn't exited within the command timeout.
+ */
+fn run_command(cmd: &str, alert: &Alert, body: &str) -> Result<(), String> {
+    let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+    let mut child = try!(
+        Command::new(shell).arg(flag).arg(cmd)
+        .env("STABPING_ALERT_RULE", &alert.rule)
+        .env("STABPING_ALERT_TARGET", &alert.target)
+        .env("STABPING_ALERT_ADDR", &alert.addr)
+        .env("STABPING_ALERT_STATE", &alert.state)
+        .env("STABPING_ALERT_MESSAGE", &alert.message)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("{}", e))
+    );
+    // the command's standard input is closed once this is dropped
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(body.as_bytes());
+    }
+    let deadline = Instant::now() + Duration::from_secs(COMMAND_TIMEOUT_SECS);
+    let status = loop {
+        match try!(child.try_wait().map_err(|e| format!("{}", e))) {
+            Some(status) => break status,
+            None if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("Command '{}' was killed after {} seconds.", cmd, COMMAND_TIMEOUT_SECS));
+            },
+            None => thread::sleep(Duration::from_millis(100)),
+        }
+    };
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("Command '{}' failed with {}.", cmd, status))
+    }
+}
+
+/**
+ * Logs the given alert to the local syslog (as a warning while firing, and a
+ * notice once resolved).
+ */
+#[cfg(unix)]
+fn send_syslog(alert: &Alert) -> Result<(), String> {
+    use std::os::unix::net::UnixDatagram;
+
+    // facility daemon (3), severity warning (4) or notice (5)
+    let priority = 3 * 8 + if alert.state == "firing" { 4 } else { 5 };
+    let message = format!("<{}>stabping[{}]: {}", priority, ::std::process::id(), alert.message);
+    let socket = try!(UnixDatagram::unbound().map_err(|e| format!("{}", e)));
+    try!(socket.send_to(message.as_bytes(), "/dev/log").map_err(|e| format!("{}", e)));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_syslog(_: &Alert) -> Result<(), String> {
+    Err("Syslog is only available on Unix.".to_owned())
+}
+
+/**
+ * Emails the given alert as specified by the given SMTP settings.
+ */
+fn send_email(settings: &SmtpSettings, alert: &Alert) -> Result<(), String> {
+    let subject = format!("[stabping] {} {}: {} {}", alert.state.to_uppercase(), alert.rule,
+                          alert.target, alert.addr);
+    let body = format!("{}\n\nRule: {}\nTarget: {}\nAddress: {}\nState: {}\nSince: {}\nValue: {}\n",
+                       alert.message, alert.rule, alert.target, alert.addr, alert.state,
+                       Local.timestamp(alert.since, 0).to_rfc2822(), alert.value);
+    smtp::send_mail(settings, &subject, &body)
+}
+
+/**
+ * Delivers the given alert via the given notifier (with the given SMTP
+ * settings for email).
+ */
+pub fn deliver(n: &Notifier, smtp: Option<&SmtpSettings>, alert: &Alert) -> Result<(), String> {
+    let body = json::encode(alert).unwrap();
+    if let Some(ref url) = n.webhook {
+        post_json(url, &body)
+    } else if let Some(ref cmd) = n.command {
+        run_command(cmd, alert, &body)
+    } else if n.email == Some(true) {
+        match smtp {
+            Some(settings) => send_email(settings, alert),
+            None => Err("No smtp settings to send email with.".to_owned()),
+        }
+    } else {
+        send_syslog(alert)
+    }
+}
+
+/**
+ * Runs the notifier thread, which delivers each alert sent to it via every
+ * one of the given notifiers, or describes what is wrong with the first
+ * invalid one. Failing to deliver is only logged.
+ */
+pub fn run_notifier(notifiers: Vec<Notifier>, smtp: Option<SmtpSettings>) -> Result<Sender<Alert>, String> {
+    for n in notifiers.iter() {
+        try!(check(n, smtp.as_ref()));
+    }
+
+    let (sender, alerts) = channel::<Alert>();
+    thread::spawn(move || {
+        for alert in alerts {
+            println!("Alert {}", alert.message);
+            for n in notifiers.iter() {
+                if let Err(e) = deliver(n, smtp.as_ref(), &alert) {
+                    println!("Failed to notify of alert via {:?}: {}", n, e);
+                }
+            }
+        }
+    });
+    Ok(sender)
+}
+
+#[test]
+fn posts_alerts_to_webhook() {
+    use std::net::TcpListener;
+
+    // a stand-in for the receiving end, answering each request with the given status
+    let serve = |status: &'static str| -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0; 4096];
+            // read until the end of the body (the last thing in it is a closing brace)
+            while !received.ends_with(b"}") {
+                let n = stream.read(&mut buf).unwrap();
+                received.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes()).unwrap();
+            String::from_utf8(received).unwrap()
+        });
+        (url, handle)
+    };
+    let alert = Alert {
+        rule: "down".to_owned(),
+        target: "tcpping".to_owned(),
+        addr: "example.com:80".to_owned(),
+        state: "firing".to_owned(),
+        since: 60,
+        time: 60,
+        value: 3.0,
+        message: "down: tcpping example.com:80 has errors_in_a_row of 3 (limit 3).".to_owned(),
+        silenced: false,
+    };
+
+    let (url, handle) = serve("204 No Content");
+    let n = Notifier { webhook: Some(url), command: None, syslog: None, email: None };
+    assert!(check(&n, None).is_ok());
+    deliver(&n, None, &alert).unwrap();
+    let request = handle.join().unwrap();
+    assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+    assert!(request.ends_with(&json::encode(&alert).unwrap()));
+
+    let (url, handle) = serve("500 Internal Server Error");
+    let n = Notifier { webhook: Some(url), command: None, syslog: None, email: None };
+    assert!(deliver(&n, None, &alert).is_err());
+    handle.join().unwrap();
+
+    assert!(check(&Notifier { webhook: None, command: None, syslog: Some(false), email: None }, None).is_err());
+    assert!(check(&Notifier { webhook: None, command: None, syslog: None, email: Some(true) }, None).is_err());
+}
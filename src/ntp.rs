@@ -0,0 +1,307 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+use std::io;
+use std::cmp;
+use std::thread;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use std::net::{UdpSocket, ToSocketAddrs};
+
+use options::{SENTINEL_ERROR, SENTINEL_NODATA};
+use options::TargetResults;
+use persist::TargetManager;
+use helpers::{now_millis, with_default_port};
+
+/// seconds between the NTP epoch (1900) and the UNIX epoch (1970)
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+const NTP_PACKET_LEN: usize = 48;
+const NTP_DEFAULT_PORT: u16 = 123;
+
+/**
+ * Obtains the current wall-clock time in microseconds from epoch (the same
+ * clock `now_millis()` reads when timestamping data).
+ */
+fn now_micros() -> i64 {
+    let d = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    d.as_secs() as i64 * 1_000_000 + (d.subsec_nanos() / 1000) as i64
+}
+
+/**
+ * Converts microseconds from the UNIX epoch into a 64-bit NTP timestamp.
+ */
+fn micros_to_ntp(micros: i64) -> u64 {
+    let secs = (micros / 1_000_000) as u64 + NTP_UNIX_OFFSET;
+    let frac = (((micros % 1_000_000) as u64) << 32) / 1_000_000;
+    (secs << 32) | frac
+}
+
+/**
+ * Converts a 64-bit NTP timestamp into microseconds from the UNIX epoch.
+ */
+fn ntp_to_micros(ntp: u64) -> i64 {
+    let secs = (ntp >> 32) as i64 - NTP_UNIX_OFFSET as i64;
+    let frac = (((ntp & 0xffff_ffff) * 1_000_000) >> 32) as i64;
+    secs * 1_000_000 + frac
+}
+
+fn read_u64_be(buf: &[u8]) -> u64 {
+    buf.iter().take(8).fold(0, |acc, &b| (acc << 8) | b as u64)
+}
+
+fn write_u64_be(buf: &mut [u8], val: u64) {
+    for i in 0..8 {
+        buf[i] = (val >> (56 - 8 * i)) as u8;
+    }
+}
+
+/**
+ * Performs a single SNTP (RFC 4330) query against the given server, returning
+ * the (offset, round-trip delay) of the local clock in microseconds, where a
+ * positive offset means the local clock is behind the server.
+ */
+pub fn sntp_query(addr: &str, timeout: Duration) -> io::Result<(i64, i64)> {
+    let server = try!(
+        try!(with_default_port(addr, NTP_DEFAULT_PORT).to_socket_addrs())
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "No address resolved."))
+    );
+
+    let bind_addr = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = try!(UdpSocket::bind(bind_addr));
+    try!(socket.set_read_timeout(Some(timeout)));
+
+    // LI = 0 (no warning), VN = 4, Mode = 3 (client)
+    let mut req = [0u8; NTP_PACKET_LEN];
+    req[0] = 0x23;
+
+    let t1 = now_micros();
+    let t1_ntp = micros_to_ntp(t1);
+    write_u64_be(&mut req[40..48], t1_ntp);
+    try!(socket.send_to(&req, server));
+
+    let mut resp = [0u8; NTP_PACKET_LEN];
+    loop {
+        let (len, from) = try!(socket.recv_from(&mut resp));
+        let t4 = now_micros();
+
+        // ignore anything that isn't a reply to our query
+        if from != server || len < NTP_PACKET_LEN || read_u64_be(&resp[24..32]) != t1_ntp {
+            continue;
+        }
+
+        let mode = resp[0] & 0x07;
+        let stratum = resp[1];
+        if mode != 4 && mode != 5 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Response not from an NTP server."));
+        }
+        if stratum == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Kiss-o'-death from NTP server."));
+        }
+
+        let t2 = ntp_to_micros(read_u64_be(&resp[32..40]));
+        let t3 = ntp_to_micros(read_u64_be(&resp[40..48]));
+
+        let offset = ((t2 - t1) + (t3 - t4)) / 2;
+        let delay = (t4 - t1) - (t3 - t2);
+        return Ok((offset, delay));
+    }
+}
+
+/**
+ * Clamps an offset in microseconds into the range of i32 values that do not
+ * collide with the sentinel values.
+ */
+fn clamp_offset(offset: i64) -> i32 {
+    if offset > i32::max_value() as i64 {
+        i32::max_value()
+    } else if offset <= SENTINEL_NODATA as i64 {
+        SENTINEL_NODATA + 1
+    } else {
+        offset as i32
+    }
+}
+
+/**
+ * Runs the NTP target's data-collection worker.
+ */
+pub fn run_ntp_worker(manager: Arc<TargetManager>,
+                      results_out: Sender<TargetResults>) -> thread::JoinHandle<()> {
+    // start a new thread for the worker
+    thread::spawn(move || {
+        let mut handles = Vec::new();
+
+        // continue to collect data forever
+        loop {
+            // retrieve the target's current options
+            let (dur_interval, dur_timeout, avg_across, dur_pause, num_addrs, threshold) = {
+                let ref opt = manager.options_read();
+                (
+                    Duration::from_millis(opt.interval as u64),
+                    Duration::from_millis(cmp::max(opt.interval / cmp::max(opt.avg_across, 1), 1) as u64),
+                    opt.avg_across,
+                    Duration::from_millis(opt.pause as u64),
+                    opt.addrs.len(),
+                    opt.threshold,
+                )
+            };
+
+            // get the current time (to timestamp this round of data with)
+            let time_ms = now_millis();
+            let timestamp = (time_ms / 1000) as i32;
+
+            let (nonce, addrs) = {
+                let ref t_opt = manager.options_read();
+                for addr in t_opt.addrs.iter() {
+                    let a = addr.clone();
+                    let m = manager.clone();
+
+                    /*
+                     * create channels so the per-addr threads can send back
+                     * their data to the worker thread
+                     */
+                    let (tx, rx) = channel();
+                    handles.push(rx);
+
+                    /*
+                     * spawn a thread to actually query each separate server,
+                     * giving each query an even share of the interval
+                     */
+                    thread::spawn(move || {
+                        let mut offset_sum = 0;
+                        let mut delay_sum = 0;
+                        let mut denom = 0;
+                        let mut raw = Vec::with_capacity(avg_across as usize);
+                        // average the results across the given number of times
+                        for _ in 0..avg_across {
+                            let attempted_at = now_millis();
+                            if let Ok((offset, delay)) = sntp_query(a.as_str(), dur_timeout) {
+                                offset_sum += offset;
+                                delay_sum += delay;
+                                denom += 1;
+                                raw.push((attempted_at, clamp_offset(offset)));
+                            } else {
+                                raw.push((attempted_at, SENTINEL_ERROR));
+                            }
+                            thread::sleep(dur_pause);
+                        }
+
+                        // keep the individual attempts around if so configured
+                        if let Err(e) = m.append_raw(&a, &raw) {
+                            println!("Failed to store raw samples: {}", e);
+                        }
+
+                        if denom != 0 {
+                            // as for tcpping, a failed send is of no concern
+                            let _ = tx.send((offset_sum / denom, delay_sum / denom));
+                        }
+                    });
+                }
+                (t_opt.nonce, t_opt.addrs.clone())
+            };
+
+            /*
+             * wait out the designated data-collectiong interval, while giving
+             * the give the per-addr subthreads the entire interval of time to
+             * come back
+             */
+            thread::sleep(dur_interval);
+
+            let mut data: Vec<i32> = Vec::with_capacity(3 + num_addrs);
+            let mut delays: Vec<i32> = Vec::with_capacity(num_addrs);
+
+            data.push(manager.kind.kind_id());
+            data.push(nonce);
+            data.push(timestamp);
+
+            // read back the data from the per-addr subthreads
+            for (h, addr) in handles.drain(..).zip(addrs.iter()) {
+                if let Ok((offset, delay)) = h.try_recv() {
+                    data.push(clamp_offset(offset));
+                    delays.push(clamp_offset(delay));
+
+                    /*
+                     * warn when the local clock (which timestamps all our
+                     * data) drifts beyond the threshold (in millis)
+                     */
+                    match threshold {
+                        Some(t) if offset.abs() > t as i64 * 1000 => {
+                            manager.health().raise(
+                                manager.kind.compact_name(), addr,
+                                format!("Local clock offset {} ms exceeds {} ms (round-trip delay {} ms).",
+                                        offset / 1000, t, delay / 1000));
+                        },
+                        _ => manager.health().clear(manager.kind.compact_name(), addr),
+                    }
+                } else {
+                    // on error or timeout, hand back a sentinel value
+                    data.push(SENTINEL_ERROR);
+                    delays.push(SENTINEL_NODATA);
+                }
+            }
+
+            // send off our results to the main thread
+            if results_out.send(TargetResults(data, time_ms, delays)).is_err() {
+                println!("Worker Control: failed to send final results back.");
+            }
+        }
+    })
+}
+
+#[test]
+fn ntp_timestamp_conversion_roundtrip() {
+    let micros = 1_476_000_000_123_456;
+    assert_eq!(ntp_to_micros(micros_to_ntp(micros)), micros);
+}
+
+#[test]
+fn sntp_query_against_local_stand_in() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // pretend to be a stratum 2 server whose clock is 1.5s ahead of ours
+    let skew = 1_500_000;
+    thread::spawn(move || {
+        let mut buf = [0u8; NTP_PACKET_LEN];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let mut resp = [0u8; NTP_PACKET_LEN];
+        resp[0] = 0x24;  // VN = 4, Mode = 4 (server)
+        resp[1] = 2;
+        // originate timestamp is the client's transmit timestamp
+        resp[24..32].copy_from_slice(&buf[40..48]);
+        let now = micros_to_ntp(now_micros() + skew);
+        write_u64_be(&mut resp[32..40], now);
+        write_u64_be(&mut resp[40..48], now);
+        server.send_to(&resp, client).unwrap();
+    });
+
+    let (offset, delay) = sntp_query(&format!("{}", server_addr), Duration::from_secs(2)).unwrap();
+    assert!((offset - skew).abs() < 100_000);
+    assert!(delay >= 0 && delay < 100_000);
+}
+
+#[test]
+fn sntp_query_rejects_kiss_of_death() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    thread::spawn(move || {
+        let mut buf = [0u8; NTP_PACKET_LEN];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let mut resp = [0u8; NTP_PACKET_LEN];
+        resp[0] = 0x24;
+        resp[1] = 0;  // stratum 0, i.e. kiss-o'-death
+        resp[24..32].copy_from_slice(&buf[40..48]);
+        server.send_to(&resp, client).unwrap();
+    });
+
+    assert!(sntp_query(&format!("{}", server_addr), Duration::from_secs(2)).is_err());
+}
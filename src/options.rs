@@ -13,18 +13,55 @@ use std::sync::mpsc::Sender;
 
 use persist::{TargetManager, ManagerError};
 use tcpping::run_tcpping_worker;
+use ntp::run_ntp_worker;
+use tcpexpect::run_tcpexpect_worker;
+use tlsexpiry::run_tlsexpiry_worker;
 
-#[derive(RustcEncodable, RustcDecodable, Debug)]
+/**
+ * How much persisted data to keep around, by age and/or by size of the data
+ * file (whichever is stricter).
+ */
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone)]
+pub struct RetentionPolicy {
+    pub max_age: Option<u32>,  // in seconds
+    pub max_size: Option<u64>,  // in bytes
+}
+
+/**
+ * How much time each segment of a target's data covers.
+ */
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, Copy, PartialEq)]
+pub enum SegmentSpan {
+    Day,
+    Month,
+}
+
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone)]
 pub struct TargetOptions {
     pub nonce: i32,
     pub addrs: Vec<String>,  // Vec of addresses (IPs to hit with TCP, files to download, etc.)
     pub interval: u32,  // interval between collection attempts, in millis
     pub avg_across: u32,  // number of sub-attempts average across for each interval
     pub pause: u32,  // pause between sub-attempts, in millis
+    pub threshold: Option<i32>,  // kind-defined threshold beyond which a health warning is raised
+    pub fast_interval: Option<u32>,  // interval for probing degraded addrs, in millis (adaptive sampling, tcpping only)
+    pub raw_retention: Option<u32>,  // how long to keep raw per-attempt samples, in seconds (none if unset)
+    pub retention: Option<RetentionPolicy>,  // overrides the configuration's retention for this target
+    pub latency_threshold: Option<i32>,  // values beyond which an addr counts as degraded in incidents
+    pub anomaly_sigma: Option<f64>,  // standard deviations off an addr's baseline that make an anomaly
 }
 
 pub static SENTINEL_ERROR: i32 = -2_100_000_000;
 pub static SENTINEL_NODATA: i32 = -2_000_000_000;
+pub static SENTINEL_MISMATCH: i32 = -2_110_000_000;
+
+/**
+ * Whether the given value is a failure: all sentinels besides nodata (which
+ * isn't a sample, and is never stored) are.
+ */
+pub fn is_failure(val: i32) -> bool {
+    val < SENTINEL_NODATA
+}
 
 /*
  * Data for each address. Structured as:
@@ -36,28 +73,48 @@ pub static SENTINEL_NODATA: i32 = -2_000_000_000;
  *
  * each datapoint is for each address in TargetOptions.addrs
  * (encoding of data inside the i32 is target-defined, or one of the
- * sentinel values for error or nodata),
+ * sentinel values for error, nodata, or mismatch: an answer that was not the
+ * one expected, such as a certificate chain failing validation),
+ *
+ * along with the time these data were collected at in milliseconds from
+ * epoch (which is what gets stored; the timestamp above is only as sent to
+ * clients over websockets), and the secondary (aux) value of each datapoint
+ * in the same order (empty for kinds without one)
  */
-pub struct TargetResults(pub Vec<i32>);
+pub struct TargetResults(pub Vec<i32>, pub i64, pub Vec<i32>);
 
 pub enum TargetKind {
     TcpPing,
+    Ntp,
+    TcpExpect,
+    TlsExpiry,
     HttpDownload,
 }
 
-static ALL_KINDS: [TargetKind; 1] = [TargetKind::TcpPing];
+pub static ALL_KINDS: [TargetKind; 4] = [
+    TargetKind::TcpPing,
+    TargetKind::Ntp,
+    TargetKind::TcpExpect,
+    TargetKind::TlsExpiry,
+];
 
 impl TargetKind {
     pub fn kind_id(&self) -> i32 {
         match *self {
             TargetKind::TcpPing => 0,
-            TargetKind::HttpDownload => 1
+            TargetKind::Ntp => 1,
+            TargetKind::TcpExpect => 2,
+            TargetKind::TlsExpiry => 3,
+            TargetKind::HttpDownload => 4,
         }
     }
 
     pub fn compact_name(&self) -> &'static str {
         match *self {
             TargetKind::TcpPing => "tcpping",
+            TargetKind::Ntp => "ntp",
+            TargetKind::TcpExpect => "tcpexpect",
+            TargetKind::TlsExpiry => "tlsexpiry",
             TargetKind::HttpDownload => "httpdownload",
         }
     }
@@ -70,6 +127,51 @@ impl TargetKind {
                 interval: 10_000,
                 avg_across: 3,
                 pause: 100,
+                threshold: None,
+                fast_interval: None,
+                raw_retention: None,
+                retention: None,
+                latency_threshold: None,
+                anomaly_sigma: None,
+            },
+            TargetKind::Ntp => TargetOptions {
+                nonce: 0,
+                addrs: Vec::new(),
+                interval: 60_000,
+                avg_across: 1,
+                pause: 0,
+                threshold: Some(500),
+                fast_interval: None,
+                raw_retention: None,
+                retention: None,
+                latency_threshold: None,
+                anomaly_sigma: None,
+            },
+            TargetKind::TcpExpect => TargetOptions {
+                nonce: 0,
+                addrs: Vec::new(),
+                interval: 30_000,
+                avg_across: 1,
+                pause: 0,
+                threshold: None,
+                fast_interval: None,
+                raw_retention: None,
+                retention: None,
+                latency_threshold: None,
+                anomaly_sigma: None,
+            },
+            TargetKind::TlsExpiry => TargetOptions {
+                nonce: 0,
+                addrs: Vec::new(),
+                interval: 3_600_000,
+                avg_across: 1,
+                pause: 0,
+                threshold: Some(14),
+                fast_interval: None,
+                raw_retention: None,
+                retention: None,
+                latency_threshold: None,
+                anomaly_sigma: None,
             },
             _ => unimplemented!()
         }
@@ -79,25 +181,88 @@ impl TargetKind {
                              results_out: Sender<TargetResults>) -> thread::JoinHandle<()> {
         match *self {
             TargetKind::TcpPing => run_tcpping_worker(manager, results_out),
+            TargetKind::Ntp => run_ntp_worker(manager, results_out),
+            TargetKind::TcpExpect => run_tcpexpect_worker(manager, results_out),
+            TargetKind::TlsExpiry => run_tlsexpiry_worker(manager, results_out),
             _ => unimplemented!()
         }
     }
 
-    pub fn new_managers_for_all<'a>(data_path: &'a Path) -> Result<Vec<Arc<TargetManager>>, ManagerError> {
+    pub fn new_managers_for_all<'a>(data_path: &'a Path, span: SegmentSpan) -> Result<Vec<Arc<TargetManager>>, ManagerError> {
         let mut targets = Vec::with_capacity(ALL_KINDS.len());
         for k in ALL_KINDS.iter() {
             targets.push(
-                Arc::new(try!(TargetManager::new(k, data_path)))
+                Arc::new(try!(TargetManager::new(k, data_path, span)))
             );
         }
         Ok(targets)
     }
 }
 
+/**
+ * A rule raising an alert for an address of a target (or each of them, if no
+ * `addr` is given) when its `condition` holds:
+ *
+ * - "p95_above": the 95th percentile of values over the last `window` seconds
+ *   is above `limit`
+ * - "loss_above": the percentage of failed attempts over the last `window`
+ *   seconds is above `limit`
+ * - "errors_in_a_row": at least `limit` consecutive attempts failed (counting
+ *   those at the fast interval, so it may fire in less than `limit` intervals
+ *   while an address is sampled faster)
+ * - "below": the last value was below `limit` (e.g. days left of a TLS
+ *   certificate), a mismatch counting as below any limit and attempts failing
+ *   outright leaving it as it was
+ * - "anomaly": the last value was more than `limit` standard deviations off
+ *   the address's learned baseline
+ */
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone)]
+pub struct AlertRule {
+    pub name: String,
+    pub target: String,  // compact name of the target kind
+    pub addr: Option<String>,
+    pub condition: String,
+    pub limit: f64,
+    pub window: Option<u32>,  // in seconds (5 minutes if unset)
+}
+
+/**
+ * Where to send alert notifications to: exactly one of a `webhook` URL (to
+ * POST the alert as JSON to), a `command` (run by the shell with the alert as
+ * JSON on its standard input), `syslog`, or `email` (via the configuration's
+ * SMTP settings).
+ */
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone)]
+pub struct Notifier {
+    pub webhook: Option<String>,
+    pub command: Option<String>,
+    pub syslog: Option<bool>,
+    pub email: Option<bool>,
+}
+
+/**
+ * How to send email (alerts and daily digests) over SMTP.
+ */
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone)]
+pub struct SmtpSettings {
+    pub server: String,  // host or host:port (port 587 if unset)
+    pub starttls: Option<bool>,  // upgrade to TLS before authenticating (true if unset)
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    pub digest_hour: Option<u32>,  // local hour of day to send a daily digest at (none if unset)
+}
+
 #[derive(RustcEncodable, RustcDecodable, Debug)]
 pub struct MainConfiguration {
     pub web_port: u16,
     pub ws_port: u16,
+    pub retention: Option<RetentionPolicy>,  // for all targets (that don't override it)
+    pub segment_span: Option<SegmentSpan>,  // how much time each data segment covers (a day if unset)
+    pub alerts: Option<Vec<AlertRule>>,
+    pub notifiers: Option<Vec<Notifier>>,  // where alerts are sent to
+    pub smtp: Option<SmtpSettings>,
 }
 
 impl Default for MainConfiguration {
@@ -105,6 +270,11 @@ impl Default for MainConfiguration {
         MainConfiguration {
             web_port: 5001,
             ws_port: 5002,
+            retention: None,
+            segment_span: None,
+            alerts: None,
+            notifiers: None,
+            smtp: None,
         }
     }
 }
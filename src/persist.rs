@@ -8,19 +8,32 @@
 
 use std::fmt;
 use std::fmt::Display;
-use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::fs::OpenOptions;
 use std::fs::File;
+use std::mem;
 use std::io::Write;
-use std::io::BufReader;
 use std::sync::{Mutex, RwLock, RwLockReadGuard};
-use std::ops::Deref;
 use std::iter;
 use std::iter::Extend;
 
-use helpers::{SPIOError, SPFile, VecIntoRawBytes, overwrite_json};
-use options::{TargetKind, TargetOptions, TargetResults, SENTINEL_NODATA};
+use chrono::Local;
+use memmap::{Mmap, Protection};
+
+use helpers::{SPIOError, SPFile, VecIntoRawBytes, overwrite_json, read_json_or_prev,
+              raw_bytes_as_slice, decode_i32_le, now_millis};
+use datafile;
+use datafile::{Record, DataRecord};
+use segments::Segments;
+use addrindex::{AddrIndex, AddrInfo, AddrChange, IndexError};
+use health::Health;
+use history::{OptionsHistory, OptionsVersion};
+use incidents::{IncidentLog, Incident};
+use baseline::{Baselines, AnomalyEvent};
+use maintenance;
+use maintenance::{Maintenance, MaintenanceWindow};
+use rollup::{RollupTier, ROLLUP_TIERS};
+use options::{TargetKind, TargetOptions, TargetResults, RetentionPolicy, SegmentSpan, SENTINEL_NODATA};
 
 /**
  * A stabping-specific error container for errors incurred during TargetManager
@@ -31,6 +44,12 @@ pub enum ManagerError {
     IndexFileIO(SPIOError),
     DataFileIO(SPIOError),
     OptionsFileIO(SPIOError),
+    RawFileIO(SPIOError),
+    RollupFileIO(SPIOError),
+    HistoryFileIO(SPIOError),
+    IncidentFileIO(SPIOError),
+    MaintenanceFileIO(SPIOError),
+    InvalidChange(String),
 }
 
 impl ManagerError {
@@ -39,6 +58,12 @@ impl ManagerError {
             ManagerError::IndexFileIO(ref e) => format!("{} index file", e.description()),
             ManagerError::DataFileIO(ref e) => format!("{} data file", e.description()),
             ManagerError::OptionsFileIO(ref e) => format!("{} options file", e.description()),
+            ManagerError::RawFileIO(ref e) => format!("{} raw samples file", e.description()),
+            ManagerError::RollupFileIO(ref e) => format!("{} rollup file", e.description()),
+            ManagerError::HistoryFileIO(ref e) => format!("{} options history file", e.description()),
+            ManagerError::IncidentFileIO(ref e) => format!("{} incident log file", e.description()),
+            ManagerError::MaintenanceFileIO(ref e) => format!("{} maintenance windows file", e.description()),
+            ManagerError::InvalidChange(ref msg) => msg.clone(),
         }
     }
 }
@@ -51,123 +76,55 @@ impl Display for ManagerError {
 
 
 /**
- * A per-target global persistent mapping of index (an integer) to an address
- * (a string used in `TargetOptions.addrs`) backed by an index file.
+ * Representation of raw (per-attempt) samples on-disk in a target's raw
+ * samples file. They are 32-bit back-to-back little-endian integers.
  */
-#[derive(Debug)]
-struct AddrIndex {
-    file: File,
-    data: Vec<String>,
-    map: HashMap<String, i32>,
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct RawElement {
+    time: [u8; 4],  // seconds from epoch
+    millis: [u8; 4],  // milliseconds past `time`
+    index: [u8; 4],
+    val: [u8; 4],
 }
 
-impl AddrIndex {
-    /**
-     * Creates an `AddrIndex` backed by the index file residing at the given
-     * path.
-     */
-    fn from_path<'b>(path: &'b Path) -> Result<Self, ManagerError> {
-        // attempt to open the index file
-        let mut index_file = try!(
-            File::open_from(OpenOptions::new().read(true).append(true).create(true), path)
-            .map_err(|e| ManagerError::IndexFileIO(e))
-        );
-
-        let mut index_data = Vec::new();
-        /*
-         * if the index file is non-empty, read the data into a list that will
-         * function as the index -> addr mapping
-         */
-        if try!(index_file.length_p(path)
-                .map_err(|e| ManagerError::IndexFileIO(e))) > 0 {
-            use std::io::BufRead;
-            let reader = BufReader::new(&mut index_file);
-
-            for line_res in reader.lines() {
-                let line = try!(line_res
-                                .map_err(|_| ManagerError::IndexFileIO(
-                                             SPIOError::Parse(Some(path.to_owned())))));
-                index_data.push(line);
-            }
-        }
-
-        // create the map that will contain the reverse addr -> index mapping
-        let mut index_map = HashMap::new();
-        for (i, addr) in index_data.iter().enumerate() {
-            index_map.insert(addr.clone(), i as i32);
-        }
-
-        Ok(AddrIndex {
-            file: index_file,
-            data: index_data,
-            map: index_map,
-        })
-    }
-
-    /**
-     * Adds an addr into this index as necessary (if it does not already
-     * exist in the index).
-     */
-    fn add_addr(&mut self, addr: &str) -> Result<(), ManagerError> {
-        if let None = self.map.get(addr) {
-            // only deal with it if we don't already have it
-            self.map.insert(addr.to_owned(), self.data.len() as i32);
-            self.data.push(addr.to_owned());
-            try!(self.file.write_all(format!("{}\n", addr).as_bytes())
-                 .map_err(|_| ManagerError::IndexFileIO(
-                              SPIOError::Write(None))));
-        }
-        Ok(())
-    }
-
-    /**
-     * Ensures (adding them if necessary) that all addrs in the given iterator
-     * exist in this index.
-     */
-    fn ensure_for_addrs<'a, I, K>(&mut self, addrs: I) -> Result<(), ManagerError>
-            where I: Iterator<Item=&'a K>, K: 'a + Deref<Target=str> {
-        for addr in addrs {
-            try!(self.add_addr(&addr));
-        }
-        Ok(())
-    }
+impl RawElement {
+    pub fn time(&self) -> i32 { decode_i32_le(self.time) }
+    pub fn millis(&self) -> i32 { decode_i32_le(self.millis) }
+    pub fn index(&self) -> i32 { decode_i32_le(self.index) }
+    pub fn val(&self) -> i32 { decode_i32_le(self.val) }
+}
 
-    /**
-     * Retrieves the index associated with the given address.
-     */
-    fn get_index(&self, addr: &str) -> i32 {
-        self.map.get(addr).cloned().expect("Non-existant addr requested from AddrIndex!")
-    }
+/// seconds between prunes of expired raw samples
+static RAW_PRUNE_PERIOD: i64 = 3600;
 
-    /**
-     * Retrieves the adress associated with the given index.
-     */
-    fn get_addr(&self, index: i32) -> &String {
-        self.data.get(index as usize).expect("Non-existant index requested from AddrIndex!")
-    }
-
-    /**
-     * Returns the length (as in number of unique addresses) in this index.
-     */
-    fn len(&self) -> usize {
-        self.data.len()
-    }
-}
+/// how far back data is learned from for addr baselines at startup, in millis
+static BASELINE_LEARN_MS: i64 = 86_400_000;
 
 /**
  * Master control structure managing all I/O backed resources (with the
  * exception of running workers which is handled by `TargetKind` and the main
  * thread directly) of a given target.
  *
- * This is include most notably, the target's data file, address index (and
- * associated index file), and options (and associated options file).
+ * This is include most notably, the target's data segments, address index (and
+ * associated index file), options (and associated options file), and raw
+ * samples file.
  */
 pub struct TargetManager {
     pub kind: &'static TargetKind,
     index: RwLock<AddrIndex>,
-    data_file: RwLock<File>,
+    data: RwLock<Segments>,
+    rollups: Vec<RollupTier>,  // finest first
+    raw_file: RwLock<File>,
+    raw_path: PathBuf,
+    raw_pruned: Mutex<i64>,  // when raw samples were last pruned, in seconds from epoch
     options_path: Mutex<PathBuf>,
     options: RwLock<TargetOptions>,
+    history: RwLock<OptionsHistory>,
+    incidents: Mutex<IncidentLog>,
+    baselines: Mutex<Baselines>,
+    maintenance: RwLock<Maintenance>,
+    health: Health,
 }
 
 impl TargetManager {
@@ -175,65 +132,146 @@ impl TargetManager {
      * Creates a new `TargetManager` for the given target kind that will store
      * persistent data at the given location path.
      */
-    pub fn new<'b>(kind: &'static TargetKind, data_path: &'b Path) -> Result<Self, ManagerError> {
+    pub fn new<'b>(kind: &'static TargetKind, data_path: &'b Path, span: SegmentSpan) -> Result<Self, ManagerError> {
         let mut path = data_path.to_owned();
 
-        // attempt to open the target's data file
-        path.push(format!("{}.data.dat", kind.compact_name()));
-        let data_file = try!(
-            File::open_from(OpenOptions::new().read(true).append(true).create(true), &path)
+        // attempt to open the target's data segments
+        let segments = try!(
+            Segments::open(kind.compact_name(), &path, span)
             .map_err(|e| ManagerError::DataFileIO(e))
         );
+
+        /*
+         * open the target's rollup tiers, catching each up with the data
+         * after its last written bucket
+         */
+        let mut rollups = Vec::with_capacity(ROLLUP_TIERS.len());
+        for &(name, width) in ROLLUP_TIERS.iter() {
+            let tier = try!(
+                RollupTier::open(kind.compact_name(), &path, name, width)
+                .map_err(|e| ManagerError::RollupFileIO(e))
+            );
+            let resume_ms = try!(tier.resume_ms().map_err(|e| ManagerError::RollupFileIO(e)));
+            try!(
+                segments.for_each_chunk(resume_ms, i64::max_value(), |chunk| tier.observe(chunk))
+                .map_err(|e| ManagerError::RollupFileIO(e))
+            );
+            rollups.push(tier);
+        }
+
+        // attempt to open the target's raw samples file
+        path.push(format!("{}.raw.dat", kind.compact_name()));
+        let raw_path = path.clone();
+        if raw_path.exists() {
+            try!(datafile::repair_torn_tail(&raw_path, 0, mem::size_of::<RawElement>())
+                 .map_err(|e| ManagerError::RawFileIO(e)));
+        }
+        let raw_file = try!(
+            File::open_from(OpenOptions::new().read(true).append(true).create(true), &raw_path)
+            .map_err(|e| ManagerError::RawFileIO(e))
+        );
         path.pop();
 
         // attempt to open the target's options file
         let options_file_name = format!("{}.options.json", kind.compact_name());
         path.push(&options_file_name);
-        let mut options_file = try!(
-            File::open_from(OpenOptions::new().read(true).write(true).create(true), &path)
-            .map_err(|e| ManagerError::OptionsFileIO(e))
-        );
 
         /*
-         * read back existing options from the options file, or write out
-         * default options for this target to the options file
+         * read back existing options from the options file (or its
+         * previous-good copy), or write out default options for this target
+         * to the options file
          */
-        let options = if try!(options_file.length_p(&path)
-                              .map_err(|e| ManagerError::OptionsFileIO(e))) > 0 {
-            try!(
-                options_file.read_json_p(&path)
-                .map_err(|e| ManagerError::OptionsFileIO(e))
-            )
-        } else {
-            let default_options = kind.default_options();
-            try!(
-                options_file.write_json_p(&default_options, &path)
-                .map_err(|e| ManagerError::OptionsFileIO(e))
-            );
-            default_options
+        let options = match try!(read_json_or_prev(&path)
+                                 .map_err(|e| ManagerError::OptionsFileIO(e))) {
+            Some(options) => options,
+            None => {
+                let default_options = kind.default_options();
+                try!(
+                    overwrite_json(&default_options, &path)
+                    .map_err(|e| ManagerError::OptionsFileIO(e))
+                );
+                default_options
+            },
         };
 
         path.pop();
 
+        // attempt to open the target's options history (recording the options)
+        path.push(format!("{}.history.json", kind.compact_name()));
+        let history = try!(
+            OptionsHistory::open(&path, &options)
+            .map_err(|e| ManagerError::HistoryFileIO(e))
+        );
+        path.pop();
+
+        // attempt to open the target's incident log
+        path.push(format!("{}.incidents.json", kind.compact_name()));
+        let mut incidents = try!(IncidentLog::open(&path).map_err(|e| ManagerError::IncidentFileIO(e)));
+        path.pop();
+
+        // attempt to open the target's maintenance windows
+        path.push(format!("{}.maintenance.json", kind.compact_name()));
+        let maintenance = try!(Maintenance::open(&path).map_err(|e| ManagerError::MaintenanceFileIO(e)));
+        path.pop();
+
         /*
          * attempt to open the target's index file and create an index out of
          * it; additionally ensure that all addresses present in the options
          * are present in the index
          */
         path.push(format!("{}.index.json", kind.compact_name()));
-        let mut index = try!(AddrIndex::from_path(&path));
-        try!(index.ensure_for_addrs(options.addrs.iter()));
+        let mut index = try!(AddrIndex::from_path(&path).map_err(|e| ManagerError::IndexFileIO(e)));
+        try!(index.ensure_for_addrs(options.addrs.iter()).map_err(|e| ManagerError::IndexFileIO(e)));
+
+        // catch up the first and last seen times of addrs with the data
+        let seen_until = index.seen_until();
+        try!(
+            segments.for_each_chunk(seen_until, i64::max_value(), |chunk| index.observe(chunk))
+            .and_then(|_| index.flush_seen())
+            .map_err(|e| ManagerError::IndexFileIO(e))
+        );
         path.pop();
 
+        // pick back up the states of addrs in an ongoing incident from the data
+        if let Some(resume_ms) = incidents.resume_ms() {
+            try!(
+                segments.for_each_chunk(resume_ms, i64::max_value(), |chunk| {
+                    incidents.replay(&chunk[datafile::lower_bound(chunk, resume_ms)..], &index,
+                                     &options.addrs, options.latency_threshold)
+                })
+                .map_err(|e| ManagerError::IncidentFileIO(e))
+            );
+        }
+
+        // learn the baselines of addrs from the last day of data
+        let mut baselines = Baselines::new();
+        let learn_from = now_millis() - BASELINE_LEARN_MS;
+        try!(
+            segments.for_each_chunk(learn_from, i64::max_value(), |chunk| {
+                baselines.observe(&chunk[datafile::lower_bound(chunk, learn_from)..], options.anomaly_sigma);
+                Ok(())
+            })
+            .map_err(|e| ManagerError::DataFileIO(e))
+        );
+
         // leave the path to the options file here so we can store it
         path.push(options_file_name);
 
         Ok(TargetManager {
             kind: kind,
             index: RwLock::new(index),
-            data_file: RwLock::new(data_file),
+            data: RwLock::new(segments),
+            rollups: rollups,
+            raw_file: RwLock::new(raw_file),
+            raw_path: raw_path,
+            raw_pruned: Mutex::new(0),
             options_path: Mutex::new(path),
             options: RwLock::new(options),
+            history: RwLock::new(history),
+            incidents: Mutex::new(incidents),
+            baselines: Mutex::new(baselines),
+            maintenance: RwLock::new(maintenance),
+            health: Health::new(),
         })
     }
 
@@ -245,31 +283,98 @@ impl TargetManager {
     }
 
     /**
-     * Attempts to update this target's options with the given new options.
+     * Attempts to update this target's options with the given new options,
+     * recording them in the options history as put in place by the given
+     * source (client IP or user).
      */
-    pub fn options_update(&self, new_options: TargetOptions) -> Result<(), ManagerError> {
+    pub fn options_update(&self, new_options: TargetOptions, source: &str) -> Result<(), ManagerError> {
         let mut guard = self.options.write().unwrap();
         let mut options_path = self.options_path.lock().unwrap();
+        // index new addrs first, so the saved options never refer to unknown ones
+        try!(
+            self.index.write().unwrap().ensure_for_addrs(new_options.addrs.iter())
+            .map_err(|e| ManagerError::IndexFileIO(e))
+        );
         *guard = new_options;
         try!(
             overwrite_json(&*guard, &*options_path)
             .map_err(|e| ManagerError::OptionsFileIO(e))
         );
-        try!(self.index.write().unwrap().ensure_for_addrs(guard.addrs.iter()));
-        println!("Updated {} options: {:?}", self.kind.compact_name(), *guard);
+        try!(
+            self.history.write().unwrap().record(&*guard, source)
+            .map_err(|e| ManagerError::HistoryFileIO(e))
+        );
+        println!("Updated {} options (by {}): {:?}", self.kind.compact_name(), source, *guard);
         Ok(())
     }
 
     /**
-     * Acquires a read lock on this target's data file.
+     * Retrieves all versions of this target's options, oldest first.
+     */
+    pub fn options_history(&self) -> Vec<OptionsVersion> {
+        self.history.read().unwrap().versions().to_vec()
+    }
+
+    /**
+     * Retrieves the version of this target's options with the given nonce
+     * (the latest, should the nonce have wrapped around), if any.
      */
-    pub fn data_file_read<'a>(&'a self) -> RwLockReadGuard<'a, File> {
-        self.data_file.read().unwrap()
+    pub fn options_version(&self, nonce: i32) -> Option<OptionsVersion> {
+        self.history.read().unwrap().find(nonce).cloned()
+    }
+
+    /**
+     * Describes the entries of this target's address index.
+     */
+    pub fn index_describe(&self) -> Vec<AddrInfo> {
+        let options = self.options_read();
+        self.index.read().unwrap().describe(&options.addrs)
+    }
+
+    /**
+     * Retrieves the address of the given index (as it is now in this
+     * target's address index), if it is still kept.
+     */
+    pub fn addr_of(&self, index: i32) -> Option<String> {
+        self.index.read().unwrap().addr_of(index).map(|a| a.to_owned())
+    }
+
+    /**
+     * Applies the given change (aliasing, naming, retiring or renaming) to the
+     * entry of this target's address index that its address resolves to.
+     */
+    pub fn index_change(&self, change: &AddrChange) -> Result<(), ManagerError> {
+        // lock in the same order as options_update() does
+        let options = self.options_read();
+        let mut index = self.index.write().unwrap();
+        try!(
+            index.apply(change, &options.addrs, now_millis())
+            .map_err(|e| match e {
+                IndexError::Invalid(msg) => ManagerError::InvalidChange(msg),
+                IndexError::FileIO(e) => ManagerError::IndexFileIO(e),
+            })
+        );
+        println!("Changed {} index: {:?}", self.kind.compact_name(), change);
+        Ok(())
+    }
+
+    /**
+     * Retrieves the health warnings tracker for this target.
+     */
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    /**
+     * Acquires a read lock on this target's data segments.
+     */
+    pub fn segments_read<'a>(&'a self) -> RwLockReadGuard<'a, Segments> {
+        self.data.read().unwrap()
     }
 
     /**
      * Appends the given live-collected data (`TargetResults`) to this target's
-     * data file.
+     * data segments.
      */
     pub fn append_data(&self, data_res: &TargetResults) -> Result<(), ManagerError> {
         let ref in_data = data_res.0;
@@ -282,19 +387,335 @@ impl TargetManager {
             return Ok(());
         }
 
-        let mut out_data: Vec<i32> = Vec::with_capacity((in_data.len() - 3) * 3);
-        let time = in_data[2];
+        let mut out_data: Vec<DataRecord> = Vec::with_capacity(in_data.len() - 3);
+        let time_ms = data_res.1;
+        let anomaly_sigma;
+        {
+            // lock in the same order as options_update() does
+            let options = self.options_read();
+            anomaly_sigma = options.anomaly_sigma;
+            let index = self.index.read().unwrap();
+
+            let samples: Vec<(&str, i32)> = options.addrs.iter().map(|a| &a[..])
+                .zip(in_data[3..].iter().cloned()).collect();
+            try!(
+                self.incidents.lock().unwrap()
+                .observe(time_ms / 1000, &samples, &options.addrs, options.latency_threshold)
+                .map_err(|e| ManagerError::IncidentFileIO(e))
+            );
+
+            for (i, (addr, val)) in options.addrs.iter().zip(in_data[3..].iter()).enumerate() {
+                /*
+                 * addrs not sampled this round (e.g. only degraded ones are
+                 * sampled at the fast interval) don't get a datapoint at all
+                 */
+                if *val == SENTINEL_NODATA {
+                    continue;
+                }
+                let aux = data_res.2.get(i).cloned().unwrap_or(SENTINEL_NODATA);
+                out_data.push(DataRecord::with_all(time_ms, index.get_index(addr), 0, *val, aux));
+            }
+        }
+
+        let ref mut segments = *self.data.write().unwrap();
+
+        /*
+         * should the clock have gone backwards (e.g. stepped by NTP), appending
+         * would break the time order reads rely on, so set those records aside
+         */
+        let (in_order, late): (Vec<DataRecord>, Vec<DataRecord>) =
+            out_data.into_iter().partition(|r| segments.accepts(r.time_ms()));
+        if late.is_empty() {
+            self.health.clear(self.kind.compact_name(), "");
+        } else {
+            try!(segments.set_aside(&late).map_err(|e| ManagerError::DataFileIO(e)));
+            self.health.raise(self.kind.compact_name(), "", format!(
+                "Clock went backwards: set aside {} record(s) timestamped before already stored data.",
+                late.len()));
+        }
+        try!(segments.append(&in_order).map_err(|e| ManagerError::DataFileIO(e)));
+
+        /*
+         * roll up the new data while still holding the segments' lock, so
+         * that a concurrent rebuild of the rollups can't count it twice
+         */
+        for tier in self.rollups.iter() {
+            try!(tier.observe(&in_order).map_err(|e| ManagerError::RollupFileIO(e)));
+        }
+        try!(
+            self.index.write().unwrap().observe(&in_order)
+            .map_err(|e| ManagerError::IndexFileIO(e))
+        );
+        self.baselines.lock().unwrap().observe(&in_order, anomaly_sigma);
+        Ok(())
+    }
+
+    /**
+     * Retrieves the latest anomaly events of this target (values far off
+     * their address's baseline), oldest first.
+     */
+    pub fn anomalies(&self) -> Vec<AnomalyEvent> {
+        let mut events = self.baselines.lock().unwrap().events();
         let index = self.index.read().unwrap();
-        for (addr, val) in self.options_read().addrs.iter().zip(in_data[3..].iter()) {
-            out_data.push(time);
-            out_data.push(index.get_index(addr));
-            out_data.push(*val);
+        for e in events.iter_mut() {
+            e.addr = index.addr_of(e.index).map(|a| a.to_owned());
         }
+        events
+    }
 
-        let ref mut file = *self.data_file.write().unwrap();
-        try!(file.write_all(&out_data.into_raw_bytes())
-             .map_err(|_| ManagerError::DataFileIO(
-                          SPIOError::Write(None))));
+    /**
+     * How many standard deviations off its baseline the last value collected
+     * for the given address was (if it was a success measured against a
+     * learned baseline).
+     */
+    pub fn deviation_of(&self, addr: &str) -> Option<f64> {
+        let index = match self.index.read().unwrap().lookup(addr) {
+            Some(i) => i,
+            None => return None,
+        };
+        self.baselines.lock().unwrap().deviation(index)
+    }
+
+    /**
+     * Retrieves the incidents in this target's incident log, oldest first.
+     */
+    pub fn incidents(&self) -> Vec<Incident> {
+        self.incidents.lock().unwrap().incidents().to_vec()
+    }
+
+    /**
+     * Retrieves this target's maintenance windows.
+     */
+    pub fn maintenance_windows(&self) -> Vec<MaintenanceWindow> {
+        self.maintenance.read().unwrap().windows()
+    }
+
+    /**
+     * Adds the given maintenance window to this target, returning the id
+     * assigned to it.
+     */
+    pub fn maintenance_add(&self, window: MaintenanceWindow) -> Result<u32, ManagerError> {
+        let cron = try!(maintenance::check(&window).map_err(|msg| ManagerError::InvalidChange(msg)));
+        self.maintenance.write().unwrap().add(window, cron)
+            .map_err(|e| ManagerError::MaintenanceFileIO(e))
+    }
+
+    /**
+     * Removes the maintenance window with the given id from this target,
+     * returning whether there was one.
+     */
+    pub fn maintenance_remove(&self, id: u32) -> Result<bool, ManagerError> {
+        self.maintenance.write().unwrap().remove(id)
+            .map_err(|e| ManagerError::MaintenanceFileIO(e))
+    }
+
+    /**
+     * Whether the given address of this target is in maintenance at the given
+     * time (in seconds from epoch).
+     */
+    pub fn in_maintenance(&self, addr: &str, time: i64) -> bool {
+        let index = self.index.read().unwrap();
+        self.maintenance.read().unwrap().covers(&index, addr, time)
+    }
+
+    /**
+     * Finds when the given address of this target is in maintenance between
+     * the given times (see `Maintenance::intervals()`).
+     */
+    pub fn maintenance_intervals(&self, addr: &str, from: i64, to: i64) -> Vec<(i64, i64)> {
+        let index = self.index.read().unwrap();
+        self.maintenance.read().unwrap().intervals(&index, addr, from, to)
+    }
+
+    /**
+     * Retrieves this target's rollup tiers, finest first.
+     */
+    pub fn rollups(&self) -> &[RollupTier] {
+        &self.rollups
+    }
+
+    /**
+     * Rebuilds all of this target's rollup tiers from its data segments. This
+     * reads every segment, so is only done offline (see `main`), while nothing
+     * is being appended.
+     */
+    pub fn rebuild_rollups(&self) -> Result<(), ManagerError> {
+        let segments = self.segments_read();
+
+        let mut total = 0;
+        for tier in self.rollups.iter() {
+            try!(tier.rebuild(&[]).map_err(|e| ManagerError::RollupFileIO(e)));
+        }
+        try!(
+            segments.for_each_chunk(i64::min_value(), i64::max_value(), |chunk| {
+                for tier in self.rollups.iter() {
+                    try!(tier.observe(chunk));
+                }
+                total += chunk.len();
+                Ok(())
+            })
+            .map_err(|e| ManagerError::RollupFileIO(e))
+        );
+
+        println!("Rebuilt {} rollups from {} records.", self.kind.compact_name(), total);
+        Ok(())
+    }
+
+    /**
+     * Checks this target's data segments for problems, returning a
+     * description of each one found (see `Segments::fsck()`).
+     */
+    pub fn fsck(&self) -> Vec<String> {
+        self.segments_read().fsck()
+    }
+
+    /**
+     * Converts this target's sealed data segments to the compressed block
+     * format. Returns the number of records converted.
+     */
+    pub fn compress(&self) -> Result<usize, ManagerError> {
+        let converted = try!(
+            self.data.write().unwrap().compress_sealed()
+            .map_err(|e| ManagerError::DataFileIO(e))
+        );
+        println!("Compressed {} records of {} data.", converted, self.kind.compact_name());
+        Ok(converted)
+    }
+
+    /**
+     * Acquires a read lock on this target's raw samples file.
+     */
+    pub fn raw_file_read<'a>(&'a self) -> RwLockReadGuard<'a, File> {
+        self.raw_file.read().unwrap()
+    }
+
+    /**
+     * Appends the given raw (per-attempt) samples, as (time in millis from
+     * epoch, value) pairs, of the given address to this target's raw samples
+     * file. Does nothing unless raw samples are enabled in options (i.e.
+     * `raw_retention` is set).
+     */
+    pub fn append_raw(&self, addr: &str, samples: &[(i64, i32)]) -> Result<(), ManagerError> {
+        let retention = match self.options_read().raw_retention {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+
+        let index = match self.index.read().unwrap().lookup(addr) {
+            Some(i) => i,
+            // the addr was swapped out of options under us, drop its samples
+            None => return Ok(()),
+        };
+
+        let mut out_data: Vec<i32> = Vec::with_capacity(samples.len() * 4);
+        for &(time_ms, val) in samples {
+            out_data.push((time_ms / 1000) as i32);
+            out_data.push((time_ms % 1000) as i32);
+            out_data.push(index);
+            out_data.push(val);
+        }
+
+        {
+            let ref mut file = *self.raw_file.write().unwrap();
+            try!(file.write_all(&out_data.into_raw_bytes())
+                 .map_err(|_| ManagerError::RawFileIO(
+                              SPIOError::Write(Some(self.raw_path.clone())))));
+        }
+
+        // every so often, drop raw samples older than the retention period
+        let now = Local::now().timestamp();
+        let due = {
+            let mut pruned = self.raw_pruned.lock().unwrap();
+            if now - *pruned >= RAW_PRUNE_PERIOD {
+                *pruned = now;
+                true
+            } else {
+                false
+            }
+        };
+        if due {
+            try!(self.prune_raw((now - retention as i64) as i32));
+        }
+        Ok(())
+    }
+
+    /**
+     * Drops all raw samples taken before the given cutoff time (in seconds
+     * from epoch) by compacting the raw samples file.
+     */
+    fn prune_raw(&self, cutoff: i32) -> Result<(), ManagerError> {
+        /*
+         * find the first sample at or after the cutoff. Each address's
+         * subthread appends its attempts once it is done, so samples of
+         * different addresses interleave out of time order, and the file
+         * can't be searched by time. Older samples past this one are kept
+         * until the next prune.
+         */
+        let cut = {
+            let guard = self.raw_file_read();
+            let len = try!(guard.metadata().map_err(|_| ManagerError::RawFileIO(
+                SPIOError::Metadata(Some(self.raw_path.clone()))))).len();
+            if len == 0 {
+                return Ok(());
+            }
+
+            let map = try!(
+                Mmap::open(&*guard, Protection::Read)
+                .map_err(|_| ManagerError::RawFileIO(SPIOError::Read(Some(self.raw_path.clone()))))
+            );
+            let data: &[RawElement] = match raw_bytes_as_slice(unsafe { map.as_slice() }) {
+                Some(d) => d,
+                None => return Err(ManagerError::RawFileIO(SPIOError::Parse(Some(self.raw_path.clone())))),
+            };
+            let begin = data.iter().position(|d| d.time() >= cutoff).unwrap_or(data.len());
+            (begin * mem::size_of::<RawElement>()) as u64
+        };
+
+        if cut == 0 {
+            return Ok(());
+        }
+        datafile::compact_front(&self.raw_file, &self.raw_path, 0, cut)
+            .map_err(|e| ManagerError::RawFileIO(e))
+    }
+
+    /**
+     * Drops the oldest segments of this target's data as necessary to satisfy
+     * the given retention policy, along with the rollup buckets starting, and
+     * the index entries of addresses retired, before the oldest data that
+     * remains. Indices are never reused,
+     * so the index stays consistent with the data that remains.
+     */
+    pub fn compact(&self, policy: &RetentionPolicy) -> Result<(), ManagerError> {
+        let (dropped, oldest_ms) = {
+            let mut segments = self.data.write().unwrap();
+            let dropped = try!(
+                segments.drop_expired(policy, now_millis())
+                .map_err(|e| ManagerError::DataFileIO(e))
+            );
+            (dropped, segments.list().first().map_or(i64::max_value(), |s| s.start))
+        };
+        if dropped > 0 {
+            println!("Dropped {} expired {} data segment(s).", dropped, self.kind.compact_name());
+        }
+
+        // trim the rollups too, so that they refer to no index forgotten below
+        for tier in self.rollups.iter() {
+            try!(tier.drop_before(oldest_ms).map_err(|e| ManagerError::RollupFileIO(e)));
+        }
+
+        let forgotten = try!(
+            self.index.write().unwrap().drop_retired_before(oldest_ms)
+            .map_err(|e| ManagerError::IndexFileIO(e))
+        );
+
+        // also take the chance to write out the last seen times of addrs
+        try!(
+            self.index.write().unwrap().flush_seen()
+            .map_err(|e| ManagerError::IndexFileIO(e))
+        );
+        if forgotten > 0 {
+            println!("Dropped {} retired {} address(es) from the index.", forgotten, self.kind.compact_name());
+        }
         Ok(())
     }
 
@@ -307,25 +728,74 @@ impl TargetManager {
      */
     pub fn get_current_indices(&self) -> (i32, Vec<i32>, Vec<i32>) {
         let options = self.options_read();
+        let (ordered_list, membership) = self.indices_for(&options.addrs);
+        (options.nonce, ordered_list, membership)
+    }
 
-        let index = self.index.read().unwrap();
-
-        let mut ordered_list = Vec::with_capacity(options.addrs.len());
-
-        let mut membership = {
-            let len = index.len();
-            let mut v = Vec::with_capacity(len);
-            v.extend(iter::repeat(0).take(len));
-            v
+    /**
+     * Like `get_current_indices()`, but for the addrs of the options with the
+     * given nonce, which may be that of any version in the options history.
+     * Returns `None` if there is no such version.
+     */
+    pub fn get_indices_for_nonce(&self, nonce: i32) -> Option<(Vec<i32>, Vec<i32>)> {
+        let addrs = {
+            let options = self.options_read();
+            if options.nonce == nonce {
+                return Some(self.indices_for(&options.addrs));
+            }
+            match self.options_version(nonce) {
+                Some(v) => v.options.addrs,
+                None => return None,
+            }
         };
+        Some(self.indices_for(&addrs))
+    }
 
-        for addr in options.addrs.iter() {
-            let i = index.get_index(addr);
-            ordered_list.push(i);
-            membership[i as usize] = SENTINEL_NODATA;
-        }
+    /**
+     * Like `get_current_indices()`, but for the given addrs (or aliases),
+     * whether in the current options or not.
+     */
+    pub fn indices_for(&self, addrs: &[String]) -> (Vec<i32>, Vec<i32>) {
+        let index = self.index.read().unwrap();
+        let found = addrs.iter().map(|a| index.lookup(a)).collect();
+        ordered_membership(found, index.len())
+    }
 
-        (options.nonce, ordered_list, membership)
+    /**
+     * Like `get_current_indices()`, but for the given indices directly.
+     */
+    pub fn indices_of(&self, indices: &[i32]) -> (Vec<i32>, Vec<i32>) {
+        let len = self.index.read().unwrap().len();
+        let found = indices.iter().map(|&i| if i >= 0 && (i as usize) < len { Some(i) } else { None }).collect();
+        ordered_membership(found, len)
     }
 }
 
+/**
+ * Makes the (ordered_list, membership) pair (see `get_current_indices()`) for
+ * the given indices of an index of the given length, `None` standing for an
+ * addr not in the index.
+ */
+fn ordered_membership(found: Vec<Option<i32>>, len: usize) -> (Vec<i32>, Vec<i32>) {
+    let mut ordered_list = Vec::with_capacity(found.len());
+
+    /*
+     * one more than there are indices, the extra one (which no record has)
+     * standing in for addrs not in the index, so that they always come out as
+     * nodata
+     */
+    let missing = len as i32;
+    let mut membership = {
+        let mut v = Vec::with_capacity(len + 1);
+        v.extend(iter::repeat(0).take(len + 1));
+        v
+    };
+
+    for i in found {
+        let i = i.unwrap_or(missing);
+        ordered_list.push(i);
+        membership[i as usize] = SENTINEL_NODATA;
+    }
+
+    (ordered_list, membership)
+}
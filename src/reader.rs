@@ -8,10 +8,8 @@
 
 /*!
  * Structs and traits for reading back persistent data via `mmap` of an
- * associated target's data file.
+ * associated target's data segments (or rollups) and raw samples file.
  */
-use std::mem;
-use std::slice;
 use std::io;
 use std::io::{Write, BufWriter};
 use std::sync::Arc;
@@ -19,31 +17,84 @@ use std::sync::Arc;
 use memmap::{Mmap, Protection};
 use iron::response::{WriteBody};
 
-use helpers::VecIntoRawBytes;
-use persist::TargetManager;
+use helpers::{SPIOError, VecIntoRawBytes, raw_bytes_as_slice};
+use persist::{TargetManager, RawElement};
+use datafile;
+use datafile::Record;
+use downsample::{Bucketer, Aggregate};
 use options::SENTINEL_NODATA;
 
 /**
  * A request from the client for persistent data for a target in the time range
- * `lower` to `upper` in context of the target's current options, verified
- * with `nonce`.
+ * `lower` to `upper` in context of the target's options with `nonce` (the
+ * current options, or any earlier version in the options history). Instead
+ * of the addrs of those options, the values may be for an explicit list of
+ * `addrs` (or aliases) or `indices`, including retired ones.
+ *
+ * If given, `resolution` is the spacing (in seconds) between data points the
+ * client is content with, allowing a rollup tier to be read instead of every
+ * record. Alternatively, if `max_points` is given, the data is downsampled
+ * into at most that many buckets, combined with the given `aggregate` (see
+ * `downsample::Aggregate`, "avg" if not given).
+ *
+ * If `aux` is set, the records' secondary values (e.g. the round-trip delay
+ * of ntp) are read in place of their values.
  */
 #[derive(RustcEncodable, RustcDecodable, Debug)]
 pub struct DataRequest {
     nonce: i32,
     lower: i32,
     upper: i32,
+    resolution: Option<i32>,
+    addrs: Option<Vec<String>>,
+    indices: Option<Vec<i32>>,
+    max_points: Option<u32>,
+    aggregate: Option<String>,
+    aux: Option<bool>,
 }
 
 /**
- * Representation of data elements on-disk in a target's data file. They are
- * 32-bit back-to-back integers.
+ * Which addresses a request (for data, or statistics) is for, in order.
  */
-#[repr(C, packed)]
-struct DataElement {
-    time: i32,
-    index: i32,
-    val: i32,
+pub enum Selection {
+    Nonce(i32),
+    Addrs(Vec<String>),
+    Indices(Vec<i32>),
+}
+
+impl Selection {
+    /**
+     * Works out the selection of a request with the given nonce and optional
+     * explicit list of addrs or indices (at most one of them). Without an
+     * explicit list, the nonce must be that of some version of the options.
+     */
+    pub fn new(tm: &TargetManager, nonce: i32, addrs: Option<Vec<String>>,
+               indices: Option<Vec<i32>>) -> Option<Selection> {
+        match (addrs, indices) {
+            (Some(addrs), None) => Some(Selection::Addrs(addrs)),
+            (None, Some(indices)) => Some(Selection::Indices(indices)),
+            (Some(_), Some(_)) => None,
+            (None, None) => {
+                if nonce != tm.options_read().nonce && tm.options_version(nonce).is_none() {
+                    return None;
+                }
+                Some(Selection::Nonce(nonce))
+            },
+        }
+    }
+
+    /**
+     * Acquires the (ordered_list, membership) of the selected addrs (see
+     * `TargetManager::get_current_indices()`) from the given manager, or
+     * `None` if the selected nonce is no longer known.
+     */
+    pub fn indices(&self, tm: &TargetManager) -> Option<(Vec<i32>, Vec<i32>)> {
+        match *self {
+            Selection::Nonce(nonce) => tm.get_indices_for_nonce(nonce),
+            Selection::Addrs(ref addrs) => Some(tm.indices_for(addrs)),
+            Selection::Indices(ref indices) => Some(tm.indices_of(indices)),
+        }
+    }
 }
 
 /**
@@ -51,165 +102,406 @@ struct DataElement {
  * data of a target.
  */
 pub struct SPDataReader {
+    selection: Selection,
     lower: i32,
     upper: i32,
+    resolution: Option<i32>,
+    downsample: Option<(u32, Aggregate)>,  // max points and aggregate
+    aux: bool,  // whether to read secondary values instead of values
     tm: Arc<TargetManager>,
 }
 
 impl SPDataReader {
     pub fn new(tm: Arc<TargetManager>, dr: DataRequest) -> Option<Self> {
-        // verify the nonce, and refuse to create a reader if it doesn't match
-        if dr.nonce != tm.options_read().nonce {
-            return None;
-        }
+        // verify what is requested, and refuse to create a reader if it's amiss
+        let selection = match Selection::new(&tm, dr.nonce, dr.addrs, dr.indices) {
+            Some(s) => s,
+            None => return None,
+        };
+
+        // refuse to create a reader for an unknown aggregate
+        let downsample = match (dr.max_points, dr.aggregate) {
+            (Some(max_points), aggregate) if max_points > 0 => {
+                match Aggregate::parse(aggregate.as_ref().map_or("avg", |a| &a[..])) {
+                    Some(a) => Some((max_points, a)),
+                    None => return None,
+                }
+            },
+            (Some(_), _) => return None,
+            (None, _) => None,
+        };
 
         Some(SPDataReader{
+            selection: selection,
             lower: dr.lower,
             upper: dr.upper,
+            resolution: dr.resolution,
+            downsample: downsample,
+            aux: dr.aux.unwrap_or(false),
             tm: tm,
         })
     }
 }
 
-impl WriteBody for SPDataReader {
-    /**
-     * Writes the body of the response with the requested persistent data.
+/**
+ * A record with its secondary value in place of its value.
+ */
+#[derive(Clone, Copy)]
+struct AuxOf<R: Record>(R);
+
+impl<R: Record> Record for AuxOf<R> {
+    fn time_ms(&self) -> i64 { self.0.time_ms() }
+    fn index(&self) -> i32 { self.0.index() }
+    fn val(&self) -> i32 { self.0.aux() }
+}
+
+/**
+ * Writes out the records (of any data file version) in the time range `lower`
+ * to `upper` (in seconds from epoch) as back-to-back [time, value1, value2,
+ * ...] arrays of 32-bit little-endian integers, with values (or secondary
+ * values, if `aux`) in the order given by `ordered_list`.
+ */
+fn write_records<R: Record>(data: &[R], lower: i32, upper: i32, aux: bool,
+                            ordered_list: &[i32], membership: &mut Vec<i32>,
+                            res: &mut io::Write) -> io::Result<()> {
+    let lower_ms = lower as i64 * 1000;
+    // the upper bound is inclusive of the entire second
+    let upper_ms = upper as i64 * 1000 + 999;
+
+    // search for the requested start/lower/begin time of the data
+    let begin = match data.binary_search_by_key(&lower_ms, |d| d.time_ms()) {
+        Ok(mut i) => {
+            /*
+             * we may end up in the middle of a series of data points taken
+             * at the same time; we seek to the first
+             */
+            while i > 0 && data[i - 1].time_ms() == lower_ms {
+                i -= 1;
+            }
+            i
+        },
+        Err(i) => i
+    };
+
+    // search for the requested end/upper time of the data
+    let end = match data.binary_search_by_key(&upper_ms, |d| d.time_ms()) {
+        Ok(mut i) => {
+            /*
+             * we may end up in the middle of a series of data points taken
+             * at the same time; we seek to the last
+             */
+            while i < data.len() && data[i].time_ms() == upper_ms {
+                i += 1;
+            }
+            i
+        },
+        Err(i) => i
+    };
+
+    /*
+     * if our search reveals that we need to start past the data we have,
+     * then we don't have that data
      */
-    fn write_body(&mut self, res: &mut io::Write) -> io::Result<()> {
-        /*
-         * acquire nonce and current indices (current state of addrs for this
-         * target) from the TargetManager
-         */
-        let (nonce, ordered_list, mut membership) = self.tm.get_current_indices();
+    if begin >= data.len() || begin >= end {
+        return Ok(())
+    }
 
-        // verify that the request nonce and the manager's nonce match
-        if nonce != self.tm.options_read().nonce {
-            println!("Nonce mismatch in WriteBody for SPDatReader!");
-            return Ok(())
-        }
+    // initialize a buffered writer to actually write the response body
+    let mut writer = BufWriter::new(res);
 
-        // get a lock on the target's data file
-        let guard = self.tm.data_file_read();
+    /*
+     * we process the data in time-based segments (of whole seconds, the
+     * resolution of our network transfer format), initialize a buffer of the
+     * appropriate size to store that data until we write it
+     */
+    let mut buf: Vec<i32> = Vec::with_capacity(1 + ordered_list.len());
+    let mut cur = (data[begin].time_ms() / 1000) as i32;
 
-        // attempt to mmap the target's data file
-        let map = try!(
-            Mmap::open(&*guard, Protection::Read)
-            .map_err(|e| {
-                println!("ERROR: Mmap failed!");
-                e
-            })
-        );
+    // loop through all the data points we have between begin and end
+    for d in &data[begin..end] {
+        let time = (d.time_ms() / 1000) as i32;
 
         /*
-         * attempt to read the raw bytes of the mapped data file as a series of
-         * DataElements (three 32-bit integers back-to-back)
+         * if we encounter a different time, process one complete time
+         * segment and write it
          */
-        let data: &[DataElement] = unsafe {
-            let orig = map.as_slice();
-            let raw_ptr = orig.as_ptr();
-
-            let orig_len = orig.len();
-            if orig_len % mem::size_of::<DataElement>() != 0 {
-                println!("ERROR: data file not a multiple 3 * 4 bytes!");
-                return Err(io::Error::new(io::ErrorKind::Other, "Data file incorrect multiple!"));
+        if cur != time {
+            // first element is time
+            buf.push(cur);
+
+            /*
+             * followed by data values in-order in which they appear in the
+             * target's current addrs (here tracked by the ordered_list
+             * of indices obtained from manager)
+             */
+            for &i in ordered_list.iter() {
+                buf.push(membership[i as usize]);
+                membership[i as usize] = SENTINEL_NODATA;
             }
-            let new_len = orig.len() / mem::size_of::<DataElement>();
 
-            mem::forget(orig);
-            slice::from_raw_parts(raw_ptr as *const DataElement, new_len)
-        };
+            // write out the data and reset our buffer and time tracker
+            try!(writer.write_all(&buf.into_raw_bytes()));
+            buf = Vec::with_capacity(1 + ordered_list.len());
+            cur = time;
+        }
 
-        // search for the requested start/lower/begin time of the data
-        let begin = match data.binary_search_by_key(&self.lower, |d| d.time) {
-            Ok(mut i) => {
-                /*
-                 * we may end up in the middle of a series of data points taken
-                 * at the same time; we seek to the first
-                 */
-                while i > 0 && data[i].time == self.lower {
-                    i -= 1;
-                }
-                i
+        /*
+         * if this data point is relevant to us, meaning the addr
+         * represented by its index is in the target's current addrs (here
+         * tracked by membership), then we store it (cheatingly in
+         * membership indexed by its index -- this way we don't need to
+         * allocate another buffer to store it)
+         */
+        let index = d.index() as usize;
+        if index < membership.len() && membership[index] != 0 {
+            membership[index] = if aux { d.aux() } else { d.val() };
+        }
+    }
+
+    // process the final time segment, and flush our writer
+    buf.push(cur);
+    for &i in ordered_list.iter() {
+        buf.push(membership[i as usize]);
+        membership[i as usize] = SENTINEL_NODATA;
+    }
+    try!(writer.write_all(&buf.into_raw_bytes()));
+    try!(writer.flush());
+
+    Ok(())
+}
+
+impl WriteBody for SPDataReader {
+    /**
+     * Writes the body of the response with the requested persistent data.
+     */
+    fn write_body(&mut self, res: &mut io::Write) -> io::Result<()> {
+        /*
+         * acquire the indices of the requested addrs (or of the addrs of the
+         * options with the requested nonce) from the TargetManager
+         */
+        let (ordered_list, mut membership) = match self.selection.indices(&self.tm) {
+            Some(indices) => indices,
+            None => {
+                println!("Nonce not found in WriteBody for SPDataReader!");
+                return Ok(())
             },
-            Err(i) => i
         };
 
-        // search for the requested end/upper time of the data
-        let end = match data.binary_search_by_key(&self.upper, |d| d.time) {
-            Ok(mut i) => {
-                /*
-                 * we may end up in the middle of a series of data points taken
-                 * at the same time; we seek to the last
-                 */
-                while i < data.len() && data[i].time == self.upper {
-                    i += 1;
-                }
-                i
-            },
-            Err(i) => i
+        // downsample into buckets if requested, in the bucketed format
+        if let Some((max_points, aggregate)) = self.downsample {
+            let mut bucketer = Bucketer::new(self.lower as i64 * 1000, self.upper as i64 * 1000 + 999,
+                                             max_points, aggregate, &ordered_list);
+            return self.write_downsampled(&mut bucketer, res);
+        }
+
+        /*
+         * read the coarsest rollup tier that still satisfies the requested
+         * resolution (if there is one), as averages over its buckets
+         */
+        let resolution = self.resolution.unwrap_or(0) as i64;
+        let tier = if self.aux {
+            None
+        } else {
+            self.tm.rollups().iter().rev().find(|t| t.width <= resolution)
         };
+        if let Some(tier) = tier {
+            let (lower, upper) = (self.lower, self.upper);
+            return tier.with_records(|records, open| {
+                try!(write_records(records, lower, upper, false, &ordered_list, &mut membership, res));
+                write_records(open, lower, upper, false, &ordered_list, &mut membership, res)
+            });
+        }
 
         /*
-         * if our search reveals that we need to start past the data we have,
-         * then we don't have that data
+         * get a lock on the target's data segments, and write out the records
+         * in the range from each that overlaps it
          */
-        if begin >= data.len() {
-            return Ok(())
+        let segments = self.tm.segments_read();
+        let (lower, upper, aux) = (self.lower, self.upper, self.aux);
+        let mut write_err = None;
+        let result = segments.for_each_chunk(lower as i64 * 1000, upper as i64 * 1000 + 999, |chunk| {
+            write_records(chunk, lower, upper, aux, &ordered_list, &mut membership, res)
+                .map_err(|e| {
+                    write_err = Some(e);
+                    SPIOError::Write(None)
+                })
+        });
+
+        match (result, write_err) {
+            (_, Some(e)) => Err(e),
+            (Err(e), None) => {
+                println!("ERROR: {}", e);
+                Err(io::Error::new(io::ErrorKind::Other, e.description()))
+            },
+            (Ok(()), None) => Ok(()),
         }
+    }
+}
 
-        // initialize a buffered writer to actually write the response body
+impl SPDataReader {
+    /**
+     * Writes out the requested data downsampled by the given bucketer, read
+     * from the coarsest rollup tier no wider than its buckets if the aggregate
+     * can be computed from rollups, or else from the data segments.
+     */
+    fn write_downsampled(&self, bucketer: &mut Bucketer, res: &mut io::Write) -> io::Result<()> {
+        let lower_ms = self.lower as i64 * 1000;
+        let upper_ms = self.upper as i64 * 1000 + 999;
         let mut writer = BufWriter::new(res);
 
+        let tier = if bucketer.aggregate().from_summaries() && !self.aux {
+            self.tm.rollups().iter().rev().find(|t| t.width * 1000 <= bucketer.width_ms())
+        } else {
+            None
+        };
+
+        if let Some(tier) = tier {
+            // whole rollup buckets, from the one the range starts in
+            bucketer.align_to(tier.width * 1000);
+            let origin_ms = bucketer.origin_ms();
+            try!(tier.with_records(|records, open| {
+                let begin = datafile::lower_bound(records, origin_ms);
+                for r in records[begin..].iter().chain(open.iter())
+                        .filter(|r| r.time_ms() >= origin_ms && r.time_ms() <= upper_ms) {
+                    try!(bucketer.add_summary(r, &mut writer));
+                }
+                Ok(())
+            }));
+        } else {
+            let segments = self.tm.segments_read();
+            let mut write_err = None;
+            let result = segments.for_each_chunk(lower_ms, upper_ms, |chunk| {
+                let begin = datafile::lower_bound(chunk, lower_ms);
+                for r in chunk[begin..].iter().take_while(|r| r.time_ms() <= upper_ms) {
+                    let added = if self.aux {
+                        bucketer.add(&AuxOf(*r), &mut writer)
+                    } else {
+                        bucketer.add(r, &mut writer)
+                    };
+                    if let Err(e) = added {
+                        write_err = Some(e);
+                        return Err(SPIOError::Write(None));
+                    }
+                }
+                Ok(())
+            });
+            match (result, write_err) {
+                (_, Some(e)) => return Err(e),
+                (Err(e), None) => {
+                    println!("ERROR: {}", e);
+                    return Err(io::Error::new(io::ErrorKind::Other, e.description()));
+                },
+                (Ok(()), None) => {},
+            }
+        }
+
+        try!(bucketer.finish(&mut writer));
+        writer.flush()
+    }
+}
+
+/// the widest time range (in seconds) that may be requested of raw samples
+pub static MAX_RAW_WINDOW: i32 = 3600;
+
+/**
+ * A request from the client for the raw (per-attempt) samples of a target in
+ * the (short) time range `lower` to `upper` in context of the target's
+ * current options, verified with `nonce`.
+ */
+#[derive(RustcEncodable, RustcDecodable, Debug)]
+pub struct RawRequest {
+    nonce: i32,
+    lower: i32,
+    upper: i32,
+}
+
+/**
+ * A reader (implemented as an Iron body writer `WriteBody`) for the raw
+ * samples of a target. Like everything else we send, the response is
+ * little-endian.
+ */
+pub struct SPRawReader {
+    lower: i32,
+    upper: i32,
+    tm: Arc<TargetManager>,
+}
+
+impl SPRawReader {
+    pub fn new(tm: Arc<TargetManager>, rr: RawRequest) -> Option<Self> {
         /*
-         * we process the data in time-based segments, initialize a buffer of
-         * the appropriate size to store that data until we write it
+         * verify the nonce and that the time range is sane and short enough,
+         * and refuse to create a reader otherwise
          */
-        let mut buf: Vec<i32> = Vec::with_capacity(1 + ordered_list.len());
-        let mut cur = data[begin].time;
+        if rr.nonce != tm.options_read().nonce || rr.upper < rr.lower ||
+                rr.upper - rr.lower > MAX_RAW_WINDOW {
+            return None;
+        }
 
-        // loop through all the data points we have between begin and end
-        for d in &data[begin..end] {
-            /*
-             * if we encounter a different time, process one complete time
-             * segment and write it
-             */
-            if cur != d.time {
-                // first element is time
-                buf.push(cur);
-
-                /*
-                 * followed by data values in-order in which they appear in the
-                 * target's current addrs (here tracked by the ordered_list
-                 * of indices obtained from manager)
-                 */
-                for &i in ordered_list.iter() {
-                    buf.push(membership[i as usize]);
-                    membership[i as usize] = SENTINEL_NODATA;
-                }
+        Some(SPRawReader {
+            lower: rr.lower,
+            upper: rr.upper,
+            tm: tm,
+        })
+    }
+}
 
-                // write out the data and reset our buffer and time tracker
-                try!(writer.write_all(&buf.into_raw_bytes()));
-                buf = Vec::with_capacity(1 + ordered_list.len());
-                cur = d.time;
-            }
+impl WriteBody for SPRawReader {
+    /**
+     * Writes the body of the response with the requested raw samples, as
+     * back-to-back [time, millis, position, value] 32-bit integers, where
+     * position is that of the sample's address in the target's current addrs.
+     */
+    fn write_body(&mut self, res: &mut io::Write) -> io::Result<()> {
+        let (_, ordered_list, _) = self.tm.get_current_indices();
 
-            /*
-             * if this data point is relevant to us, meaning the addr
-             * represented by its index is in the target's current addrs (here
-             * tracked by membership), then we store it (cheatingly in
-             * membership indexed by its index -- this way we don't need to
-             * allocate another buffer to store it)
-             */
-            if membership[d.index as usize] != 0 {
-                membership[d.index as usize] = d.val;
+        // map each address index to its position in current addrs (or -1)
+        let mut positions: Vec<i32> = Vec::new();
+        for (pos, &i) in ordered_list.iter().enumerate() {
+            if positions.len() <= i as usize {
+                positions.resize(i as usize + 1, -1);
             }
+            positions[i as usize] = pos as i32;
         }
 
-        // process the final time segment, and flush our writer
-        buf.push(cur);
-        for &i in ordered_list.iter() {
-            buf.push(membership[i as usize]);
-            membership[i as usize] = SENTINEL_NODATA;
+        // get a lock on the target's raw samples file and mmap it (if non-empty)
+        let guard = self.tm.raw_file_read();
+        if try!(guard.metadata()).len() == 0 {
+            return Ok(());
+        }
+        let map = try!(
+            Mmap::open(&*guard, Protection::Read)
+            .map_err(|e| {
+                println!("ERROR: Mmap failed!");
+                e
+            })
+        );
+
+        let data: &[RawElement] = match raw_bytes_as_slice(unsafe { map.as_slice() }) {
+            Some(d) => d,
+            None => {
+                println!("ERROR: raw samples file not a multiple 4 * 4 bytes!");
+                return Err(io::Error::new(io::ErrorKind::Other, "Raw samples file incorrect multiple!"));
+            }
+        };
+
+        /*
+         * each address's attempts are appended together once they are done,
+         * so samples are not in time order and the whole file is scanned
+         * (it only reaches back as far as raw_retention)
+         */
+        let mut selected: Vec<&RawElement> = data.iter()
+            .filter(|d| d.time() >= self.lower && d.time() <= self.upper)
+            .filter(|d| positions.get(d.index() as usize).map_or(false, |&p| p >= 0))
+            .collect();
+        selected.sort_by_key(|d| (d.time(), d.millis()));
+
+        let mut writer = BufWriter::new(res);
+        let mut buf: Vec<i32> = Vec::with_capacity(selected.len() * 4);
+        for d in selected {
+            buf.push(d.time());
+            buf.push(d.millis());
+            buf.push(positions[d.index() as usize]);
+            buf.push(d.val());
         }
         try!(writer.write_all(&buf.into_raw_bytes()));
         try!(writer.flush());
@@ -217,5 +509,3 @@ impl WriteBody for SPDataReader {
         Ok(())
     }
 }
-
-
@@ -0,0 +1,389 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Downsampled rollup tiers of a target's data, maintained incrementally as
+ * data is appended so that long time ranges can be read without streaming
+ * every record.
+ *
+ * Each tier has its own file of fixed-length records (after a header like
+ * that of the data file), one per address per bucket of the tier's width,
+ * holding the count, error count, mismatch count, min, max and sum of the
+ * values in that bucket. The bucket currently being filled is kept in memory until the first
+ * value of the next bucket arrives.
+ */
+use std::io;
+use std::mem;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::fs::{OpenOptions, File};
+use std::io::Write;
+use std::sync::{Mutex, RwLock};
+
+use memmap::{Mmap, Protection};
+
+use helpers::{SPIOError, SPFile, raw_bytes_as_slice, slice_as_raw_bytes};
+use helpers::{encode_i32_le, decode_i32_le, encode_i64_le, decode_i64_le};
+use datafile;
+use datafile::{Record, DataRecord};
+use options::{SENTINEL_ERROR, SENTINEL_MISMATCH, is_failure};
+
+pub static ROLLUP_MAGIC: &'static [u8; 8] = b"STABROLL";
+pub static ROLLUP_VERSION: i32 = 2;
+
+/// the (name, width in seconds) of each rollup tier, finest first
+pub static ROLLUP_TIERS: [(&'static str, i64); 3] = [
+    ("1m", 60),
+    ("1h", 3600),
+    ("1d", 86400),
+];
+
+/**
+ * Representation of rollup records on-disk in a rollup tier's file. Like data
+ * records, all fields are little-endian integers kept as bytes.
+ */
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct RollupRecord {
+    time: [u8; 8],  // start of the bucket, in milliseconds from epoch
+    index: [u8; 4],
+    count: [u8; 4],  // number of (successfully collected) values
+    errors: [u8; 4],  // number of failures other than mismatches
+    mismatches: [u8; 4],  // number of mismatch sentinels
+    min: [u8; 4],
+    max: [u8; 4],
+    sum: [u8; 8],
+}
+
+impl RollupRecord {
+    pub fn count(&self) -> i32 { decode_i32_le(self.count) }
+    pub fn errors(&self) -> i32 { decode_i32_le(self.errors) }
+    pub fn mismatches(&self) -> i32 { decode_i32_le(self.mismatches) }
+    pub fn min(&self) -> i32 { decode_i32_le(self.min) }
+    pub fn max(&self) -> i32 { decode_i32_le(self.max) }
+    pub fn sum(&self) -> i64 { decode_i64_le(self.sum) }
+}
+
+/**
+ * Rollup records read like data records whose value is the average of the
+ * bucket, or if nothing but failures were collected, the mismatch sentinel if
+ * there were any mismatches and the error sentinel otherwise.
+ */
+impl Record for RollupRecord {
+    fn time_ms(&self) -> i64 { decode_i64_le(self.time) }
+    fn index(&self) -> i32 { decode_i32_le(self.index) }
+    fn val(&self) -> i32 {
+        let count = self.count();
+        if count > 0 {
+            (self.sum() / count as i64) as i32
+        } else if self.mismatches() > 0 {
+            SENTINEL_MISMATCH
+        } else {
+            SENTINEL_ERROR
+        }
+    }
+}
+
+/**
+ * Running aggregate of the values of one address in the bucket being filled.
+ */
+#[derive(Clone, Copy)]
+struct Accum {
+    count: i32,
+    errors: i32,
+    mismatches: i32,
+    min: i32,
+    max: i32,
+    sum: i64,
+}
+
+impl Accum {
+    fn new() -> Self {
+        Accum { count: 0, errors: 0, mismatches: 0, min: i32::max_value(), max: i32::min_value(), sum: 0 }
+    }
+
+    fn add(&mut self, val: i32) {
+        if val == SENTINEL_MISMATCH {
+            self.mismatches += 1;
+        } else if is_failure(val) {
+            self.errors += 1;
+        } else {
+            self.count += 1;
+            self.min = cmp::min(self.min, val);
+            self.max = cmp::max(self.max, val);
+            self.sum += val as i64;
+        }
+    }
+
+    fn to_record(&self, time_ms: i64, index: i32) -> RollupRecord {
+        let (min, max) = if self.count > 0 {
+            (self.min, self.max)
+        } else {
+            (SENTINEL_ERROR, SENTINEL_ERROR)
+        };
+        RollupRecord {
+            time: encode_i64_le(time_ms),
+            index: encode_i32_le(index),
+            count: encode_i32_le(self.count),
+            errors: encode_i32_le(self.errors),
+            mismatches: encode_i32_le(self.mismatches),
+            min: encode_i32_le(min),
+            max: encode_i32_le(max),
+            sum: encode_i64_le(self.sum),
+        }
+    }
+}
+
+/**
+ * The bucket currently being filled (if any), keyed by address index.
+ */
+struct OpenBucket {
+    start_ms: Option<i64>,
+    accums: BTreeMap<i32, Accum>,
+}
+
+impl OpenBucket {
+    fn records(&self) -> Vec<RollupRecord> {
+        match self.start_ms {
+            Some(start) => self.accums.iter().map(|(&i, a)| a.to_record(start, i)).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/**
+ * Encodes the header of a rollup tier file.
+ */
+fn rollup_header_bytes() -> Vec<u8> {
+    let mut header = Vec::with_capacity(datafile::HEADER_LEN);
+    header.extend_from_slice(ROLLUP_MAGIC);
+    header.extend_from_slice(&encode_i32_le(ROLLUP_VERSION));
+    header.extend_from_slice(&encode_i32_le(mem::size_of::<RollupRecord>() as i32));
+    header
+}
+
+/**
+ * A single rollup tier of a target, with its file and in-memory open bucket.
+ */
+pub struct RollupTier {
+    pub name: &'static str,
+    pub width: i64,  // in seconds
+    file: RwLock<File>,
+    path: PathBuf,
+    open: Mutex<OpenBucket>,
+}
+
+impl RollupTier {
+    /**
+     * Opens (creating if necessary) the rollup tier of the given name and
+     * width for the target of the given compact name. A tier file that is
+     * missing or unreadable is started over, and so should be caught up from
+     * `resume_ms()` (as should any other, as the open bucket was lost).
+     */
+    pub fn open(kind_name: &str, data_path: &Path, name: &'static str,
+                width: i64) -> Result<RollupTier, SPIOError> {
+        let mut path = data_path.to_owned();
+        path.push(format!("{}.rollup.{}.dat", kind_name, name));
+
+        if path.exists() {
+            try!(datafile::repair_torn_tail(&path, datafile::HEADER_LEN, mem::size_of::<RollupRecord>()));
+        }
+        let mut file = try!(File::open_from(OpenOptions::new().read(true).append(true).create(true), &path));
+
+        // (re)start the file if it is new or not one we understand
+        let valid = {
+            let len = try!(file.length_p(&path));
+            if len < datafile::HEADER_LEN as u64 {
+                false
+            } else {
+                let map = try!(
+                    Mmap::open(&file, Protection::Read)
+                    .map_err(|_| SPIOError::Read(Some(path.clone())))
+                );
+                let raw = unsafe { map.as_slice() };
+                &raw[..datafile::HEADER_LEN] == &rollup_header_bytes()[..]
+            }
+        };
+        if !valid {
+            try!(
+                file.set_len(0)
+                .and_then(|_| file.write_all(&rollup_header_bytes()))
+                .map_err(|_| SPIOError::Write(Some(path.clone())))
+            );
+        }
+
+        Ok(RollupTier {
+            name: name,
+            width: width,
+            file: RwLock::new(file),
+            path: path,
+            open: Mutex::new(OpenBucket { start_ms: None, accums: BTreeMap::new() }),
+        })
+    }
+
+    /**
+     * The time (in milliseconds from epoch) from which data has yet to be
+     * taken into account in this tier's file, i.e. the end of its last
+     * written bucket.
+     */
+    pub fn resume_ms(&self) -> Result<i64, SPIOError> {
+        let last = try!(self.with_records(|records, _| Ok(records.last().map(|r| r.time_ms())))
+                        .map_err(|_| SPIOError::Read(Some(self.path.clone()))));
+        Ok(match last {
+            Some(t) => t + self.width * 1000,
+            None => i64::min_value(),
+        })
+    }
+
+    /**
+     * Takes newly appended data records into account, writing out the open
+     * bucket once a record from a later bucket arrives. Records arriving late
+     * (for an earlier bucket) are counted in the open bucket.
+     */
+    pub fn observe(&self, records: &[DataRecord]) -> Result<(), SPIOError> {
+        let width_ms = self.width * 1000;
+        let mut open = self.open.lock().unwrap();
+
+        for r in records {
+            let time_ms = r.time_ms();
+            let bucket = time_ms - ((time_ms % width_ms) + width_ms) % width_ms;
+            match open.start_ms {
+                Some(start) if bucket <= start => {},
+                Some(_) => {
+                    try!(self.flush(&open));
+                    open.start_ms = Some(bucket);
+                    open.accums.clear();
+                },
+                None => open.start_ms = Some(bucket),
+            }
+            open.accums.entry(r.index()).or_insert_with(Accum::new).add(r.val());
+        }
+        Ok(())
+    }
+
+    fn flush(&self, open: &OpenBucket) -> Result<(), SPIOError> {
+        let records = open.records();
+        let ref mut file = *self.file.write().unwrap();
+        file.write_all(slice_as_raw_bytes(&records))
+            .map_err(|_| SPIOError::Write(Some(self.path.clone())))
+    }
+
+    /**
+     * Throws away everything in this tier and rebuilds it from the given
+     * records of the target's data file.
+     */
+    pub fn rebuild(&self, data: &[DataRecord]) -> Result<(), SPIOError> {
+        {
+            let mut open = self.open.lock().unwrap();
+            open.start_ms = None;
+            open.accums.clear();
+
+            let ref mut file = *self.file.write().unwrap();
+            try!(
+                file.set_len(datafile::HEADER_LEN as u64)
+                .map_err(|_| SPIOError::Write(Some(self.path.clone())))
+            );
+        }
+        self.observe(data)
+    }
+
+    /**
+     * Drops the records of buckets starting before the given time (in
+     * milliseconds from epoch) from this tier's file, e.g. once the data they
+     * summarize has been dropped. Returns the number of records dropped.
+     */
+    pub fn drop_before(&self, cut_ms: i64) -> Result<usize, SPIOError> {
+        let dropped = try!(self.with_records(|records, _| Ok(datafile::lower_bound(records, cut_ms)))
+                           .map_err(|_| SPIOError::Read(Some(self.path.clone()))));
+        if dropped > 0 {
+            try!(datafile::compact_front(&self.file, &self.path, datafile::HEADER_LEN,
+                                         (dropped * mem::size_of::<RollupRecord>()) as u64));
+        }
+        Ok(dropped)
+    }
+
+    /**
+     * Calls the given function with the records written out to this tier's
+     * file so far, along with those of the open bucket (which are all later).
+     */
+    pub fn with_records<F, T>(&self, f: F) -> io::Result<T>
+            where F: FnOnce(&[RollupRecord], &[RollupRecord]) -> io::Result<T> {
+        // lock in the same order as observe() does
+        let open = self.open.lock().unwrap();
+        let guard = self.file.read().unwrap();
+
+        let map = try!(Mmap::open(&*guard, Protection::Read));
+        let raw = unsafe { map.as_slice() };
+        match raw_bytes_as_slice::<RollupRecord>(&raw[datafile::HEADER_LEN..]) {
+            Some(records) => f(records, &open.records()),
+            None => Err(io::Error::new(io::ErrorKind::Other, "Rollup file incorrect multiple!")),
+        }
+    }
+}
+
+#[test]
+fn rolls_up_and_resumes() {
+    use helpers::TempDir;
+
+    let dir = TempDir::new("rollup");
+
+    let data = [
+        DataRecord::new(0, 0, 10),
+        DataRecord::new(10_000, 0, 30),
+        DataRecord::new(10_000, 1, SENTINEL_ERROR),
+        DataRecord::new(20_000, 1, SENTINEL_MISMATCH),
+        DataRecord::new(60_000, 0, 5),
+        DataRecord::new(125_000, 0, 7),
+    ];
+
+    let open_from = |data: &[DataRecord]| {
+        let tier = RollupTier::open("tcpping", &dir, "1m", 60).unwrap();
+        let resume_ms = tier.resume_ms().unwrap();
+        tier.observe(&data[datafile::lower_bound(data, resume_ms)..]).unwrap();
+        tier
+    };
+
+    {
+        let tier = open_from(&data[..5]);
+        tier.with_records(|records, open| {
+            assert_eq!(records.len(), 2);
+            assert_eq!((records[0].count(), records[0].min(), records[0].max(), records[0].val()),
+                       (2, 10, 30, 20));
+            assert_eq!((records[1].count(), records[1].errors(), records[1].mismatches(), records[1].val()),
+                       (0, 1, 1, SENTINEL_MISMATCH));
+            assert_eq!(open.len(), 1);
+            assert_eq!(open[0].time_ms(), 60_000);
+            Ok(())
+        }).unwrap();
+    }
+
+    // reopening picks up where the file left off, without double counting
+    let tier = open_from(&data);
+    tier.with_records(|records, open| {
+        assert_eq!(records.len(), 3);
+        assert_eq!((records[2].time_ms(), records[2].val()), (60_000, 5));
+        assert_eq!(open[0].time_ms(), 120_000);
+        Ok(())
+    }).unwrap();
+
+    tier.rebuild(&data).unwrap();
+    tier.with_records(|records, _| {
+        assert_eq!(records.len(), 3);
+        Ok(())
+    }).unwrap();
+
+    // dropping the oldest buckets leaves the tier appendable
+    assert_eq!(tier.drop_before(60_000).unwrap(), 2);
+    tier.observe(&[DataRecord::new(180_000, 0, 1)]).unwrap();
+    tier.with_records(|records, open| {
+        assert_eq!(records.iter().map(|r| r.time_ms()).collect::<Vec<_>>(), vec![60_000, 120_000]);
+        assert_eq!(open[0].time_ms(), 180_000);
+        Ok(())
+    }).unwrap();
+}
@@ -0,0 +1,784 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Time-partitioned storage of a target's data: a series of segment files,
+ * each holding the records of one day or month (UTC), listed in a small JSON
+ * manifest. Only the last segment is appended to; the others are sealed and
+ * may be compressed (see `blockfile`) or deleted whole.
+ */
+use std::fs;
+use std::cmp;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::fs::{OpenOptions, File};
+use std::io::Write;
+
+use chrono::{NaiveDate, NaiveDateTime, Datelike};
+use memmap::{Mmap, Protection};
+
+use helpers::{SPIOError, SPFile, overwrite_json, read_json_or_prev, raw_bytes_as_slice,
+              slice_as_raw_bytes, with_suffix};
+use datafile;
+use datafile::{Record, DataRecord};
+use blockfile;
+use blockfile::CompressedFile;
+use options::{SegmentSpan, RetentionPolicy};
+
+pub static MANIFEST_VERSION: i32 = 1;
+
+impl SegmentSpan {
+    /**
+     * Finds the period (of this span, in UTC) containing the given time,
+     * returning its start and end (exclusive), in milliseconds from epoch,
+     * and its name.
+     */
+    pub fn period_of(&self, time_ms: i64) -> (i64, i64, String) {
+        let secs = if time_ms >= 0 { time_ms / 1000 } else { (time_ms - 999) / 1000 };
+        let date = NaiveDateTime::from_timestamp(secs, 0).date();
+        let (start, end, name) = match *self {
+            SegmentSpan::Day => (date, date.succ(), format!("{}", date.format("%Y-%m-%d"))),
+            SegmentSpan::Month => {
+                let first = NaiveDate::from_ymd(date.year(), date.month(), 1);
+                let next = if date.month() == 12 {
+                    NaiveDate::from_ymd(date.year() + 1, 1, 1)
+                } else {
+                    NaiveDate::from_ymd(date.year(), date.month() + 1, 1)
+                };
+                (first, next, format!("{}", first.format("%Y-%m")))
+            },
+        };
+        (start.and_hms(0, 0, 0).timestamp() * 1000, end.and_hms(0, 0, 0).timestamp() * 1000, name)
+    }
+}
+
+/**
+ * A segment's entry in the manifest.
+ */
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone)]
+pub struct SegmentInfo {
+    pub file: String,  // file name, within the data directory
+    pub start: i64,  // milliseconds from epoch, inclusive
+    pub end: i64,  // milliseconds from epoch, exclusive
+    pub compressed: bool,
+}
+
+#[derive(RustcEncodable, RustcDecodable, Debug)]
+struct Manifest {
+    version: i32,
+    segments: Vec<SegmentInfo>,  // in time order
+}
+
+/**
+ * The segments of a target's data, with the last one open for appending.
+ */
+pub struct Segments {
+    kind_name: &'static str,
+    dir: PathBuf,
+    span: SegmentSpan,
+    manifest_path: PathBuf,
+    manifest: Manifest,
+    active: Option<File>,  // the last segment, unless there is none or it is compressed
+    floor_ms: i64,  // the earliest time a record may be appended with (that of the last one)
+}
+
+impl Segments {
+    /**
+     * Opens the segments of the target of the given compact name in the given
+     * data directory, creating the manifest if it doesn't exist yet (taking in
+     * the target's data file and sealed data file from before segments), and
+     * reconciling it with the segment files on disk (see `reconcile()`).
+     */
+    pub fn open(kind_name: &'static str, dir: &Path, span: SegmentSpan) -> Result<Segments, SPIOError> {
+        let manifest_path = dir.join(format!("{}.manifest.json", kind_name));
+        let manifest = match try!(read_json_or_prev(&manifest_path)) {
+            Some(manifest) => manifest,
+            None => {
+                let manifest = Manifest {
+                    version: MANIFEST_VERSION,
+                    segments: try!(import_legacy(kind_name, dir)),
+                };
+                try!(overwrite_json(&manifest, &manifest_path));
+                manifest
+            },
+        };
+
+        let mut segments = Segments {
+            kind_name: kind_name,
+            dir: dir.to_owned(),
+            span: span,
+            manifest_path: manifest_path,
+            manifest: manifest,
+            active: None,
+            floor_ms: i64::min_value(),
+        };
+        try!(segments.reconcile());
+
+        // reopen the last segment for appending (recovering from a crash mid-append)
+        let last = segments.manifest.segments.last().cloned();
+        if let Some(last) = last {
+            if !last.compressed {
+                let path = segments.dir.join(&last.file);
+                try!(datafile::repair_torn_tail(&path, datafile::HEADER_LEN, mem::size_of::<DataRecord>()));
+                let mut file = try!(File::open_from(OpenOptions::new().read(true).append(true).create(true), &path));
+                if try!(file.length_p(&path)) == 0 {
+                    try!(file.write_all(&datafile::header_bytes())
+                         .map_err(|_| SPIOError::Write(Some(path.clone()))));
+                }
+                segments.active = Some(file);
+            }
+        }
+        segments.floor_ms = try!(segments.last_time());
+        Ok(segments)
+    }
+
+    /**
+     * Brings the manifest in line with the segment files on disk, which a
+     * crash (or a manifest restored from its previous copy) may have left out
+     * of step with it: entries of missing files are dropped, and files not in
+     * the manifest are taken in as the last segments if they come after all
+     * others, removed if they hold no records (or are the uncompressed
+     * original of a compressed segment), and otherwise set aside with a
+     * `.stray` suffix.
+     */
+    fn reconcile(&mut self) -> Result<(), SPIOError> {
+        let mut changed = false;
+
+        let dir = self.dir.clone();
+        let before = self.manifest.segments.len();
+        self.manifest.segments.retain(|s| {
+            let exists = dir.join(&s.file).exists();
+            if !exists {
+                println!("WARNING: segment '{}' listed in the manifest is missing, dropping it.", s.file);
+            }
+            exists
+        });
+        changed |= self.manifest.segments.len() != before;
+
+        // segment files (of the form <kind>.data.<period>[.<n>].dat) not in the manifest, in name order
+        let prefix = format!("{}.data.", self.kind_name);
+        let mut unlisted = Vec::new();
+        let entries = try!(fs::read_dir(&self.dir).map_err(|_| SPIOError::Read(Some(self.dir.clone()))));
+        for entry in entries {
+            let entry = try!(entry.map_err(|_| SPIOError::Read(Some(self.dir.clone()))));
+            if let Some(name) = entry.file_name().to_str() {
+                if name.starts_with(&prefix) && name.ends_with(".dat") && name.len() > prefix.len() + 4 &&
+                        !self.manifest.segments.iter().any(|s| s.file == name) {
+                    unlisted.push(name.to_owned());
+                }
+            }
+        }
+        unlisted.sort();
+
+        for name in unlisted {
+            let path = self.dir.join(&name);
+            let compressed_name = path.with_extension("cdat").file_name()
+                .and_then(|n| n.to_str()).unwrap_or("").to_owned();
+            if self.manifest.segments.iter().any(|s| s.file == compressed_name) {
+                println!("Removing '{}', left behind by compressing it.", name);
+                try!(fs::remove_file(&path).map_err(|_| SPIOError::Write(Some(path.clone()))));
+                continue;
+            }
+
+            try!(datafile::repair_torn_tail(&path, datafile::HEADER_LEN, mem::size_of::<DataRecord>()));
+            let range = {
+                let file = try!(File::open_from(OpenOptions::new().read(true), &path));
+                if try!(file.metadata().map_err(|_| SPIOError::Metadata(Some(path.clone())))).len() <=
+                        datafile::HEADER_LEN as u64 {
+                    Some(None)
+                } else {
+                    let map = try!(
+                        Mmap::open(&file, Protection::Read)
+                        .map_err(|_| SPIOError::Read(Some(path.clone())))
+                    );
+                    let raw = unsafe { map.as_slice() };
+                    if datafile::detect_version(raw) != 2 {
+                        None
+                    } else {
+                        let data: &[DataRecord] = try!(
+                            raw_bytes_as_slice(&raw[datafile::HEADER_LEN..])
+                            .ok_or_else(|| SPIOError::Parse(Some(path.clone())))
+                        );
+                        Some(Some((data[0].time_ms(), data[data.len() - 1].time_ms())))
+                    }
+                }
+            };
+
+            let last_end = self.manifest.segments.last().map_or(i64::min_value(), |s| s.end);
+            match range {
+                Some(None) => {
+                    println!("Removing '{}', a segment without records.", name);
+                    try!(fs::remove_file(&path).map_err(|_| SPIOError::Write(Some(path.clone()))));
+                },
+                Some(Some((first, last))) if first >= last_end => {
+                    println!("Taking in segment '{}' missing from the manifest.", name);
+                    let (start, end, _) = self.span.period_of(first);
+                    self.manifest.segments.push(SegmentInfo {
+                        file: name,
+                        start: cmp::max(start, last_end),
+                        end: cmp::max(end, last + 1),
+                        compressed: false,
+                    });
+                    changed = true;
+                },
+                _ => {
+                    let stray = with_suffix(&path, "stray");
+                    println!("WARNING: setting aside '{}' (not in the manifest, and overlapping its segments) as '{}'.",
+                             name, stray.display());
+                    try!(fs::rename(&path, &stray).map_err(|_| SPIOError::Write(Some(stray.clone()))));
+                },
+            }
+        }
+
+        if changed {
+            try!(self.save_manifest());
+        }
+        Ok(())
+    }
+
+    /**
+     * Finds the time of the last record in the last segment (or the start of
+     * that segment, if it is empty).
+     */
+    fn last_time(&self) -> Result<i64, SPIOError> {
+        let last = match self.manifest.segments.last() {
+            Some(last) => last,
+            None => return Ok(i64::min_value()),
+        };
+
+        let path = self.dir.join(&last.file);
+        let file = try!(File::open_from(OpenOptions::new().read(true), &path));
+        let map = try!(
+            Mmap::open(&file, Protection::Read)
+            .map_err(|_| SPIOError::Read(Some(path.clone())))
+        );
+        let raw = unsafe { map.as_slice() };
+        let time = if last.compressed {
+            let compressed = try!(CompressedFile::parse(raw).ok_or_else(|| SPIOError::Parse(Some(path.clone()))));
+            compressed.blocks().last().map(|b| b.last_ms)
+        } else {
+            let data: &[DataRecord] = try!(
+                raw_bytes_as_slice(&raw[datafile::HEADER_LEN..])
+                .ok_or_else(|| SPIOError::Parse(Some(path.clone())))
+            );
+            data.last().map(|r| r.time_ms())
+        };
+        Ok(time.unwrap_or(last.start))
+    }
+
+    /**
+     * Whether a record with the given time can be appended, i.e. it isn't
+     * earlier than the last one appended. Records with the same time as the
+     * last are fine, as all those of a round of collection share a time.
+     */
+    pub fn accepts(&self, time_ms: i64) -> bool {
+        time_ms >= self.floor_ms
+    }
+
+    /**
+     * Appends the given records (which the clock going backwards made earlier
+     * than ones already appended, see `accepts()`) to a side file of
+     * out-of-order records, keeping them out of the time-ordered segments.
+     */
+    pub fn set_aside(&mut self, records: &[DataRecord]) -> Result<(), SPIOError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let path = self.dir.join(format!("{}.ooo.dat", self.kind_name));
+        let mut file = try!(File::open_from(OpenOptions::new().append(true).create(true), &path));
+        if try!(file.length_p(&path)) == 0 {
+            try!(file.write_all(&datafile::header_bytes()).map_err(|_| SPIOError::Write(Some(path.clone()))));
+        }
+        file.write_all(slice_as_raw_bytes(records)).map_err(|_| SPIOError::Write(Some(path)))
+    }
+
+    pub fn list(&self) -> &[SegmentInfo] {
+        &self.manifest.segments
+    }
+
+    fn save_manifest(&self) -> Result<(), SPIOError> {
+        overwrite_json(&self.manifest, &self.manifest_path)
+    }
+
+    /**
+     * Appends the given (time-ordered, and accepted, see `accepts()`) records,
+     * rolling over to a new segment whenever a record falls past the end of
+     * the last one.
+     */
+    pub fn append(&mut self, records: &[DataRecord]) -> Result<(), SPIOError> {
+        let mut begin = 0;
+        for (i, r) in records.iter().enumerate() {
+            debug_assert!(self.accepts(r.time_ms()));
+            self.floor_ms = r.time_ms();
+
+            let past_end = match (self.active.is_some(), self.manifest.segments.last()) {
+                (true, Some(last)) => r.time_ms() >= last.end,
+                _ => true,
+            };
+            if past_end {
+                try!(self.write_active(&records[begin..i]));
+                try!(self.rollover(r.time_ms()));
+                begin = i;
+            }
+        }
+        self.write_active(&records[begin..])
+    }
+
+    fn write_active(&mut self, records: &[DataRecord]) -> Result<(), SPIOError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let path = self.dir.join(&self.manifest.segments.last().unwrap().file);
+        self.active.as_mut().unwrap().write_all(slice_as_raw_bytes(records))
+            .map_err(|_| SPIOError::Write(Some(path)))
+    }
+
+    /**
+     * Seals the last segment and starts a new one for the period containing
+     * the given time.
+     */
+    fn rollover(&mut self, time_ms: i64) -> Result<(), SPIOError> {
+        if let Some(file) = self.active.take() {
+            try!(file.sync_all().map_err(|_| SPIOError::Write(None)));
+        }
+
+        // keep segments from overlapping (the last one may be compressed, so not appended to)
+        let (start, end, name) = self.span.period_of(time_ms);
+        let start = cmp::max(start, self.manifest.segments.last().map_or(i64::min_value(), |s| s.end));
+        let end = cmp::max(end, start + 1);
+
+        // the name may already be taken (e.g. after a change of span)
+        let mut file_name = format!("{}.data.{}.dat", self.kind_name, name);
+        let mut n = 1;
+        while self.manifest.segments.iter().any(|s| s.file == file_name) {
+            file_name = format!("{}.data.{}.{}.dat", self.kind_name, name, n);
+            n += 1;
+        }
+
+        /*
+         * a file by that name not in the manifest would have been taken in or
+         * set aside on opening, so never overwrite one
+         */
+        let path = self.dir.join(&file_name);
+        let mut file = try!(
+            OpenOptions::new().read(true).write(true).create_new(true).open(&path)
+            .map_err(|e| {
+                println!("ERROR: refusing to start segment '{}': {}", path.display(), e);
+                SPIOError::Open(Some(path.clone()))
+            })
+        );
+        try!(file.write_all(&datafile::header_bytes()).map_err(|_| SPIOError::Write(Some(path.clone()))));
+        // reopen for appending, as the active segment always is
+        let file = try!(File::open_from(OpenOptions::new().read(true).append(true), &path));
+
+        self.manifest.segments.push(SegmentInfo {
+            file: file_name,
+            start: start,
+            end: end,
+            compressed: false,
+        });
+        try!(self.save_manifest());
+        self.active = Some(file);
+        Ok(())
+    }
+
+    /**
+     * Calls the given function with the records in the given time range (in
+     * milliseconds from epoch, inclusive) in time order, in chunks, reading
+     * only the segments (and for compressed segments, the blocks) that
+     * overlap it. Chunks never split records taken at the same time.
+     */
+    pub fn for_each_chunk<F>(&self, lower_ms: i64, upper_ms: i64, mut f: F) -> Result<(), SPIOError>
+            where F: FnMut(&[DataRecord]) -> Result<(), SPIOError> {
+        for seg in self.manifest.segments.iter().filter(|s| s.start <= upper_ms && s.end > lower_ms) {
+            try!(self.read_segment(seg, lower_ms, upper_ms, &mut f));
+        }
+        Ok(())
+    }
+
+    /**
+     * Calls the given function with the records of the given segment in the
+     * given time range, in chunks, as for `for_each_chunk()`.
+     */
+    fn read_segment<F>(&self, seg: &SegmentInfo, lower_ms: i64, upper_ms: i64, f: &mut F) -> Result<(), SPIOError>
+            where F: FnMut(&[DataRecord]) -> Result<(), SPIOError> {
+        let after_upper = upper_ms.saturating_add(1);
+        let in_range = |data: &[DataRecord]| -> (usize, usize) {
+            (datafile::lower_bound(data, lower_ms), datafile::lower_bound(data, after_upper))
+        };
+
+        let path = self.dir.join(&seg.file);
+        let file = try!(File::open_from(OpenOptions::new().read(true), &path));
+        let map = try!(
+            Mmap::open(&file, Protection::Read)
+            .map_err(|_| SPIOError::Read(Some(path.clone())))
+        );
+        let raw = unsafe { map.as_slice() };
+
+        match datafile::detect_version(raw) {
+            2 => {
+                let data: &[DataRecord] = try!(
+                    raw_bytes_as_slice(&raw[datafile::HEADER_LEN..])
+                    .ok_or_else(|| SPIOError::Parse(Some(path.clone())))
+                );
+                let (begin, end) = in_range(data);
+                if begin < end {
+                    try!(f(&data[begin..end]));
+                }
+            },
+            3 => {
+                let compressed = try!(CompressedFile::parse(raw).ok_or_else(|| SPIOError::Parse(Some(path.clone()))));
+                let (first, last) = compressed.blocks_in_range(lower_ms, upper_ms);
+                for i in first..last {
+                    let block = try!(compressed.decode(i).ok_or_else(|| SPIOError::Parse(Some(path.clone()))));
+                    let (begin, end) = in_range(&block[..]);
+                    if begin < end {
+                        try!(f(&block[begin..end]));
+                    }
+                }
+            },
+            _ => return Err(SPIOError::Parse(Some(path))),
+        }
+        Ok(())
+    }
+
+    /**
+     * Checks the segments for violations of what reads rely on: files that
+     * are missing or can't be read, and records out of time order (within or
+     * across segments) or outside their segment's time range. Returns a
+     * description of each problem found.
+     */
+    pub fn fsck(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut prev_ms = i64::min_value();
+        let mut prev_end = i64::min_value();
+
+        for seg in self.manifest.segments.iter() {
+            if seg.start < prev_end {
+                problems.push(format!("{}: time range overlaps that of the previous segment", seg.file));
+            }
+            prev_end = seg.end;
+
+            let mut backwards = 0;
+            let mut outside = 0;
+            let mut first_backwards = None;
+            // (the binary searches for the whole range hold up even when out of order)
+            let result = self.read_segment(seg, i64::min_value(), i64::max_value(), &mut |chunk: &[DataRecord]| {
+                for r in chunk {
+                    let t = r.time_ms();
+                    if t < prev_ms {
+                        backwards += 1;
+                        first_backwards = first_backwards.or(Some((prev_ms, t)));
+                    }
+                    if t < seg.start || t >= seg.end {
+                        outside += 1;
+                    }
+                    prev_ms = t;
+                }
+                Ok(())
+            });
+
+            if let Err(e) = result {
+                problems.push(format!("{}: {}", seg.file, e));
+            }
+            if let Some((before, after)) = first_backwards {
+                problems.push(format!("{}: time goes backwards {} time(s), first from {} to {} (ms from epoch)",
+                                      seg.file, backwards, before, after));
+            }
+            if outside > 0 {
+                problems.push(format!("{}: {} record(s) outside the segment's time range", seg.file, outside));
+            }
+        }
+        problems
+    }
+
+    /**
+     * Drops whole segments (never the one being appended to) as necessary to
+     * satisfy the given retention policy at the given time: those that ended
+     * before *max_age*, and then the oldest until the total size is within
+     * *max_size*. Returns the number of segments dropped.
+     */
+    pub fn drop_expired(&mut self, policy: &RetentionPolicy, now_ms: i64) -> Result<usize, SPIOError> {
+        let droppable = self.manifest.segments.len() - if self.active.is_some() { 1 } else { 0 };
+
+        let mut sizes = Vec::with_capacity(self.manifest.segments.len());
+        for seg in self.manifest.segments.iter() {
+            let path = self.dir.join(&seg.file);
+            sizes.push(try!(fs::metadata(&path).map_err(|_| SPIOError::Metadata(Some(path.clone())))).len());
+        }
+        let mut total: u64 = sizes.iter().sum();
+
+        let mut cut = 0;
+        while cut < droppable {
+            let expired = policy.max_age.map_or(false, |a| self.manifest.segments[cut].end <= now_ms - a as i64 * 1000);
+            let oversize = policy.max_size.map_or(false, |s| total > s);
+            if !expired && !oversize {
+                break;
+            }
+            total -= sizes[cut];
+            cut += 1;
+        }
+        if cut == 0 {
+            return Ok(0);
+        }
+
+        /*
+         * take the segments out of the manifest before deleting them, so that
+         * a crash in between leaves stray files rather than missing segments
+         */
+        let dropped: Vec<SegmentInfo> = self.manifest.segments.drain(..cut).collect();
+        try!(self.save_manifest());
+        for seg in dropped.iter() {
+            let path = self.dir.join(&seg.file);
+            try!(fs::remove_file(&path).map_err(|_| SPIOError::Write(Some(path.clone()))));
+        }
+        Ok(cut)
+    }
+
+    /**
+     * Converts every sealed (i.e. not being appended to) uncompressed segment
+     * to the compressed block format. Returns the number of records converted.
+     */
+    pub fn compress_sealed(&mut self) -> Result<usize, SPIOError> {
+        let sealed = self.manifest.segments.len() - if self.active.is_some() { 1 } else { 0 };
+        let mut converted = 0;
+
+        for i in 0..sealed {
+            if self.manifest.segments[i].compressed {
+                continue;
+            }
+
+            let old_path = self.dir.join(&self.manifest.segments[i].file);
+            let new_path = old_path.with_extension("cdat");
+            {
+                let file = try!(File::open_from(OpenOptions::new().read(true), &old_path));
+                let map = try!(
+                    Mmap::open(&file, Protection::Read)
+                    .map_err(|_| SPIOError::Read(Some(old_path.clone())))
+                );
+                let raw = unsafe { map.as_slice() };
+                let data: &[DataRecord] = try!(
+                    raw_bytes_as_slice(&raw[datafile::HEADER_LEN..])
+                    .ok_or_else(|| SPIOError::Parse(Some(old_path.clone())))
+                );
+                try!(blockfile::write_file(&new_path, data));
+                converted += data.len();
+            }
+
+            let new_name = new_path.file_name().and_then(|n| n.to_str()).unwrap().to_owned();
+            self.manifest.segments[i].file = new_name;
+            self.manifest.segments[i].compressed = true;
+            try!(self.save_manifest());
+            try!(fs::remove_file(&old_path).map_err(|_| SPIOError::Write(Some(old_path.clone()))));
+        }
+        Ok(converted)
+    }
+}
+
+/**
+ * Takes in the data of a target from before segments: its sealed data file
+ * (if `stabping compress` was run) and its data file (migrated to the current
+ * format if necessary), each becoming a segment as is.
+ */
+fn import_legacy(kind_name: &str, dir: &Path) -> Result<Vec<SegmentInfo>, SPIOError> {
+    let mut segments = Vec::new();
+    let mut sealed_until = None;
+
+    let sealed_name = format!("{}.sealed.dat", kind_name);
+    let sealed_path = dir.join(&sealed_name);
+    if sealed_path.exists() {
+        let file = try!(File::open_from(OpenOptions::new().read(true), &sealed_path));
+        let map = try!(
+            Mmap::open(&file, Protection::Read)
+            .map_err(|_| SPIOError::Read(Some(sealed_path.clone())))
+        );
+        let compressed = try!(
+            CompressedFile::parse(unsafe { map.as_slice() })
+            .ok_or_else(|| SPIOError::Parse(Some(sealed_path.clone())))
+        );
+        if let (Some(first), Some(last)) = (compressed.blocks().first(), compressed.blocks().last()) {
+            segments.push(SegmentInfo {
+                file: sealed_name,
+                start: first.first_ms,
+                end: last.last_ms + 1,
+                compressed: true,
+            });
+            sealed_until = Some(last.last_ms);
+        }
+    }
+
+    let data_name = format!("{}.data.dat", kind_name);
+    let data_path = dir.join(&data_name);
+    if data_path.exists() {
+        try!(datafile::migrate_if_needed(&data_path));
+        try!(datafile::repair_torn_tail(&data_path, datafile::HEADER_LEN, mem::size_of::<DataRecord>()));
+
+        let (first, last) = {
+            let file = try!(File::open_from(OpenOptions::new().read(true), &data_path));
+            if try!(file.metadata().map_err(|_| SPIOError::Metadata(Some(data_path.clone())))).len() <=
+                    datafile::HEADER_LEN as u64 {
+                (None, None)
+            } else {
+                let map = try!(
+                    Mmap::open(&file, Protection::Read)
+                    .map_err(|_| SPIOError::Read(Some(data_path.clone())))
+                );
+                let raw = unsafe { map.as_slice() };
+                let data: &[DataRecord] = try!(
+                    raw_bytes_as_slice(&raw[datafile::HEADER_LEN..])
+                    .ok_or_else(|| SPIOError::Parse(Some(data_path.clone())))
+                );
+
+                // skip anything also sealed (if sealing was interrupted)
+                let begin = sealed_until.map_or(0, |t| datafile::lower_bound(data, t + 1));
+                if begin < data.len() {
+                    (Some(data[begin].time_ms()), Some(data[data.len() - 1].time_ms()))
+                } else {
+                    (None, None)
+                }
+            }
+        };
+
+        match (first, last) {
+            (Some(first), Some(last)) => segments.push(SegmentInfo {
+                file: data_name,
+                start: first,
+                // keep appending to it until the end of the day
+                end: SegmentSpan::Day.period_of(last).1,
+                compressed: false,
+            }),
+            _ => try!(fs::remove_file(&data_path).map_err(|_| SPIOError::Write(Some(data_path.clone())))),
+        }
+    }
+
+    if !segments.is_empty() {
+        println!("Moved {} existing data into {} segment(s).", kind_name, segments.len());
+    }
+    Ok(segments)
+}
+
+#[test]
+fn periods_of_spans() {
+    // 2017-02-28T23:59:59Z and a second later
+    let t = 1488326399_000;
+    let (start, end, name) = SegmentSpan::Day.period_of(t);
+    assert_eq!((end - start, name.as_str()), (86_400_000, "2017-02-28"));
+    assert_eq!(SegmentSpan::Day.period_of(t + 1000).0, end);
+
+    let (start, end, name) = SegmentSpan::Month.period_of(t);
+    assert_eq!((end - start, name.as_str()), (28 * 86_400_000, "2017-02"));
+    assert_eq!(SegmentSpan::Month.period_of(t + 1000).2, "2017-03");
+}
+
+#[test]
+fn sets_aside_backwards_records_and_checks_order() {
+    use helpers::TempDir;
+
+    let dir = TempDir::new("fsck");
+
+    let mut segments = Segments::open("tcpping", &dir, SegmentSpan::Day).unwrap();
+    segments.append(&[DataRecord::new(5000, 0, 1), DataRecord::new(5000, 1, 1)]).unwrap();
+    assert!(segments.accepts(5000));
+    assert!(!segments.accepts(4000));
+    segments.set_aside(&[DataRecord::new(4000, 0, 2)]).unwrap();
+    assert!(segments.fsck().is_empty());
+
+    // the floor survives a reopen
+    drop(segments);
+    let segments = Segments::open("tcpping", &dir, SegmentSpan::Day).unwrap();
+    assert!(!segments.accepts(4000));
+
+    // as written by an older version, without the check
+    {
+        let mut f = OpenOptions::new().append(true).open(dir.join("tcpping.data.1970-01-01.dat")).unwrap();
+        f.write_all(slice_as_raw_bytes(&[DataRecord::new(3000, 0, 3), DataRecord::new(6000, 0, 4)])).unwrap();
+    }
+    let problems = segments.fsck();
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].contains("backwards 1 time(s), first from 5000 to 3000"));
+}
+
+#[test]
+fn rolls_over_reads_and_drops_segments() {
+    use helpers::TempDir;
+
+    let dir = TempDir::new("segments");
+
+    let day = 86_400_000;
+    let mut segments = Segments::open("tcpping", &dir, SegmentSpan::Day).unwrap();
+    segments.append(&[DataRecord::new(day, 0, 1), DataRecord::new(day + 10, 0, 2),
+                      DataRecord::new(2 * day, 0, 3)]).unwrap();
+    segments.append(&[DataRecord::new(3 * day + 5, 0, 4)]).unwrap();
+    assert_eq!(segments.list().len(), 3);
+    assert_eq!(segments.list()[1].file, "tcpping.data.1970-01-03.dat");
+
+    // reads only touch what's in range, across segments and compressed ones alike
+    assert_eq!(segments.compress_sealed().unwrap(), 3);
+    let mut vals = Vec::new();
+    segments.for_each_chunk(day + 10, 3 * day + 5, |c| {
+        vals.extend(c.iter().map(|r| r.val()));
+        Ok(())
+    }).unwrap();
+    assert_eq!(vals, vec![2, 3, 4]);
+
+    // the manifest survives a reopen, and appends continue in the last segment
+    drop(segments);
+    let mut segments = Segments::open("tcpping", &dir, SegmentSpan::Day).unwrap();
+    segments.append(&[DataRecord::new(3 * day + 6, 0, 5)]).unwrap();
+    assert_eq!(segments.list().len(), 3);
+
+    // retention drops whole segments, never the last one
+    let policy = RetentionPolicy { max_age: Some(1), max_size: None };
+    assert_eq!(segments.drop_expired(&policy, 10 * day).unwrap(), 2);
+    assert_eq!(segments.list().len(), 1);
+    assert!(!dir.join("tcpping.data.1970-01-02.cdat").exists());
+}
+
+#[test]
+fn reconciles_manifest_with_files_on_disk() {
+    use helpers::TempDir;
+
+    let dir = TempDir::new("reconcile");
+
+    let day = 86_400_000;
+    {
+        let mut segments = Segments::open("tcpping", &dir, SegmentSpan::Day).unwrap();
+        segments.append(&[DataRecord::new(day, 0, 1)]).unwrap();
+    }
+    let manifest_path = dir.join("tcpping.manifest.json");
+    let listed_one = fs::read(&manifest_path).unwrap();
+    {
+        let mut segments = Segments::open("tcpping", &dir, SegmentSpan::Day).unwrap();
+        segments.append(&[DataRecord::new(2 * day, 0, 2)]).unwrap();
+    }
+
+    // as if the manifest had fallen back to its copy from before the rollover
+    fs::write(&manifest_path, &listed_one).unwrap();
+    // and a crash had left behind a segment file without records
+    {
+        let mut f = File::create(dir.join("tcpping.data.1970-01-04.dat")).unwrap();
+        f.write_all(&datafile::header_bytes()).unwrap();
+    }
+
+    let mut segments = Segments::open("tcpping", &dir, SegmentSpan::Day).unwrap();
+    assert_eq!(segments.list().len(), 2);
+    assert_eq!(segments.list()[1].file, "tcpping.data.1970-01-03.dat");
+    assert!(!dir.join("tcpping.data.1970-01-04.dat").exists());
+
+    // appends continue in the segment taken in, and later ones never overwrite a file
+    segments.append(&[DataRecord::new(2 * day + 1, 0, 3)]).unwrap();
+    File::create(dir.join("tcpping.data.1970-01-05.dat")).unwrap();
+    assert!(segments.append(&[DataRecord::new(4 * day, 0, 4)]).is_err());
+
+    let mut vals = Vec::new();
+    segments.for_each_chunk(0, 3 * day, |c| {
+        vals.extend(c.iter().map(|r| r.val()));
+        Ok(())
+    }).unwrap();
+    assert_eq!(vals, vec![1, 2, 3]);
+}
@@ -0,0 +1,230 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * A minimal SMTP client, just enough to send plain text email (with STARTTLS
+ * and AUTH PLAIN) to a mail server for delivery.
+ */
+use std::time::Duration;
+use std::io::{Read, Write};
+
+use chrono::Local;
+use openssl::ssl::{SslConnector, SslMethod};
+use rustc_serialize::base64::{ToBase64, STANDARD};
+
+use options::SmtpSettings;
+use helpers::{with_default_port, connect_timeout};
+use tlsexpiry::host_of;
+
+/// how long to wait on the mail server before giving up on it
+const SMTP_TIMEOUT_SECS: u64 = 30;
+
+/**
+ * Reads a (possibly multi-line) reply, returning its code and text.
+ */
+fn read_reply<S: Read>(stream: &mut S) -> Result<(u32, String), String> {
+    let mut text = String::new();
+    loop {
+        // read a line a byte at a time, so nothing past the reply is consumed
+        let mut line = Vec::new();
+        while !line.ends_with(b"\r\n") {
+            let mut byte = [0; 1];
+            match stream.read(&mut byte) {
+                Ok(0) => return Err("Mail server closed the connection.".to_owned()),
+                Ok(_) => line.push(byte[0]),
+                Err(e) => return Err(format!("{}", e)),
+            }
+        }
+        let line = String::from_utf8_lossy(&line).into_owned();
+        if line.len() < 5 {
+            return Err(format!("Malformed reply from mail server: '{}'", line.trim()));
+        }
+        text.push_str(&line[4..]);
+
+        // e.g. "250-PIPELINING" is followed by more lines, "250 OK" is the last
+        if line.as_bytes()[3] != b'-' {
+            let code = try!(line[..3].parse().map_err(|_| {
+                format!("Malformed reply from mail server: '{}'", line.trim())
+            }));
+            return Ok((code, text));
+        }
+    }
+}
+
+/**
+ * Sends the given command line (if any) and reads the reply, failing unless
+ * its code is the expected one.
+ */
+fn command<S: Read + Write>(stream: &mut S, line: Option<&str>, expect: u32) -> Result<String, String> {
+    if let Some(line) = line {
+        try!(stream.write_all(format!("{}\r\n", line).as_bytes()).map_err(|e| format!("{}", e)));
+    }
+    let (code, text) = try!(read_reply(stream));
+    if code != expect {
+        return Err(format!("Mail server replied with {} {}", code, text.trim()));
+    }
+    Ok(text)
+}
+
+/**
+ * Formats the message, with dot-stuffing and CRLF line endings, as sent
+ * after DATA.
+ */
+fn format_message(settings: &SmtpSettings, subject: &str, body: &str) -> String {
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+        settings.from, settings.to.join(", "), subject, Local::now().to_rfc2822());
+    for line in body.lines() {
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message.push_str(".");
+    message
+}
+
+/**
+ * Carries out the rest of the session after greeting (and any STARTTLS),
+ * from authenticating to handing over the message.
+ */
+fn deliver<S: Read + Write>(mut stream: S, settings: &SmtpSettings, ehlo: &str,
+                            subject: &str, body: &str) -> Result<(), String> {
+    if let Some(ref username) = settings.username {
+        let password = settings.password.as_ref().map_or("", |p| &p[..]);
+        if !ehlo.contains("AUTH") {
+            return Err("Mail server doesn't offer authentication.".to_owned());
+        }
+        let credentials = format!("\0{}\0{}", username, password).as_bytes().to_base64(STANDARD);
+        try!(command(&mut stream, Some(&format!("AUTH PLAIN {}", credentials)), 235));
+    }
+
+    try!(command(&mut stream, Some(&format!("MAIL FROM:<{}>", settings.from)), 250));
+    for to in settings.to.iter() {
+        try!(command(&mut stream, Some(&format!("RCPT TO:<{}>", to)), 250));
+    }
+    try!(command(&mut stream, Some("DATA"), 354));
+    try!(command(&mut stream, Some(&format_message(settings, subject, body)), 250));
+    let _ = command(&mut stream, Some("QUIT"), 221);
+    Ok(())
+}
+
+/**
+ * Sends an email with the given subject and (plain text) body as specified
+ * by the given settings.
+ */
+pub fn send_mail(settings: &SmtpSettings, subject: &str, body: &str) -> Result<(), String> {
+    if settings.to.is_empty() {
+        return Err("No recipients to send email to.".to_owned());
+    }
+
+    let addr = with_default_port(&settings.server, 587);
+    let timeout = Duration::from_secs(SMTP_TIMEOUT_SECS);
+    let mut stream = try!(connect_timeout(&addr, timeout).map_err(|e| format!("{}", e)));
+    try!(stream.set_read_timeout(Some(timeout)).map_err(|e| format!("{}", e)));
+    try!(stream.set_write_timeout(Some(timeout)).map_err(|e| format!("{}", e)));
+
+    try!(command(&mut stream, None, 220));
+    let ehlo = try!(command(&mut stream, Some("EHLO stabping"), 250));
+
+    if !settings.starttls.unwrap_or(true) {
+        return deliver(stream, settings, &ehlo, subject, body);
+    }
+
+    // never send credentials or mail in the clear unless told to
+    if !ehlo.contains("STARTTLS") {
+        return Err("Mail server doesn't offer STARTTLS.".to_owned());
+    }
+    try!(command(&mut stream, Some("STARTTLS"), 220));
+    let connector = try!(SslConnector::builder(SslMethod::tls()).map_err(|e| format!("{}", e))).build();
+    let mut stream = try!(
+        connector.connect(host_of(&addr), stream)
+        .map_err(|e| format!("TLS handshake failed: {}", e))
+    );
+    // what the server offers may be different once encrypted
+    let ehlo = try!(command(&mut stream, Some("EHLO stabping"), 250));
+    deliver(stream, settings, &ehlo, subject, body)
+}
+
+#[test]
+fn sends_mail_to_sink() {
+    use std::thread;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    // a stand-in mail server, accepting anything and keeping the transcript
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = format!("{}", listener.local_addr().unwrap());
+    let sink = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut transcript = String::new();
+        stream.write_all(b"220 sink ready\r\n").unwrap();
+        let mut in_data = false;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            transcript.push_str(&line);
+            let reply: &[u8] = if in_data {
+                if line != ".\r\n" {
+                    continue;
+                }
+                in_data = false;
+                b"250 queued\r\n"
+            } else if line.starts_with("EHLO") {
+                b"250-sink\r\n250 AUTH PLAIN\r\n"
+            } else if line.starts_with("AUTH") {
+                b"235 ok\r\n"
+            } else if line.starts_with("DATA") {
+                in_data = true;
+                b"354 go ahead\r\n"
+            } else if line.starts_with("QUIT") {
+                stream.write_all(b"221 bye\r\n").unwrap();
+                break;
+            } else {
+                b"250 ok\r\n"
+            };
+            stream.write_all(reply).unwrap();
+        }
+        transcript
+    });
+
+    let settings = SmtpSettings {
+        server: server,
+        starttls: Some(false),
+        username: Some("user".to_owned()),
+        password: Some("pass".to_owned()),
+        from: "stabping@example.com".to_owned(),
+        to: vec!["a@example.com".to_owned(), "b@example.com".to_owned()],
+        digest_hour: None,
+    };
+    send_mail(&settings, "Test", "Hello\n.hidden dot\nBye").unwrap();
+
+    let transcript = sink.join().unwrap();
+    assert!(transcript.contains("AUTH PLAIN AHVzZXIAcGFzcw==\r\n"));
+    assert!(transcript.contains("RCPT TO:<b@example.com>\r\n"));
+    assert!(transcript.contains("Subject: Test\r\n"));
+    assert!(transcript.contains("\r\nHello\r\n..hidden dot\r\nBye\r\n.\r\n"));
+
+    // refuses to go on in the clear when STARTTLS was asked for but isn't offered
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = format!("{}", listener.local_addr().unwrap());
+    let sink = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"220 sink ready\r\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(stream.try_clone().unwrap()).read_line(&mut line).unwrap();
+        stream.write_all(b"250 sink\r\n").unwrap();
+    });
+    let settings = SmtpSettings { server: server, starttls: None, ..settings };
+    assert!(send_mail(&settings, "Test", "Hello").is_err());
+    sink.join().unwrap();
+}
@@ -0,0 +1,362 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Summary statistics (percentiles, availability, outages) of each address of
+ * a target over a time range, computed by the same scan of the data segments
+ * (or of a rollup tier) as reading data back.
+ */
+use std::cmp;
+
+use helpers::{SPIOError, nearest_rank};
+use persist::TargetManager;
+use datafile;
+use datafile::Record;
+use reader::Selection;
+use rollup::RollupRecord;
+use maintenance;
+use options::is_failure;
+
+/**
+ * A request from the client for summary statistics of a target in the time
+ * range `lower` to `upper` (in seconds from epoch), for the addresses selected
+ * as for data requests (see `reader::DataRequest`). If `resolution` is given,
+ * the coarsest rollup tier no wider than it is scanned instead of every
+ * record, making percentiles (of the bucket averages) and outages (in whole
+ * buckets) approximate. Data collected while an address was in maintenance
+ * (of a bucket starting in maintenance, for rollups) is left out.
+ *
+ * Availability and percentiles are weighted by time rather than per attempt,
+ * so that samples taken at the fast interval while an address misbehaves
+ * don't count for more than the normal ones: each sample covers the time
+ * until the next sample of its address, up to the target's interval (and a
+ * rollup bucket its width, split between its successes and failures).
+ */
+#[derive(RustcDecodable, Debug)]
+pub struct StatsRequest {
+    nonce: i32,
+    lower: i32,
+    upper: i32,
+    resolution: Option<i32>,
+    addrs: Option<Vec<String>>,
+    indices: Option<Vec<i32>>,
+}
+
+impl StatsRequest {
+    /**
+     * A request for statistics of the given target's current addresses.
+     */
+    pub fn current(tm: &TargetManager, lower: i32, upper: i32, resolution: Option<i32>) -> StatsRequest {
+        StatsRequest {
+            nonce: tm.options_read().nonce,
+            lower: lower,
+            upper: upper,
+            resolution: resolution,
+            addrs: None,
+            indices: None,
+        }
+    }
+}
+
+/**
+ * Summary statistics of one address. Values (min, mean, max and percentiles)
+ * are only over successfully collected values, and times are in seconds.
+ */
+#[derive(RustcEncodable, Debug, PartialEq)]
+pub struct AddrStats {
+    pub index: i32,
+    pub addr: Option<String>,
+    pub count: u64,
+    pub errors: u64,
+    pub availability: Option<f64>,  // percentage of the covered time spent succeeding
+    pub covered: u64,  // seconds covered by the address's samples
+    pub min: Option<i32>,
+    pub mean: Option<f64>,
+    pub max: Option<i32>,
+    pub p50: Option<i32>,
+    pub p95: Option<i32>,
+    pub p99: Option<i32>,
+    pub outages: u32,  // number of runs of consecutive failures
+    pub longest_outage: i64,
+    pub longest_outage_start: Option<i64>,
+    pub maintenance: u64,  // attempts left out for being in maintenance
+}
+
+/**
+ * Running statistics of one address.
+ */
+struct Accum {
+    count: u64,
+    errors: u64,
+    min: i32,
+    max: i32,
+    sum: i64,
+    values: Vec<(i32, u64)>,  // (value, weight in millis)
+    up: u64,  // millis covered by successes
+    down: u64,  // millis covered by failures
+    pending: Option<(i64, i32)>,  // (time in millis, value) of the last sample, still to be weighted
+    cap_ms: i64,
+    outages: u32,
+    run: Option<(i64, i64)>,  // (start, end) in millis of the ongoing outage
+    longest: Option<(i64, i64)>,  // (duration, start) in millis of the longest outage
+    maintenance: u64,
+}
+
+impl Accum {
+    /**
+     * Running statistics where a sample covers at most `cap_ms` (the target's
+     * interval).
+     */
+    fn new(cap_ms: i64) -> Accum {
+        Accum {
+            count: 0, errors: 0, min: i32::max_value(), max: i32::min_value(), sum: 0,
+            values: Vec::new(), up: 0, down: 0, pending: None, cap_ms: cap_ms,
+            outages: 0, run: None, longest: None, maintenance: 0,
+        }
+    }
+
+    /**
+     * Takes into account a single sample. It is only added once the next
+     * sample (or the end) shows how much time it covers.
+     */
+    fn sample(&mut self, time_ms: i64, val: i32) {
+        self.flush(Some(time_ms));
+        self.pending = Some((time_ms, val));
+    }
+
+    /**
+     * Adds the pending sample (if any), covering the time until `next_ms` (or
+     * the whole cap). Raw samples are not strictly in time order, so a sample
+     * followed by an earlier one covers no time.
+     */
+    fn flush(&mut self, next_ms: Option<i64>) {
+        if let Some((t, val)) = self.pending.take() {
+            let width_ms = next_ms.map_or(self.cap_ms, |n| cmp::max(0, cmp::min(n - t, self.cap_ms)));
+            if is_failure(val) {
+                self.add(t, width_ms, 0, 1, 0, 0, 0);
+            } else {
+                self.add(t, width_ms, 1, 0, val, val, val as i64);
+            }
+        }
+    }
+
+    /**
+     * Takes into account `count` successful values (summing to `sum`) and
+     * `errors` failures collected in the `width_ms` from `time_ms`, which is
+     * split between them by their number.
+     */
+    fn add(&mut self, time_ms: i64, width_ms: i64, count: u64, errors: u64, min: i32, max: i32, sum: i64) {
+        if count + errors == 0 {
+            return;
+        }
+        let up = width_ms as u64 * count / (count + errors);
+        self.up += up;
+        self.down += width_ms as u64 - up;
+        self.errors += errors;
+        if count > 0 {
+            self.count += count;
+            self.min = cmp::min(self.min, min);
+            self.max = cmp::max(self.max, max);
+            self.sum += sum;
+            self.values.push(((sum / count as i64) as i32, up));
+            // any success ends an ongoing outage
+            self.end_run(Some(time_ms));
+        } else if errors > 0 {
+            self.run = match self.run {
+                Some((start, _)) => Some((start, time_ms + width_ms)),
+                None => {
+                    self.outages += 1;
+                    Some((time_ms, time_ms + width_ms))
+                },
+            };
+        }
+    }
+
+    /**
+     * Ends the ongoing outage (if any) at the given time, or where its last
+     * failure was.
+     */
+    fn end_run(&mut self, at_ms: Option<i64>) {
+        if let Some((start, end)) = self.run.take() {
+            let duration = at_ms.unwrap_or(end) - start;
+            if self.longest.map_or(true, |(d, _)| duration > d) {
+                self.longest = Some((duration, start));
+            }
+        }
+    }
+
+    fn percentile(sorted: &[(i32, u64)], total: u64, p: u64) -> Option<i32> {
+        let rank = nearest_rank(total, p);
+        let mut seen = 0;
+        for &(v, w) in sorted {
+            seen += w;
+            if seen >= rank {
+                return Some(v);
+            }
+        }
+        None
+    }
+
+    fn finish(mut self, index: i32, addr: Option<String>) -> AddrStats {
+        self.flush(None);
+        self.end_run(None);
+        self.values.sort();
+        let some = self.count > 0;
+        let covered = self.up + self.down;
+        AddrStats {
+            index: index,
+            addr: addr,
+            count: self.count,
+            errors: self.errors,
+            availability: if covered > 0 { Some(self.up as f64 * 100.0 / covered as f64) } else { None },
+            covered: covered / 1000,
+            min: if some { Some(self.min) } else { None },
+            mean: if some { Some(self.sum as f64 / self.count as f64) } else { None },
+            max: if some { Some(self.max) } else { None },
+            p50: Accum::percentile(&self.values, self.up, 50),
+            p95: Accum::percentile(&self.values, self.up, 95),
+            p99: Accum::percentile(&self.values, self.up, 99),
+            outages: self.outages,
+            longest_outage: self.longest.map_or(0, |(d, _)| d / 1000),
+            longest_outage_start: self.longest.map(|(_, s)| s / 1000),
+            maintenance: self.maintenance,
+        }
+    }
+}
+
+/**
+ * Computes the requested statistics for the given target, one entry per
+ * selected address in order. Returns `None` if the request is amiss (e.g. for
+ * an unknown nonce).
+ */
+pub fn compute(tm: &TargetManager, sr: StatsRequest) -> Option<Result<Vec<AddrStats>, SPIOError>> {
+    if sr.upper < sr.lower {
+        return None;
+    }
+    let selection = match Selection::new(tm, sr.nonce, sr.addrs, sr.indices) {
+        Some(s) => s,
+        None => return None,
+    };
+    let (ordered_list, _) = match selection.indices(tm) {
+        Some(indices) => indices,
+        None => return None,
+    };
+
+    // map each address index to its positions in the ordered list
+    let mut positions: Vec<Vec<usize>> = Vec::new();
+    for (pos, &i) in ordered_list.iter().enumerate() {
+        if positions.len() <= i as usize {
+            positions.resize(i as usize + 1, Vec::new());
+        }
+        positions[i as usize].push(pos);
+    }
+
+    let interval_ms = tm.options_read().interval as i64;
+    let mut accums: Vec<Accum> = ordered_list.iter().map(|_| Accum::new(interval_ms)).collect();
+    let lower_ms = sr.lower as i64 * 1000;
+    let upper_ms = sr.upper as i64 * 1000 + 999;
+
+    // when each selected address was in maintenance
+    let in_maintenance: Vec<Vec<(i64, i64)>> = ordered_list.iter().map(|&i| match tm.addr_of(i) {
+        Some(addr) => tm.maintenance_intervals(&addr, sr.lower as i64, sr.upper as i64),
+        None => Vec::new(),
+    }).collect();
+
+    let resolution = sr.resolution.unwrap_or(0) as i64;
+    let result = if let Some(tier) = tm.rollups().iter().rev().find(|t| t.width <= resolution) {
+        let width_ms = tier.width * 1000;
+        tier.with_records(|records, open| {
+            let begin = datafile::lower_bound(records, lower_ms);
+            let in_range = |r: &&RollupRecord| r.time_ms() >= lower_ms && r.time_ms() <= upper_ms;
+            for r in records[begin..].iter().chain(open.iter()).filter(in_range) {
+                let failures = r.errors() + r.mismatches();
+                for &pos in positions.get(r.index() as usize).map_or(&[][..], |p| &p[..]) {
+                    if maintenance::within(&in_maintenance[pos], r.time_ms() / 1000) {
+                        accums[pos].maintenance += (r.count() + failures) as u64;
+                        continue;
+                    }
+                    accums[pos].add(r.time_ms(), width_ms, r.count() as u64, failures as u64,
+                                    r.min(), r.max(), r.sum());
+                }
+            }
+            Ok(())
+        }).map_err(|_| SPIOError::Read(None))
+    } else {
+        tm.segments_read().for_each_chunk(lower_ms, upper_ms, |chunk| {
+            let begin = datafile::lower_bound(chunk, lower_ms);
+            for r in chunk[begin..].iter().take_while(|r| r.time_ms() <= upper_ms) {
+                let val = r.val();
+                for &pos in positions.get(r.index() as usize).map_or(&[][..], |p| &p[..]) {
+                    if maintenance::within(&in_maintenance[pos], r.time_ms() / 1000) {
+                        // the sample before only covers the time up to here
+                        accums[pos].flush(Some(r.time_ms()));
+                        accums[pos].maintenance += 1;
+                        continue;
+                    }
+                    accums[pos].sample(r.time_ms(), val);
+                }
+            }
+            Ok(())
+        })
+    };
+
+    Some(result.map(|_| {
+        accums.into_iter().zip(ordered_list.iter())
+            .map(|(a, &i)| a.finish(i, tm.addr_of(i)))
+            .collect()
+    }))
+}
+
+#[test]
+fn summarizes_values_and_outages() {
+    use options::SENTINEL_ERROR;
+
+    let mut a = Accum::new(60_000);
+    let vals = [10, SENTINEL_ERROR, SENTINEL_ERROR, 20, 30, SENTINEL_ERROR, 40];
+    for (i, &v) in vals.iter().enumerate() {
+        a.sample(i as i64 * 60_000, v);
+    }
+    let stats = a.finish(0, None);
+    assert_eq!((stats.count, stats.errors, stats.outages), (4, 3, 2));
+    assert_eq!((stats.min, stats.max, stats.mean), (Some(10), Some(40), Some(25.0)));
+    assert_eq!((stats.p50, stats.p95), (Some(20), Some(40)));
+    assert_eq!(stats.availability.map(|a| a.round()), Some(57.0));
+    // failing at 1 and 2 minutes in, and only succeeding again at 3
+    assert_eq!((stats.longest_outage, stats.longest_outage_start), (120, Some(60)));
+
+    // an outage still going on at the end lasts until its last failure
+    let mut a = Accum::new(60_000);
+    a.add(0, 60_000, 0, 5, 0, 0, 0);
+    a.add(60_000, 60_000, 0, 5, 0, 0, 0);
+    let stats = a.finish(0, None);
+    assert_eq!((stats.availability, stats.p50, stats.outages, stats.longest_outage), (Some(0.0), None, 1, 120));
+}
+
+#[test]
+fn weights_samples_by_the_time_they_cover() {
+    use options::SENTINEL_ERROR;
+
+    // every minute, but every 10 seconds while slow (1-2 minutes in) and
+    // failing (2-3 minutes in)
+    let mut a = Accum::new(60_000);
+    a.sample(0, 10);
+    for i in 0..6 {
+        a.sample(60_000 + i * 10_000, 500);
+    }
+    for i in 0..6 {
+        a.sample(120_000 + i * 10_000, SENTINEL_ERROR);
+    }
+    a.sample(180_000, 10);
+    a.sample(240_000, 10);
+    let stats = a.finish(0, None);
+    assert_eq!((stats.count, stats.errors, stats.covered), (9, 6, 300));
+    // per attempt, this would be 60% available with a median of 500
+    assert_eq!(stats.availability, Some(80.0));
+    assert_eq!((stats.p50, stats.p95), (Some(10), Some(500)));
+    assert_eq!((stats.outages, stats.longest_outage, stats.longest_outage_start), (1, 60, Some(120)));
+}
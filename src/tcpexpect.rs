@@ -0,0 +1,376 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+use std::cmp;
+use std::io::{Read, Write};
+use std::thread;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+
+use std::time::Duration;
+use time::precise_time_ns;
+
+use std::net::{TcpStream, ToSocketAddrs};
+
+use regex::bytes::Regex;
+
+use options::{SENTINEL_ERROR, SENTINEL_MISMATCH};
+use options::TargetResults;
+use persist::TargetManager;
+use helpers::now_millis;
+
+/// the most bytes of a response we are willing to buffer while matching
+const MAX_RESPONSE_LEN: usize = 4096;
+
+/**
+ * A parsed address of this target, in the form `host:port|send|expect` where
+ * both `send` (a payload with `\r`, `\n`, `\t`, `\\` and `\xHH` escapes) and
+ * `expect` (a regex the response must match) are optional. An empty `send`
+ * means we only wait for the server's banner.
+ */
+#[derive(Debug)]
+pub struct ExpectSpec {
+    pub host: String,
+    pub send: Vec<u8>,
+    pub expect: Option<Regex>,
+}
+
+/**
+ * The outcome of a single send/expect attempt.
+ */
+#[derive(Debug, PartialEq)]
+pub enum ExpectOutcome {
+    Matched(u64),  // time to banner/response, in nanos
+    Mismatch,
+    Failed,
+}
+
+/**
+ * Unescapes the `send` portion of an address into raw bytes.
+ */
+fn unescape(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'r') => out.push(b'\r'),
+            Some(b'n') => out.push(b'\n'),
+            Some(b't') => out.push(b'\t'),
+            Some(b'\\') => out.push(b'\\'),
+            Some(b'x') => {
+                // exactly two hex digits
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                if hex.len() != 2 || !hex.iter().all(|d| (*d as char).is_digit(16)) {
+                    return None;
+                }
+                match String::from_utf8(hex).ok()
+                      .and_then(|h| u8::from_str_radix(&h, 16).ok()) {
+                    Some(v) => out.push(v),
+                    None => return None,
+                }
+            },
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+impl ExpectSpec {
+    /**
+     * Parses an address of this target, returning `None` if it is malformed.
+     */
+    pub fn parse(addr: &str) -> Option<ExpectSpec> {
+        // the expect regex is last so that it may itself contain '|'
+        let mut parts = addr.splitn(3, '|');
+        let host = parts.next().unwrap_or("").trim().to_owned();
+        if host.is_empty() {
+            return None;
+        }
+        let send = match unescape(parts.next().unwrap_or("")) {
+            Some(s) => s,
+            None => return None,
+        };
+        let expect = match parts.next() {
+            Some(e) if !e.is_empty() => match Regex::new(e) {
+                Ok(re) => Some(re),
+                Err(_) => return None,
+            },
+            _ => None,
+        };
+
+        Some(ExpectSpec {
+            host: host,
+            send: send,
+            expect: expect,
+        })
+    }
+
+    /**
+     * Connects, sends the payload (if any), and times how long it takes until
+     * the response matches the expect regex (or until any response arrives
+     * if there is no regex).
+     */
+    pub fn attempt(&self, timeout: Duration) -> ExpectOutcome {
+        let start = precise_time_ns();
+        let deadline = start + timeout.as_secs() * 1_000_000_000 + timeout.subsec_nanos() as u64;
+        // the time left until the deadline, if any
+        let remaining = || {
+            let now = precise_time_ns();
+            if now < deadline {
+                Some(Duration::new((deadline - now) / 1_000_000_000,
+                                   ((deadline - now) % 1_000_000_000) as u32))
+            } else {
+                None
+            }
+        };
+
+        let sock_addr = match self.host.to_socket_addrs().ok().and_then(|mut a| a.next()) {
+            Some(a) => a,
+            None => return ExpectOutcome::Failed,
+        };
+        let mut stream = match remaining().and_then(|t| TcpStream::connect_timeout(&sock_addr, t).ok()) {
+            Some(s) => s,
+            None => return ExpectOutcome::Failed,
+        };
+        if stream.set_write_timeout(remaining()).is_err() {
+            return ExpectOutcome::Failed;
+        }
+
+        if !self.send.is_empty() && stream.write_all(&self.send).is_err() {
+            return ExpectOutcome::Failed;
+        }
+
+        let mut response = Vec::new();
+        let mut buf = [0u8; 512];
+        loop {
+            // each read may only take whatever is left of the timeout
+            match remaining() {
+                Some(t) => if stream.set_read_timeout(Some(t)).is_err() {
+                    break;
+                },
+                None => break,
+            }
+            match stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => response.extend_from_slice(&buf[..n]),
+                Err(_) => break,
+            }
+
+            let matched = match self.expect {
+                Some(ref re) => re.is_match(&response),
+                None => true,
+            };
+            if matched {
+                return ExpectOutcome::Matched(precise_time_ns() - start);
+            }
+
+            if response.len() >= MAX_RESPONSE_LEN {
+                break;
+            }
+        }
+
+        /*
+         * the server talked to us but never said what we expected, which is
+         * distinct from it not talking to us at all
+         */
+        if response.is_empty() {
+            ExpectOutcome::Failed
+        } else {
+            ExpectOutcome::Mismatch
+        }
+    }
+}
+
+/**
+ * Runs the TCP send/expect target's data-collection worker.
+ */
+pub fn run_tcpexpect_worker(manager: Arc<TargetManager>,
+                            results_out: Sender<TargetResults>) -> thread::JoinHandle<()> {
+    // start a new thread for the worker
+    thread::spawn(move || {
+        let mut handles = Vec::new();
+
+        // continue to collect data forever
+        loop {
+            // retrieve the target's current options
+            let (dur_interval, dur_timeout, avg_across, dur_pause, num_addrs) = {
+                let ref opt = manager.options_read();
+                (
+                    Duration::from_millis(opt.interval as u64),
+                    Duration::from_millis(cmp::max(opt.interval / cmp::max(opt.avg_across, 1), 1) as u64),
+                    opt.avg_across,
+                    Duration::from_millis(opt.pause as u64),
+                    opt.addrs.len(),
+                )
+            };
+
+            // get the current time (to timestamp this round of data with)
+            let time_ms = now_millis();
+            let timestamp = (time_ms / 1000) as i32;
+
+            let nonce = {
+                let ref t_opt = manager.options_read();
+                for addr in t_opt.addrs.iter() {
+                    let a = addr.clone();
+                    let m = manager.clone();
+
+                    /*
+                     * create channels so the per-addr threads can send back
+                     * their data to the worker thread
+                     */
+                    let (tx, rx) = channel();
+                    handles.push(rx);
+
+                    /*
+                     * spawn a thread to actually collect the data for each
+                     * separate address
+                     */
+                    thread::spawn(move || {
+                        let spec = match ExpectSpec::parse(&a) {
+                            Some(s) => s,
+                            None => {
+                                println!("Invalid tcpexpect address '{}'.", a);
+                                return;
+                            }
+                        };
+
+                        let mut sum = 0;
+                        let mut denom = 0;
+                        let mut mismatched = false;
+                        let mut raw = Vec::with_capacity(avg_across as usize);
+                        // average the results across the given number of times
+                        for _ in 0..avg_across {
+                            let attempted_at = now_millis();
+                            let val = match spec.attempt(dur_timeout) {
+                                ExpectOutcome::Matched(t) => {
+                                    sum += t;
+                                    denom += 1;
+                                    (t / 1000) as i32
+                                },
+                                ExpectOutcome::Mismatch => {
+                                    mismatched = true;
+                                    SENTINEL_MISMATCH
+                                },
+                                ExpectOutcome::Failed => SENTINEL_ERROR,
+                            };
+                            raw.push((attempted_at, val));
+                            thread::sleep(dur_pause);
+                        }
+
+                        // keep the individual attempts around if so configured
+                        if let Err(e) = m.append_raw(&a, &raw) {
+                            println!("Failed to store raw samples: {}", e);
+                        }
+
+                        // as for tcpping, a failed send is of no concern
+                        if denom != 0 {
+                            let _ = tx.send((sum / denom / 1000) as i32);
+                        } else if mismatched {
+                            let _ = tx.send(SENTINEL_MISMATCH);
+                        }
+                    });
+                }
+                t_opt.nonce
+            };
+
+            /*
+             * wait out the designated data-collectiong interval, while giving
+             * the give the per-addr subthreads the entire interval of time to
+             * come back
+             */
+            thread::sleep(dur_interval);
+
+            let mut data: Vec<i32> = Vec::with_capacity(3 + num_addrs);
+
+            data.push(manager.kind.kind_id());
+            data.push(nonce);
+            data.push(timestamp);
+
+            // read back the data from the per-addr subthreads
+            for h in handles.drain(..) {
+                if let Ok(val) = h.try_recv() {
+                    data.push(val);
+                } else {
+                    // on error or timeout, hand back a sentinel value
+                    data.push(SENTINEL_ERROR);
+                }
+            }
+
+            // send off our results to the main thread
+            if results_out.send(TargetResults(data, time_ms, Vec::new())).is_err() {
+                println!("Worker Control: failed to send final results back.");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+fn serve_once<F>(respond: F) -> String where F: FnOnce(TcpStream) + Send + 'static {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = format!("{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        respond(stream);
+    });
+    addr
+}
+
+#[test]
+fn expect_spec_parsing() {
+    let spec = ExpectSpec::parse("localhost:6379|PING\\r\\n|^\\+PONG|^-ERR").unwrap();
+    assert_eq!(spec.host, "localhost:6379");
+    assert_eq!(spec.send, b"PING\r\n".to_vec());
+    assert!(spec.expect.unwrap().is_match(b"-ERR unknown"));
+
+    let spec = ExpectSpec::parse("mail.example.com:25||^220").unwrap();
+    assert!(spec.send.is_empty());
+
+    let spec = ExpectSpec::parse("example.com:7|\\x7c").unwrap();
+    assert_eq!(spec.send, b"|".to_vec());
+    assert!(spec.expect.is_none());
+
+    assert!(ExpectSpec::parse("example.com:7|\\q").is_none());
+    assert!(ExpectSpec::parse("example.com:7|\\x7").is_none());
+    assert!(ExpectSpec::parse("example.com:7|\\x7g").is_none());
+    assert!(ExpectSpec::parse("example.com:7||(unclosed").is_none());
+}
+
+#[test]
+fn banner_match_and_mismatch() {
+    let addr = serve_once(|mut s| { s.write_all(b"220 smtp.local ESMTP\r\n").unwrap(); });
+    let spec = ExpectSpec::parse(&format!("{}||^220 ", addr)).unwrap();
+    match spec.attempt(Duration::from_secs(2)) {
+        ExpectOutcome::Matched(_) => {},
+        o => panic!("unexpected outcome {:?}", o),
+    }
+
+    let addr = serve_once(|mut s| { s.write_all(b"554 go away\r\n").unwrap(); });
+    let spec = ExpectSpec::parse(&format!("{}||^220 ", addr)).unwrap();
+    assert_eq!(spec.attempt(Duration::from_secs(2)), ExpectOutcome::Mismatch);
+}
+
+#[test]
+fn send_then_expect_response() {
+    let addr = serve_once(|mut s| {
+        let mut buf = [0u8; 6];
+        s.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"PING\r\n");
+        s.write_all(b"+PONG\r\n").unwrap();
+    });
+    let spec = ExpectSpec::parse(&format!("{}|PING\\r\\n|^\\+PONG", addr)).unwrap();
+    match spec.attempt(Duration::from_secs(2)) {
+        ExpectOutcome::Matched(_) => {},
+        o => panic!("unexpected outcome {:?}", o),
+    }
+}
@@ -6,19 +6,47 @@
  * details.
  */
 
+use std::cmp;
 use std::thread;
-use std::sync::mpsc::{channel, Sender};
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
 use std::sync::Arc;
 
 use std::time::Duration;
 use time::precise_time_ns;
-use chrono::Local;
 
-use std::net::TcpStream;
+use std::net::{TcpStream, ToSocketAddrs};
 
-use options::SENTINEL_ERROR;
+use options::{SENTINEL_ERROR, SENTINEL_NODATA};
 use options::TargetResults;
 use persist::TargetManager;
+use adaptive::AdaptiveState;
+use helpers::now_millis;
+
+/**
+ * A probe of an address still running in its own thread.
+ */
+struct Probe {
+    rx: Receiver<i32>,
+    deadline: u64,  // in millis from epoch, after which it counts as failed
+    reported: bool,  // whether it was already reported as failed
+}
+
+/**
+ * Times a TCP handshake to the given address, giving up after the given
+ * timeout. Returns the duration in nanos.
+ */
+fn tcp_handshake(addr: &str, timeout: Duration) -> Option<u64> {
+    let sock_addr = match addr.to_socket_addrs().ok().and_then(|mut a| a.next()) {
+        Some(a) => a,
+        None => return None,
+    };
+    let start = precise_time_ns();
+    match TcpStream::connect_timeout(&sock_addr, timeout) {
+        Ok(_) => Some(precise_time_ns() - start),
+        Err(_) => None,
+    }
+}
 
 /**
  * Runs the TCP Ping target's data-collection worker.
@@ -27,35 +55,84 @@ pub fn run_tcpping_worker(manager: Arc<TargetManager>,
                           results_out: Sender<TargetResults>) -> thread::JoinHandle<()> {
     // start a new thread for the worker
     thread::spawn(move || {
-        let mut handles = Vec::new();
+        let mut probes: HashMap<String, Probe> = HashMap::new();
+        let mut adaptive = AdaptiveState::new(manager.options_read().nonce);
+
+        // millis until the next round in which all addresses are due
+        let mut until_full: u64 = 0;
 
         // continue to collect data forever
         loop {
             // retrieve the target's current options
-            let (dur_interval, avg_across, dur_pause, num_addrs) = {
+            let (interval, tick, avg_across, dur_pause, num_addrs) = {
                 let ref opt = manager.options_read();
                 (
-                    Duration::from_millis(opt.interval as u64),
+                    opt.interval as u64,
+                    /*
+                     * with adaptive sampling enabled we tick at the fast
+                     * interval (probing only degraded addresses on most
+                     * ticks), otherwise just at the normal interval
+                     */
+                    match opt.fast_interval {
+                        Some(f) if f > 0 && f < opt.interval => f as u64,
+                        _ => opt.interval as u64,
+                    },
                     opt.avg_across,
                     Duration::from_millis(opt.pause as u64),
                     opt.addrs.len(),
                 )
             };
 
+            /*
+             * a full round is due every interval exactly, so the tick before
+             * one is cut short when the interval isn't a multiple of the tick
+             */
+            until_full = cmp::min(until_full, interval);
+            let full_round = until_full == 0;
+            if full_round {
+                until_full = interval;
+            }
+            let step = cmp::max(cmp::min(tick, until_full), 1);
+            until_full = until_full.saturating_sub(step);
+            let dur_step = Duration::from_millis(step);
+
+            /*
+             * probes of full rounds have the entire interval to come back,
+             * those of the ticks in between only the tick
+             */
+            let budget = if full_round { interval } else { tick };
+            let dur_timeout = Duration::from_millis(cmp::max(budget / cmp::max(avg_across, 1) as u64, 1));
+
             // get the current time (to timestamp this round of data with)
-            let timestamp: i32 = Local::now().timestamp() as i32;
+            let time_ms = now_millis();
+            let timestamp = (time_ms / 1000) as i32;
 
-            let nonce = {
+            let (nonce, addrs) = {
                 let ref t_opt = manager.options_read();
+                adaptive.sync_nonce(t_opt.nonce);
                 for addr in t_opt.addrs.iter() {
+                    // outside of full rounds, only degraded addresses are due
+                    if !full_round && !adaptive.is_degraded(addr) {
+                        continue;
+                    }
+                    // an address still being probed gets no second probe
+                    if probes.contains_key(addr) {
+                        continue;
+                    }
+
                     let a = addr.clone();
+                    let m = manager.clone();
 
                     /*
                      * create channels so the per-addr threads can send back
                      * their data to the worker thread
                      */
                     let (tx, rx) = channel();
-                    handles.push(rx);
+                    probes.insert(addr.clone(), Probe {
+                        rx: rx,
+                        deadline: time_ms as u64 + budget,
+                        reported: false,
+                    });
 
                     /*
                      * spawn a thread to actually collect the data for each
@@ -64,20 +141,29 @@ pub fn run_tcpping_worker(manager: Arc<TargetManager>,
                     thread::spawn(move || {
                         let mut sum = 0;
                         let mut denom = 0;
+                        let mut raw = Vec::with_capacity(avg_across as usize);
                         // average the results across the given number of times
                         for _ in 0..avg_across {
                             /*
                              * time the duration of a TCP handshake to the
                              * address
                              */
-                            let start = precise_time_ns();
-                            if TcpStream::connect(a.as_str()).is_ok() {
-                                sum += precise_time_ns() - start;
+                            let attempted_at = now_millis();
+                            if let Some(dur) = tcp_handshake(a.as_str(), dur_timeout) {
+                                sum += dur;
                                 denom += 1;
+                                raw.push((attempted_at, (dur / 1000) as i32));
+                            } else {
+                                raw.push((attempted_at, SENTINEL_ERROR));
                             }
                             thread::sleep(dur_pause);
                         }
 
+                        // keep the individual attempts around if so configured
+                        if let Err(e) = m.append_raw(&a, &raw) {
+                            println!("Failed to store raw samples: {}", e);
+                        }
+
                         if denom != 0 {
                             /*
                              * send back micro-second average.
@@ -90,15 +176,25 @@ pub fn run_tcpping_worker(manager: Arc<TargetManager>,
                         }
                     });
                 }
-                t_opt.nonce
+                (t_opt.nonce, t_opt.addrs.clone())
             };
 
+            // forget about probes of addresses no longer part of the target
+            probes.retain(|a, _| addrs.contains(a));
+
+            // nothing is being collected, so there is nothing to send either
+            if probes.is_empty() {
+                thread::sleep(dur_step);
+                continue;
+            }
+
             /*
-             * wait out the designated data-collectiong interval, while giving
-             * the give the per-addr subthreads the entire interval of time to
-             * come back
+             * wait out the designated data-collection tick, then read back
+             * whichever probes have come back by now (those of full rounds
+             * may take several ticks)
              */
-            thread::sleep(dur_interval);
+            thread::sleep(dur_step);
+            let now = now_millis() as u64;
 
             let mut data: Vec<i32> = Vec::with_capacity(3 + num_addrs);
 
@@ -107,20 +203,48 @@ pub fn run_tcpping_worker(manager: Arc<TargetManager>,
             data.push(timestamp);
 
             // read back the data from the per-addr subthreads
-            for h in handles.drain(..) {
-                if let Ok(val) = h.try_recv() {
-                    data.push(val);
-                } else {
-                    // on error or timeout, hand back a sentinel value
-                    data.push(SENTINEL_ERROR);
+            for addr in addrs.iter() {
+                let (val, done) = match probes.get_mut(addr) {
+                    Some(probe) => match probe.rx.try_recv() {
+                        Ok(v) => (if probe.reported { SENTINEL_NODATA } else { v }, true),
+                        // it failed every attempt
+                        Err(TryRecvError::Disconnected) =>
+                            (if probe.reported { SENTINEL_NODATA } else { SENTINEL_ERROR }, true),
+                        /*
+                         * on timeout, hand back a sentinel value (once), but
+                         * wait for it to finish before probing again
+                         */
+                        Err(TryRecvError::Empty) if now >= probe.deadline && !probe.reported => {
+                            probe.reported = true;
+                            (SENTINEL_ERROR, false)
+                        },
+                        // still running, so no data this tick
+                        Err(TryRecvError::Empty) => (SENTINEL_NODATA, false),
+                    },
+                    // addresses that weren't due have no data this tick
+                    None => (SENTINEL_NODATA, false),
+                };
+                if done {
+                    probes.remove(addr);
+                }
+
+                if adaptive.observe(addr, val) {
+                    println!("{} {}: switching to {} sampling.",
+                             manager.kind.compact_name(), addr,
+                             if adaptive.is_degraded(addr) { "fast" } else { "normal" });
                 }
+                data.push(val);
+            }
+
+            // nothing came back this tick, so there is nothing to send
+            if data[3..].iter().all(|&v| v == SENTINEL_NODATA) {
+                continue;
             }
 
             // send off our results to the main thread
-            if results_out.send(TargetResults(data)).is_err() {
+            if results_out.send(TargetResults(data, time_ms, Vec::new())).is_err() {
                 println!("Worker Control: failed to send final results back.");
             }
         }
     })
 }
-
@@ -0,0 +1,324 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+use std::cmp;
+use std::thread;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+
+use std::time::Duration;
+
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::X509VerifyResult;
+
+use options::{SENTINEL_ERROR, SENTINEL_NODATA, SENTINEL_MISMATCH};
+use options::TargetResults;
+use persist::TargetManager;
+use helpers::{now_millis, connect_timeout};
+
+/**
+ * The state of the certificate chain presented by a server.
+ */
+#[derive(Debug)]
+pub struct CertStatus {
+    pub days_left: i32,  // days until the soonest expiring cert in the chain expires
+    pub validation_error: Option<String>,  // why the chain failed validation, if it did
+}
+
+/**
+ * Extracts the host name (for SNI and hostname verification) out of a
+ * `host:port` address.
+ */
+pub fn host_of(addr: &str) -> &str {
+    let host = match addr.rfind(':') {
+        Some(i) => &addr[..i],
+        None => addr,
+    };
+    host.trim_matches(|c| c == '[' || c == ']')
+}
+
+/**
+ * Connects to the given `host:port` address, performs a TLS handshake, and
+ * inspects the peer certificate chain, each step bounded by the timeout. The handshake is completed even if the
+ * chain fails validation so that we can still report its expiry.
+ */
+pub fn check_cert(addr: &str, timeout: Duration) -> Result<CertStatus, String> {
+    let stream = try!(connect_timeout(addr, timeout).map_err(|e| format!("{}", e)));
+    try!(stream.set_read_timeout(Some(timeout)).map_err(|e| format!("{}", e)));
+    try!(stream.set_write_timeout(Some(timeout)).map_err(|e| format!("{}", e)));
+
+    /*
+     * don't abort the handshake on verification failure, we instead check the
+     * verification result (which still includes the hostname check) ourselves
+     */
+    let mut builder = try!(SslConnector::builder(SslMethod::tls()).map_err(|e| format!("{}", e)));
+    builder.set_verify(SslVerifyMode::NONE);
+    let connector = builder.build();
+    let tls = try!(
+        connector.connect(host_of(addr), stream)
+        .map_err(|e| format!("TLS handshake failed: {}", e))
+    );
+
+    let ssl = tls.ssl();
+    let now = try!(Asn1Time::days_from_now(0).map_err(|e| format!("{}", e)));
+
+    let mut days_left = None;
+    {
+        let mut consider = |not_after: &Asn1TimeRef| -> Result<(), String> {
+            let diff = try!(now.diff(not_after).map_err(|e| format!("{}", e)));
+            days_left = Some(match days_left {
+                Some(d) => cmp::min(d, diff.days),
+                None => diff.days,
+            });
+            Ok(())
+        };
+
+        match ssl.peer_cert_chain() {
+            Some(chain) => for cert in chain.iter() {
+                try!(consider(cert.not_after()));
+            },
+            None => if let Some(cert) = ssl.peer_certificate() {
+                try!(consider(cert.not_after()));
+            },
+        }
+    }
+
+    let verify = ssl.verify_result();
+    Ok(CertStatus {
+        days_left: try!(days_left.ok_or_else(|| "No peer certificate presented.".to_owned())),
+        validation_error: if verify == X509VerifyResult::OK {
+            None
+        } else {
+            Some(verify.error_string().to_owned())
+        },
+    })
+}
+
+/**
+ * The value collected for the given status: the days left, or the mismatch
+ * sentinel if the chain failed validation (as it can't be relied on however
+ * long it has left).
+ */
+pub fn value_of(status: &CertStatus) -> i32 {
+    if status.validation_error.is_some() {
+        SENTINEL_MISMATCH
+    } else {
+        status.days_left
+    }
+}
+
+/**
+ * Runs the TLS certificate expiry target's data-collection worker.
+ */
+pub fn run_tlsexpiry_worker(manager: Arc<TargetManager>,
+                            results_out: Sender<TargetResults>) -> thread::JoinHandle<()> {
+    // start a new thread for the worker
+    thread::spawn(move || {
+        let mut handles = Vec::new();
+
+        // continue to collect data forever
+        loop {
+            /*
+             * retrieve the target's current options (averaging makes little
+             * sense for expiry, so avg_across and pause are not used)
+             */
+            let (dur_interval, dur_timeout, num_addrs, threshold) = {
+                let ref opt = manager.options_read();
+                (
+                    Duration::from_millis(opt.interval as u64),
+                    Duration::from_millis(cmp::min(opt.interval, 30_000) as u64),
+                    opt.addrs.len(),
+                    opt.threshold,
+                )
+            };
+
+            // get the current time (to timestamp this round of data with)
+            let time_ms = now_millis();
+            let timestamp = (time_ms / 1000) as i32;
+
+            let (nonce, addrs) = {
+                let ref t_opt = manager.options_read();
+                for addr in t_opt.addrs.iter() {
+                    let a = addr.clone();
+                    let m = manager.clone();
+
+                    /*
+                     * create channels so the per-addr threads can send back
+                     * their data to the worker thread
+                     */
+                    let (tx, rx) = channel();
+                    handles.push(rx);
+
+                    // spawn a thread to actually check each separate address
+                    thread::spawn(move || {
+                        let attempted_at = now_millis();
+                        let status = check_cert(a.as_str(), dur_timeout);
+
+                        // keep the (single) attempt around if so configured
+                        let val = match status {
+                            Ok(ref s) => value_of(s),
+                            Err(_) => SENTINEL_ERROR,
+                        };
+                        if let Err(e) = m.append_raw(&a, &[(attempted_at, val)]) {
+                            println!("Failed to store raw samples: {}", e);
+                        }
+
+                        // as for tcpping, a failed send is of no concern
+                        let _ = tx.send(status);
+                    });
+                }
+                (t_opt.nonce, t_opt.addrs.clone())
+            };
+
+            /*
+             * wait out the designated data-collectiong interval, while giving
+             * the give the per-addr subthreads the entire interval of time to
+             * come back
+             */
+            thread::sleep(dur_interval);
+
+            let mut data: Vec<i32> = Vec::with_capacity(3 + num_addrs);
+            let mut days: Vec<i32> = Vec::with_capacity(num_addrs);
+
+            data.push(manager.kind.kind_id());
+            data.push(nonce);
+            data.push(timestamp);
+
+            // read back the data from the per-addr subthreads
+            let kind_name = manager.kind.compact_name();
+            for (h, addr) in handles.drain(..).zip(addrs.iter()) {
+                match h.try_recv() {
+                    Ok(Ok(status)) => {
+                        // the days left are kept (as aux) even if the chain is invalid
+                        data.push(value_of(&status));
+                        days.push(status.days_left);
+
+                        /*
+                         * raise a warning when the chain is invalid or is
+                         * about to expire (threshold is in days)
+                         */
+                        if let Some(ref e) = status.validation_error {
+                            manager.health().raise(kind_name, addr,
+                                format!("Certificate chain failed validation: {}.", e));
+                        } else if threshold.map_or(false, |t| status.days_left < t) {
+                            manager.health().raise(kind_name, addr,
+                                format!("Certificate expires in {} days.", status.days_left));
+                        } else {
+                            manager.health().clear(kind_name, addr);
+                        }
+                    },
+                    Ok(Err(e)) => {
+                        manager.health().raise(kind_name, addr,
+                            format!("Unable to check certificate: {}.", e));
+                        data.push(SENTINEL_ERROR);
+                        days.push(SENTINEL_NODATA);
+                    },
+                    Err(_) => {
+                        // on timeout, hand back a sentinel value
+                        data.push(SENTINEL_ERROR);
+                        days.push(SENTINEL_NODATA);
+                    },
+                }
+            }
+
+            // send off our results to the main thread
+            if results_out.send(TargetResults(data, time_ms, days)).is_err() {
+                println!("Worker Control: failed to send final results back.");
+            }
+        }
+    })
+}
+
+#[test]
+fn host_of_strips_port_and_brackets() {
+    assert_eq!(host_of("example.com:443"), "example.com");
+    assert_eq!(host_of("[::1]:443"), "::1");
+    assert_eq!(host_of("example.com"), "example.com");
+}
+
+#[test]
+fn check_cert_against_local_self_signed() {
+    use std::net::TcpListener;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::ssl::SslAcceptor;
+    use openssl::x509::{X509Builder, X509NameBuilder};
+    use std::io::Read;
+    use alerts::{AlertEngine, AddrContext};
+    use helpers::TempDir;
+    use notify;
+    use options::{AlertRule, Notifier};
+
+    // a self-signed cert for localhost expiring in 10 days
+    let pkey = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+    let mut cert = X509Builder::new().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&pkey).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(10).unwrap()).unwrap();
+    cert.sign(&pkey, MessageDigest::sha256()).unwrap();
+    let cert = cert.build();
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    acceptor.set_private_key(&pkey).unwrap();
+    acceptor.set_certificate(&cert).unwrap();
+    let acceptor = acceptor.build();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let _ = acceptor.accept(stream);
+    });
+
+    let addr = format!("localhost:{}", port);
+    let status = check_cert(&addr, Duration::from_secs(5)).unwrap();
+    assert!(status.days_left == 9 || status.days_left == 10);
+    // self-signed, so not trusted
+    assert!(status.validation_error.is_some());
+    assert_eq!(value_of(&status), SENTINEL_MISMATCH);
+
+    // which fires a rule on the days left, notified of like any other alert
+    struct NoContext;
+    impl AddrContext for NoContext {
+        fn deviation(&self, _: &str) -> Option<f64> { None }
+        fn in_maintenance(&self, _: &str, _: i64) -> bool { false }
+    }
+
+    let mut engine = AlertEngine::new(&[AlertRule {
+        name: "expiring".to_owned(), target: "tlsexpiry".to_owned(), addr: None,
+        condition: "below".to_owned(), limit: 14.0, window: None,
+    }]).unwrap();
+    let fired = engine.observe("tlsexpiry", 0, &[(&addr[..], value_of(&status))], &NoContext);
+    assert_eq!(fired.len(), 1);
+    assert_eq!((&fired[0].state[..], &fired[0].addr[..]), ("firing", &addr[..]));
+
+    let dir = TempDir::new("tlsexpiry");
+    let out = dir.join("alert.json");
+    let n = Notifier {
+        webhook: None, command: Some(format!("cat > '{}'", out.display())), syslog: None, email: None,
+    };
+    notify::deliver(&n, None, &fired[0]).unwrap();
+    let mut delivered = String::new();
+    ::std::fs::File::open(&out).unwrap().read_to_string(&mut delivered).unwrap();
+    assert!(delivered.contains("\"rule\":\"expiring\"") && delivered.contains("\"state\":\"firing\""));
+
+    // an attempt failing outright changes nothing, and a renewed certificate resolves it
+    assert!(engine.observe("tlsexpiry", 10, &[(&addr[..], SENTINEL_ERROR)], &NoContext).is_empty());
+    let resolved = engine.observe("tlsexpiry", 20, &[(&addr[..], 90)], &NoContext);
+    assert_eq!(resolved[0].state, "resolved");
+}
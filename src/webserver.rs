@@ -10,8 +10,9 @@ use std::thread;
 use std::error::Error;
 use std::fmt;
 use std::io::Read;
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
 
 use iron::prelude::{Request, Response, Iron, IronResult, IronError};
 use iron::middleware::Handler;
@@ -25,9 +26,14 @@ use mount::Mount;
 
 use rustc_serialize::{json, Decodable};
 
-use reader::{SPDataReader, DataRequest};
+use reader::{SPDataReader, DataRequest, SPRawReader, RawRequest};
 use persist::{TargetManager, ManagerError};
 use options::{MainConfiguration, TargetOptions};
+use addrindex::AddrChange;
+use alerts::AlertEngine;
+use maintenance::MaintenanceWindow;
+use stats;
+use stats::StatsRequest;
 
 /**
  * Stabping-specific web error container for use in Iron web responses.
@@ -40,6 +46,7 @@ enum SPWebError {
     BadRequest,
     ServerError,
     NonceConflict,
+    Rejected(String),
 }
 
 impl Error for SPWebError {
@@ -51,6 +58,7 @@ impl Error for SPWebError {
             SPWebError::BadRequest => "Bad request (malformed or missing fields).",
             SPWebError::ServerError => "Server encountered an error.",
             SPWebError::NonceConflict => "The nonce given does not match the current nonce, refusing update.",
+            SPWebError::Rejected(ref msg) => msg,
         }
     }
 }
@@ -134,6 +142,34 @@ impl<'a, 'b> JsonBody for Body<'a, 'b> {
     }
 }
 
+/**
+ * Increments (and wraps around if necessary) the given options nonce.
+ */
+fn next_nonce(nonce: i32) -> i32 {
+    let (n, over) = nonce.overflowing_add(1);
+    if over {
+        0
+    } else {
+        n
+    }
+}
+
+/**
+ * Describes who made the given request for the options history: the client
+ * IP, preceded by the user named in `X-Remote-User` (as set by an
+ * authenticating reverse proxy) if there is one.
+ */
+fn request_source(req: &Request) -> String {
+    let ip = format!("{}", req.remote_addr.ip());
+    let user = req.headers.get_raw("X-Remote-User")
+        .and_then(|raw| raw.first())
+        .and_then(|v| String::from_utf8(v.clone()).ok());
+    match user {
+        Some(user) => format!("{} ({})", user, ip),
+        None => ip,
+    }
+}
+
 /**
  * Handler for each /api/target endpoint that handles returning and updating
  * target options, and retrieving persisted target data.
@@ -194,19 +230,12 @@ impl Handler for TargetHandler {
                 }
 
                 // increment (and wrap-around if necessary) the nonce
-                let new_nonce = {
-                    let (n, over) = new_options.nonce.overflowing_add(1);
-                    if over {
-                        0
-                    } else {
-                        n
-                    }
-                };
+                let new_nonce = next_nonce(new_options.nonce);
                 new_options.nonce = new_nonce;
 
                 // actually update the options via the manager
                 try!(
-                    self.manager.options_update(new_options)
+                    self.manager.options_update(new_options, &request_source(req))
                     .map_err(|_| IronError::new(SPWebError::ServerError, status::InternalServerError))
                 );
                 Ok(Response::with((format!("{}", new_nonce), status::Ok)))
@@ -217,12 +246,224 @@ impl Handler for TargetHandler {
 }
 
 
+/**
+ * Handler for each /api/target/<kind>/raw endpoint that handles retrieving
+ * persisted raw (per-attempt) samples of a target.
+ */
+struct RawHandler {
+    manager: Arc<TargetManager>,
+}
+
+impl RawHandler {
+    fn new(manager: Arc<TargetManager>) -> Self {
+        RawHandler {
+            manager: manager,
+        }
+    }
+}
+
+impl Handler for RawHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        match req.method {
+            Method::Post => { /* Retrieve Raw Samples */
+                // try and get the parameters of the request
+                let rr: RawRequest = try!(req.body.read_json());
+                println!("Request for {} raw samples: {:?}", self.manager.kind.compact_name(), rr);
+
+                let body_writer = try!(
+                    // try and create a raw samples reader out of this request
+                    SPRawReader::new(self.manager.clone(), rr)
+                    .ok_or_else(|| {
+                        println!("Failed to create SPRawReader.");
+                        IronError::new(SPWebError::BadRequest, status::BadRequest)
+                    })
+                );
+
+                // respond with the raw samples reader as the body
+                let r = Response::with((status::Ok));
+                Ok(Response {
+                    status: r.status,
+                    headers: r.headers,
+                    extensions: r.extensions,
+                    body: Some(Box::new(body_writer)),
+                })
+            },
+            _ => Err(IronError::new(SPWebError::InvalidMethod, status::MethodNotAllowed))
+        }
+    }
+}
+
+/**
+ * Handler for each /api/target/<kind>/index endpoint that handles listing and
+ * changing (aliasing, naming, retiring or renaming) the entries of a target's
+ * address index.
+ */
+struct IndexHandler {
+    manager: Arc<TargetManager>,
+}
+
+impl IndexHandler {
+    fn new(manager: Arc<TargetManager>) -> Self {
+        IndexHandler {
+            manager: manager,
+        }
+    }
+}
+
+impl Handler for IndexHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        match req.method {
+            Method::Get => { /* List Index Entries */
+                let entries = self.manager.index_describe();
+                Ok(Response::with((status::Ok, json::encode(&entries).unwrap())))
+            },
+            Method::Post => { /* Change an Index Entry */
+                let change: AddrChange = try!(req.body.read_json());
+                try!(
+                    self.manager.index_change(&change)
+                    .map_err(|e| match e {
+                        ManagerError::InvalidChange(msg) =>
+                            IronError::new(SPWebError::Rejected(msg.clone()), (status::BadRequest, msg)),
+                        _ => IronError::new(SPWebError::ServerError, status::InternalServerError),
+                    })
+                );
+                Ok(Response::with((status::Ok)))
+            },
+            _ => Err(IronError::new(SPWebError::InvalidMethod, status::MethodNotAllowed))
+        }
+    }
+}
+
+/**
+ * A request to remove the maintenance window with the given `id`.
+ */
+#[derive(RustcDecodable, Debug)]
+struct RemoveRequest {
+    id: u32,
+}
+
+/**
+ * Handler for each /api/target/<kind>/maintenance endpoint that handles
+ * listing, adding and removing a target's maintenance windows.
+ */
+struct MaintenanceHandler {
+    manager: Arc<TargetManager>,
+}
+
+impl MaintenanceHandler {
+    fn new(manager: Arc<TargetManager>) -> Self {
+        MaintenanceHandler {
+            manager: manager,
+        }
+    }
+}
+
+impl Handler for MaintenanceHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        match req.method {
+            Method::Get => { /* List Maintenance Windows */
+                let windows = self.manager.maintenance_windows();
+                Ok(Response::with((status::Ok, json::encode(&windows).unwrap())))
+            },
+            Method::Post => { /* Add a Maintenance Window */
+                let window: MaintenanceWindow = try!(req.body.read_json());
+                println!("Request to add {} maintenance window: {:?}", self.manager.kind.compact_name(), window);
+                let id = try!(
+                    self.manager.maintenance_add(window)
+                    .map_err(|e| match e {
+                        ManagerError::InvalidChange(msg) =>
+                            IronError::new(SPWebError::Rejected(msg.clone()), (status::BadRequest, msg)),
+                        _ => IronError::new(SPWebError::ServerError, status::InternalServerError),
+                    })
+                );
+                Ok(Response::with((format!("{}", id), status::Ok)))
+            },
+            Method::Delete => { /* Remove a Maintenance Window */
+                let rr: RemoveRequest = try!(req.body.read_json());
+                let removed = try!(
+                    self.manager.maintenance_remove(rr.id)
+                    .map_err(|_| IronError::new(SPWebError::ServerError, status::InternalServerError))
+                );
+                if !removed {
+                    return Err(IronError::new(SPWebError::NotFound, status::NotFound));
+                }
+                Ok(Response::with((status::Ok)))
+            },
+            _ => Err(IronError::new(SPWebError::InvalidMethod, status::MethodNotAllowed))
+        }
+    }
+}
+
+/**
+ * A request to roll back a target's options to the version with nonce `to`,
+ * verified with the current `nonce`.
+ */
+#[derive(RustcDecodable, Debug)]
+struct RollbackRequest {
+    nonce: i32,
+    to: i32,
+}
+
+/**
+ * Handler for each /api/target/<kind>/history (and .../history/rollback)
+ * endpoint that handles listing the versions of a target's options, and
+ * rolling back to one of them.
+ */
+struct HistoryHandler {
+    manager: Arc<TargetManager>,
+}
+
+impl HistoryHandler {
+    fn new(manager: Arc<TargetManager>) -> Self {
+        HistoryHandler {
+            manager: manager,
+        }
+    }
+}
+
+impl Handler for HistoryHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        match req.method {
+            Method::Get => { /* List Options History */
+                let versions = self.manager.options_history();
+                Ok(Response::with((status::Ok, json::encode(&versions).unwrap())))
+            },
+            Method::Post => { /* Roll Back Options */
+                let rr: RollbackRequest = try!(req.body.read_json());
+                println!("Request to roll back {} options: {:?}", self.manager.kind.compact_name(), rr);
+
+                // make sure the received nonce matches the existing nonce
+                if rr.nonce != self.manager.options_read().nonce {
+                    return Err(IronError::new(SPWebError::NonceConflict, status::Conflict));
+                }
+
+                let mut restored = try!(
+                    self.manager.options_version(rr.to)
+                    .ok_or_else(|| IronError::new(SPWebError::NotFound, status::NotFound))
+                ).options;
+
+                // the restored options are put in place as a new version
+                let new_nonce = next_nonce(rr.nonce);
+                restored.nonce = new_nonce;
+                let source = format!("{}, rolling back to {}", request_source(req), rr.to);
+                try!(
+                    self.manager.options_update(restored, &source)
+                    .map_err(|_| IronError::new(SPWebError::ServerError, status::InternalServerError))
+                );
+                Ok(Response::with((format!("{}", new_nonce), status::Ok)))
+            },
+            _ => Err(IronError::new(SPWebError::InvalidMethod, status::MethodNotAllowed))
+        }
+    }
+}
+
 /**
  * Creates and starts the web server given the configuration (with the web
- * port) and a list of target managers.
+ * port), a list of target managers, and the alert engine.
  */
 pub fn web_server<'a, T>(configuration: Arc<RwLock<MainConfiguration>>,
-                         targets: T) -> thread::JoinHandle<()>
+                         targets: T,
+                         alerts: Arc<Mutex<AlertEngine>>) -> thread::JoinHandle<()>
                          where T: Iterator<Item=&'a Arc<TargetManager>> {
     let mut router = Router::new();
 
@@ -239,11 +480,89 @@ pub fn web_server<'a, T>(configuration: Arc<RwLock<MainConfiguration>>,
     };
     router.get("/api/config/ws_port", ws_port_handler, "api_config_ws_port");
 
+    /*
+     * serve the outstanding health warnings of all targets at /api/health,
+     * keyed by target kind
+     */
+    let targets: Vec<Arc<TargetManager>> = targets.cloned().collect();
+    let health_targets = targets.clone();
+    let health_handler = move |_: &mut Request| -> IronResult<Response> {
+        let mut warnings = HashMap::new();
+        for tm in health_targets.iter() {
+            warnings.insert(tm.kind.compact_name(), tm.health().snapshot());
+        }
+        Ok(Response::with((status::Ok, json::encode(&warnings).unwrap())))
+    };
+    router.get("/api/health", health_handler, "api_health");
+
+    // serve the alerts currently firing at /api/alerts
+    let alerts_handler = move |_: &mut Request| -> IronResult<Response> {
+        let firing = alerts.lock().unwrap().firing();
+        Ok(Response::with((status::Ok, json::encode(&firing).unwrap())))
+    };
+    router.get("/api/alerts", alerts_handler, "api_alerts");
+
     // route each /api/target/... endpoint to the appropriate TargetHandler
-    for tm in targets {
+    for tm in targets.iter() {
         router.any(format!("/api/target/{}", tm.kind.compact_name()),
                    TargetHandler::new(tm.clone()),
                    format!("target_{}", tm.kind.compact_name()));
+        router.post(format!("/api/target/{}/raw", tm.kind.compact_name()),
+                    RawHandler::new(tm.clone()),
+                    format!("target_{}_raw", tm.kind.compact_name()));
+        router.get(format!("/api/target/{}/history", tm.kind.compact_name()),
+                   HistoryHandler::new(tm.clone()),
+                   format!("target_{}_history", tm.kind.compact_name()));
+        router.post(format!("/api/target/{}/history/rollback", tm.kind.compact_name()),
+                    HistoryHandler::new(tm.clone()),
+                    format!("target_{}_history_rollback", tm.kind.compact_name()));
+        router.any(format!("/api/target/{}/index", tm.kind.compact_name()),
+                   IndexHandler::new(tm.clone()),
+                   format!("target_{}_index", tm.kind.compact_name()));
+        router.any(format!("/api/target/{}/maintenance", tm.kind.compact_name()),
+                   MaintenanceHandler::new(tm.clone()),
+                   format!("target_{}_maintenance", tm.kind.compact_name()));
+
+        // compute summary statistics of the target's data on request
+        let stats_tm = tm.clone();
+        let stats_handler = move |req: &mut Request| -> IronResult<Response> {
+            let sr: StatsRequest = try!(req.body.read_json());
+            println!("Request for {} statistics: {:?}", stats_tm.kind.compact_name(), sr);
+            let result = try!(
+                stats::compute(&stats_tm, sr)
+                .ok_or_else(|| IronError::new(SPWebError::BadRequest, status::BadRequest))
+            );
+            let all_stats = try!(
+                result.map_err(|e| {
+                    println!("ERROR: {}", e);
+                    IronError::new(SPWebError::ServerError, status::InternalServerError)
+                })
+            );
+            Ok(Response::with((status::Ok, json::encode(&all_stats).unwrap())))
+        };
+        router.post(format!("/api/target/{}/stats", tm.kind.compact_name()),
+                    stats_handler,
+                    format!("target_{}_stats", tm.kind.compact_name()));
+
+        // list the target's incident log
+        let incidents_tm = tm.clone();
+        let incidents_handler = move |_: &mut Request| -> IronResult<Response> {
+            let incidents = incidents_tm.incidents();
+            Ok(Response::with((status::Ok, json::encode(&incidents).unwrap())))
+        };
+        router.get(format!("/api/target/{}/incidents", tm.kind.compact_name()),
+                   incidents_handler,
+                   format!("target_{}_incidents", tm.kind.compact_name()));
+
+        // list the target's latest anomaly events
+        let anomalies_tm = tm.clone();
+        let anomalies_handler = move |_: &mut Request| -> IronResult<Response> {
+            let events = anomalies_tm.anomalies();
+            Ok(Response::with((status::Ok, json::encode(&events).unwrap())))
+        };
+        router.get(format!("/api/target/{}/anomalies", tm.kind.compact_name()),
+                   anomalies_handler,
+                   format!("target_{}_anomalies", tm.kind.compact_name()));
     }
 
     let mut mount = Mount::new();
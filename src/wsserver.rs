@@ -12,7 +12,8 @@ use std::sync::{Arc, Mutex, RwLock};
 use ws;
 use ws::{Settings, Builder};
 
-use options::MainConfiguration;
+use helpers::VecIntoRawBytes;
+use options::{MainConfiguration, TargetResults};
 
 /**
  * Error container for websocket broadcasts.
@@ -56,6 +57,14 @@ impl Broadcaster {
             Err(BroadcastError::SocketNotAvail)
         }
     }
+
+    /**
+     * Broadcasts live data to all connected websocket clients as a binary
+     * message of 32-bit little-endian integers.
+     */
+    pub fn send_results(&self, results: TargetResults) -> Result<(), BroadcastError> {
+        self.send(results.0.into_raw_bytes())
+    }
 }
 
 pub fn ws_server(configuration: Arc<RwLock<MainConfiguration>>,